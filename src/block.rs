@@ -0,0 +1,91 @@
+use crate::error::{Error, Result};
+use crate::io::IoQueuePair;
+use crate::memory::Allocator;
+
+/// A device storing data in blocks of a fixed size.
+///
+/// This gives filesystems and other storage stacks a uniform entry point,
+/// which `Disk` implements on top of an I/O queue pair.
+pub trait BlockDevice {
+    /// The error returned by the operations of the device
+    type Error;
+
+    /// Get the size of a block (in bytes).
+    fn block_size(&self) -> usize;
+
+    /// Get the number of blocks of the device.
+    fn block_count(&self) -> u64;
+
+    /// Read the blocks starting from `lba` into `buf`.
+    ///
+    /// The length of `buf` must be a multiple of the block size.
+    fn read_block(&mut self, buf: &mut [u8], lba: u64) -> core::result::Result<(), Self::Error>;
+
+    /// Write `buf` to the blocks starting from `lba`.
+    ///
+    /// The length of `buf` must be a multiple of the block size.
+    fn write_block(&mut self, buf: &[u8], lba: u64) -> core::result::Result<(), Self::Error>;
+
+    /// Make all written blocks persistent.
+    fn flush(&mut self) -> core::result::Result<(), Self::Error>;
+}
+
+/// A namespace used as a block device through an I/O queue pair.
+///
+/// It owns the queue pair, which can be taken back by `into_inner`,
+/// e.g. to delete it with `Device::delete_io_queue_pair`.
+pub struct Disk<A: Allocator> {
+    qpair: IoQueuePair<A>,
+    block_size: usize,
+    block_count: u64,
+}
+
+impl<A: Allocator> Disk<A> {
+    /// Create a block device from the namespace of `qpair`.
+    pub fn new(qpair: IoQueuePair<A>) -> Self {
+        let namespace = qpair.namespace();
+        Self {
+            block_size: namespace.block_size() as usize,
+            block_count: namespace.block_count(),
+            qpair,
+        }
+    }
+
+    /// Get the queue pair, e.g. to use the operations not covered
+    /// by `BlockDevice`.
+    pub fn queue_pair(&mut self) -> &mut IoQueuePair<A> {
+        &mut self.qpair
+    }
+
+    /// Take back the queue pair.
+    pub fn into_inner(self) -> IoQueuePair<A> {
+        self.qpair
+    }
+}
+
+impl<A: Allocator> BlockDevice for Disk<A> {
+    type Error = Error;
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    /// See `IoQueuePair::read_blocks`.
+    fn read_block(&mut self, buf: &mut [u8], lba: u64) -> Result<()> {
+        self.qpair.read_blocks(buf, lba)
+    }
+
+    /// See `IoQueuePair::write_blocks`.
+    fn write_block(&mut self, buf: &[u8], lba: u64) -> Result<()> {
+        self.qpair.write_blocks(buf, lba)
+    }
+
+    /// See `IoQueuePair::flush_namespace`.
+    fn flush(&mut self) -> Result<()> {
+        self.qpair.flush_namespace()
+    }
+}
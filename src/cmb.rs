@@ -0,0 +1,170 @@
+use alloc::collections::btree_map::BTreeMap;
+
+use crate::cmd::Command;
+use crate::device::{Device, Register};
+use crate::error::{Error, Result};
+use crate::memory::{Allocator, Dma};
+use crate::queues::SubQueue;
+
+/// Alignment of the queues allocated in the CMB.
+const CMB_ALIGNMENT: usize = 4096;
+
+/// CMBMSC.CRE: Capabilities Registers Enabled.
+const CMBMSC_CAPABILITIES_ENABLED: u64 = 1 << 0;
+/// CMBMSC.CMSE: Controller Memory Space Enabled.
+const CMBMSC_SPACE_ENABLED: u64 = 1 << 1;
+
+/// The location and capabilities of the Controller Memory Buffer (CMB).
+///
+/// Reported by the CMBLOC and CMBSZ registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CmbInfo {
+    /// The PCI BAR the CMB is mapped by (BIR)
+    pub bar: u8,
+    /// The offset of the CMB in the BAR (in bytes)
+    pub offset: u64,
+    /// The size of the CMB (in bytes)
+    pub size: u64,
+    /// Submission queues may be placed in the CMB
+    pub submission_queues: bool,
+    /// Completion queues may be placed in the CMB
+    pub completion_queues: bool,
+    /// PRP and SGL lists may be placed in the CMB
+    pub prp_lists: bool,
+    /// Data transferred to the host may be placed in the CMB
+    pub read_data: bool,
+    /// Data transferred to the controller may be placed in the CMB
+    pub write_data: bool,
+}
+
+impl CmbInfo {
+    /// Parses the values of the CMBLOC and CMBSZ registers.
+    ///
+    /// Returns `None` if the controller has no CMB.
+    pub(crate) fn from_raw(cmbloc: u32, cmbsz: u32) -> Option<Self> {
+        if cmbsz == 0 {
+            return None;
+        }
+
+        // The size unit grows by a factor of 16 from 4 KiB.
+        let unit = 4096u64 << (4 * ((cmbsz >> 8) & 0xF));
+        Some(Self {
+            bar: cmbloc as u8 & 0x7,
+            offset: (cmbloc >> 12) as u64 * unit,
+            size: (cmbsz >> 12) as u64 * unit,
+            submission_queues: cmbsz & (1 << 0) != 0,
+            completion_queues: cmbsz & (1 << 1) != 0,
+            prp_lists: cmbsz & (1 << 2) != 0,
+            read_data: cmbsz & (1 << 3) != 0,
+            write_data: cmbsz & (1 << 4) != 0,
+        })
+    }
+}
+
+/// The mapped CMB and the queues allocated in it.
+pub(crate) struct Cmb {
+    address: usize,
+    phys_addr: usize,
+    size: usize,
+    /// The size of each allocation by its offset in the CMB
+    used: BTreeMap<usize, usize>,
+}
+
+impl Cmb {
+    /// Allocate a submission queue of `len` entries in the CMB.
+    ///
+    /// Returns `None` if there is no room left.
+    pub fn allocate_sub_queue(&mut self, len: usize) -> Option<SubQueue> {
+        let size = (len * size_of::<Command>()).next_multiple_of(CMB_ALIGNMENT);
+
+        // Take the first gap between the allocations which is large enough.
+        let mut offset = 0;
+        for (&start, &used) in &self.used {
+            if offset + size <= start {
+                break;
+            }
+            offset = (start + used).next_multiple_of(CMB_ALIGNMENT);
+        }
+        if offset + size > self.size {
+            return None;
+        }
+
+        self.used.insert(offset, size);
+        Some(SubQueue {
+            data: Dma {
+                addr: (self.address + offset) as *mut Command,
+                phys_addr: self.phys_addr + offset,
+                count: len,
+            },
+            head: 0,
+            prp_list: None,
+            tail: 0,
+        })
+    }
+
+    /// Release the memory at `addr`.
+    ///
+    /// Returns whether it was allocated in the CMB.
+    pub fn release(&mut self, addr: usize) -> bool {
+        addr.checked_sub(self.address)
+            .is_some_and(|offset| self.used.remove(&offset).is_some())
+    }
+}
+
+impl<A: Allocator> Device<A> {
+    /// Get the location and capabilities of the Controller Memory Buffer.
+    ///
+    /// Returns `None` if the controller has no CMB.
+    pub fn cmb_info(&self) -> Option<CmbInfo> {
+        self.cmb_info
+    }
+
+    /// Use the Controller Memory Buffer for submission queues.
+    ///
+    /// The CMB is a part of the PCI BAR reported by `cmb_info`, which has
+    /// to be mapped by the caller. The `address` is the virtual address
+    /// of the CMB (the BAR plus the offset) and `phys_addr` the address
+    /// the controller knows it by.
+    ///
+    /// Queue pairs created by `create_io_queue_pair_in_cmb` afterwards
+    /// place their submission queue in the CMB, which saves the controller
+    /// fetching the commands from host memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::FeatureNotSupported` if the controller has no CMB
+    /// or it does not support submission queues, and `Error::IoQueuesExist`
+    /// if queues are already allocated in the CMB.
+    pub fn map_cmb(&mut self, address: usize, phys_addr: usize) -> Result<()> {
+        let Some(info) = self.cmb_info.filter(|info| info.submission_queues) else {
+            return Err(Error::FeatureNotSupported);
+        };
+        if self.cmb.as_ref().is_some_and(|cmb| !cmb.used.is_empty()) {
+            return Err(Error::IoQueuesExist);
+        }
+
+        // Newer controllers only respond to the CMB once it is enabled.
+        if self.capabilities().cmb_supported {
+            let value = phys_addr as u64 | CMBMSC_SPACE_ENABLED | CMBMSC_CAPABILITIES_ENABLED;
+            self.set_reg::<u64>(Register::CMBMSC, value);
+        }
+
+        self.cmb = Some(Cmb {
+            address,
+            phys_addr,
+            size: info.size as usize,
+            used: BTreeMap::new(),
+        });
+        Ok(())
+    }
+
+    /// Read the CMB registers, enabling them first if needed.
+    pub(crate) fn read_cmb_info(&self) -> Option<CmbInfo> {
+        if self.capabilities().cmb_supported {
+            self.set_reg::<u64>(Register::CMBMSC, CMBMSC_CAPABILITIES_ENABLED);
+        }
+        let cmbloc = self.get_reg::<u32>(Register::CMBLOC);
+        let cmbsz = self.get_reg::<u32>(Register::CMBSZ);
+        CmbInfo::from_raw(cmbloc, cmbsz)
+    }
+}
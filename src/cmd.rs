@@ -30,22 +30,134 @@ pub(crate) struct Command {
     cmd_15: u32,
 }
 
+/// A command with a caller defined opcode and dwords.
+///
+/// It is used to send commands that are not modeled by the driver.
+/// The command identifier and data pointer are always managed
+/// by the driver, so they cannot be set here.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawCommand {
+    opcode: u8,
+    ns_id: u32,
+    cdw: [u32; 6],
+}
+
+impl RawCommand {
+    /// Create a new raw command with the given opcode.
+    pub fn new(opcode: u8) -> Self {
+        Self {
+            opcode,
+            ..Default::default()
+        }
+    }
+
+    /// Set the namespace identifier.
+    pub fn ns_id(mut self, ns_id: u32) -> Self {
+        self.ns_id = ns_id;
+        self
+    }
+
+    /// Set command dword 10.
+    pub fn cdw10(mut self, value: u32) -> Self {
+        self.cdw[0] = value;
+        self
+    }
+
+    /// Set command dword 11.
+    pub fn cdw11(mut self, value: u32) -> Self {
+        self.cdw[1] = value;
+        self
+    }
+
+    /// Set command dword 12.
+    pub fn cdw12(mut self, value: u32) -> Self {
+        self.cdw[2] = value;
+        self
+    }
+
+    /// Set command dword 13.
+    pub fn cdw13(mut self, value: u32) -> Self {
+        self.cdw[3] = value;
+        self
+    }
+
+    /// Set command dword 14.
+    pub fn cdw14(mut self, value: u32) -> Self {
+        self.cdw[4] = value;
+        self
+    }
+
+    /// Set command dword 15.
+    pub fn cdw15(mut self, value: u32) -> Self {
+        self.cdw[5] = value;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum NamespaceList {
+    Active,
+    Allocated,
+}
+
 #[derive(Debug)]
 pub(crate) enum IdentifyType {
     Namespace(u32),
     Controller,
-    NamespaceList(u32),
+    NamespaceList(NamespaceList, u32),
+    NamespaceDescriptors(u32),
 }
 
+const OPCODE_FLUSH: u8 = 0;
 const OPCODE_READ: u8 = 2;
 const OPCODE_WRITE: u8 = 1;
+const OPCODE_COMPARE: u8 = 0x5;
+const OPCODE_WRITE_ZEROES: u8 = 0x8;
+const OPCODE_DATASET_MANAGEMENT: u8 = 0x9;
+const OPCODE_RESERVATION_REGISTER: u8 = 0x0D;
+const OPCODE_RESERVATION_REPORT: u8 = 0x0E;
+const OPCODE_RESERVATION_ACQUIRE: u8 = 0x11;
+const OPCODE_RESERVATION_RELEASE: u8 = 0x15;
+const OPCODE_ZONE_MANAGEMENT_SEND: u8 = 0x79;
+const OPCODE_ZONE_MANAGEMENT_RECEIVE: u8 = 0x7A;
+const OPCODE_ZONE_APPEND: u8 = 0x7D;
 const OPCODE_IDENTIFY: u8 = 6;
 const OPCODE_SUB_QUEUE_CREATE: u8 = 1;
 const OPCODE_COMP_QUEUE_CREATE: u8 = 5;
 const OPCODE_SUB_QUEUE_DELETE: u8 = 0;
 const OPCODE_COMP_QUEUE_DELETE: u8 = 4;
+const OPCODE_GET_LOG_PAGE: u8 = 0x2;
+const OPCODE_ABORT: u8 = 0x8;
+const OPCODE_ASYNC_EVENT_REQUEST: u8 = 0xC;
+const OPCODE_FORMAT_NVM: u8 = 0x80;
+const OPCODE_SET_FEATURES: u8 = 0x9;
+const OPCODE_GET_FEATURES: u8 = 0xA;
+const OPCODE_FIRMWARE_COMMIT: u8 = 0x10;
+const OPCODE_FIRMWARE_DOWNLOAD: u8 = 0x11;
+const OPCODE_DEVICE_SELF_TEST: u8 = 0x14;
+const OPCODE_NAMESPACE_MANAGEMENT: u8 = 0x0D;
+const OPCODE_NAMESPACE_ATTACHMENT: u8 = 0x15;
+const OPCODE_SANITIZE: u8 = 0x84;
+const OPCODE_KEEP_ALIVE: u8 = 0x18;
+const OPCODE_DOORBELL_BUFFER_CONFIG: u8 = 0x7C;
 
 impl Command {
+    pub fn raw(cmd_id: u16, raw: &RawCommand, data_ptr: [u64; 2]) -> Self {
+        Self {
+            opcode: raw.opcode,
+            cmd_id,
+            ns_id: raw.ns_id,
+            data_ptr,
+            cmd_10: raw.cdw[0],
+            cmd_11: raw.cdw[1],
+            cmd_12: raw.cdw[2],
+            cmd_13: raw.cdw[3],
+            cmd_14: raw.cdw[4],
+            cmd_15: raw.cdw[5],
+            ..Default::default()
+        }
+    }
+
     pub fn read_write(
         cmd_id: u16,
         ns_id: u32,
@@ -66,19 +178,251 @@ impl Command {
         }
     }
 
+    pub fn flush(cmd_id: u16, ns_id: u32) -> Self {
+        Self {
+            opcode: OPCODE_FLUSH,
+            cmd_id,
+            ns_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn compare(
+        cmd_id: u16,
+        ns_id: u32,
+        lba: u64,
+        block_count: u16,
+        data_ptr: [u64; 2],
+    ) -> Self {
+        Self {
+            opcode: OPCODE_COMPARE,
+            cmd_id,
+            ns_id,
+            data_ptr,
+            cmd_10: lba as u32,
+            cmd_11: (lba >> 32) as u32,
+            cmd_12: block_count as u32,
+            ..Default::default()
+        }
+    }
+
+    pub fn write_zeroes(cmd_id: u16, ns_id: u32, slba: u64, block_count: u16, deac: bool) -> Self {
+        Self {
+            opcode: OPCODE_WRITE_ZEROES,
+            cmd_id,
+            ns_id,
+            cmd_10: slba as u32,
+            cmd_11: (slba >> 32) as u32,
+            cmd_12: ((deac as u32) << 25) | block_count as u32,
+            ..Default::default()
+        }
+    }
+
+    pub fn dataset_management(
+        cmd_id: u16,
+        ns_id: u32,
+        prp: [u64; 2],
+        range_count: u16,
+        attributes: u32,
+    ) -> Self {
+        Self {
+            opcode: OPCODE_DATASET_MANAGEMENT,
+            cmd_id,
+            ns_id,
+            data_ptr: prp,
+            cmd_10: (range_count as u32 - 1) & 0xFF,
+            cmd_11: attributes,
+            ..Default::default()
+        }
+    }
+
+    /// Get the command identifier.
+    pub fn cmd_id(&self) -> u16 {
+        self.cmd_id
+    }
+
+    /// Set the Force Unit Access (FUA) and Limited Retry (LR) bits.
+    pub fn with_write_options(mut self, fua: bool, limited_retry: bool) -> Self {
+        self.cmd_12 =
+            (self.cmd_12 & !(0x3 << 30)) | (fua as u32) << 30 | (limited_retry as u32) << 31;
+        self
+    }
+
+    /// Set the metadata pointer to a separate metadata buffer.
+    pub fn with_metadata(mut self, address: usize) -> Self {
+        self.md_ptr = address as u64;
+        self
+    }
+
+    /// Set the protection information field (PRINFO) and the expected tags.
+    pub fn with_protection(
+        mut self,
+        prinfo: u8,
+        reference_tag: u32,
+        app_tag: u16,
+        app_tag_mask: u16,
+    ) -> Self {
+        self.cmd_12 = (self.cmd_12 & !(0xF << 26)) | ((prinfo as u32 & 0xF) << 26);
+        self.cmd_14 = reference_tag;
+        self.cmd_15 = app_tag as u32 | (app_tag_mask as u32) << 16;
+        self
+    }
+
+    /// Set the fused operation (FUSE), 1 for the first
+    /// and 2 for the second command of a fused pair.
+    pub fn with_fuse(mut self, fuse: u8) -> Self {
+        self.flags = (self.flags & !0x3) | (fuse & 0x3);
+        self
+    }
+
+    /// Use an SGL instead of PRPs for the data pointer (PSDT = 01).
+    pub fn with_sgl(mut self) -> Self {
+        self.flags = (self.flags & !(0x3 << 6)) | (0x1 << 6);
+        self
+    }
+
+    pub fn with_access_latency(mut self, latency: u8) -> Self {
+        self.cmd_13 = (self.cmd_13 & !(0x3 << 4)) | ((latency as u32 & 0x3) << 4);
+        self
+    }
+
+    pub fn reservation_register(
+        cmd_id: u16,
+        ns_id: u32,
+        data_ptr: [u64; 2],
+        action: u8,
+        ignore_key: bool,
+    ) -> Self {
+        Self {
+            opcode: OPCODE_RESERVATION_REGISTER,
+            cmd_id,
+            ns_id,
+            data_ptr,
+            cmd_10: (ignore_key as u32) << 3 | action as u32 & 0x7,
+            ..Default::default()
+        }
+    }
+
+    pub fn reservation_acquire(
+        cmd_id: u16,
+        ns_id: u32,
+        data_ptr: [u64; 2],
+        action: u8,
+        rtype: u8,
+    ) -> Self {
+        Self {
+            opcode: OPCODE_RESERVATION_ACQUIRE,
+            cmd_id,
+            ns_id,
+            data_ptr,
+            cmd_10: (rtype as u32) << 8 | action as u32 & 0x7,
+            ..Default::default()
+        }
+    }
+
+    pub fn reservation_release(
+        cmd_id: u16,
+        ns_id: u32,
+        data_ptr: [u64; 2],
+        action: u8,
+        rtype: u8,
+    ) -> Self {
+        Self {
+            opcode: OPCODE_RESERVATION_RELEASE,
+            cmd_id,
+            ns_id,
+            data_ptr,
+            cmd_10: (rtype as u32) << 8 | action as u32 & 0x7,
+            ..Default::default()
+        }
+    }
+
+    pub fn reservation_report(cmd_id: u16, ns_id: u32, data_ptr: [u64; 2], dwords: u32) -> Self {
+        Self {
+            opcode: OPCODE_RESERVATION_REPORT,
+            cmd_id,
+            ns_id,
+            data_ptr,
+            cmd_10: dwords - 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn zone_management_receive(
+        cmd_id: u16,
+        ns_id: u32,
+        slba: u64,
+        data_ptr: [u64; 2],
+        dwords: u32,
+        action: u8,
+        partial: bool,
+    ) -> Self {
+        Self {
+            opcode: OPCODE_ZONE_MANAGEMENT_RECEIVE,
+            cmd_id,
+            ns_id,
+            data_ptr,
+            cmd_10: slba as u32,
+            cmd_11: (slba >> 32) as u32,
+            cmd_12: dwords - 1,
+            cmd_13: (partial as u32) << 16 | action as u32,
+            ..Default::default()
+        }
+    }
+
+    pub fn zone_management_send(
+        cmd_id: u16,
+        ns_id: u32,
+        slba: u64,
+        action: u8,
+        select_all: bool,
+    ) -> Self {
+        Self {
+            opcode: OPCODE_ZONE_MANAGEMENT_SEND,
+            cmd_id,
+            ns_id,
+            cmd_10: slba as u32,
+            cmd_11: (slba >> 32) as u32,
+            cmd_13: (select_all as u32) << 8 | action as u32,
+            ..Default::default()
+        }
+    }
+
+    pub fn zone_append(
+        cmd_id: u16,
+        ns_id: u32,
+        zslba: u64,
+        block_count: u16,
+        data_ptr: [u64; 2],
+    ) -> Self {
+        Self {
+            opcode: OPCODE_ZONE_APPEND,
+            cmd_id,
+            ns_id,
+            data_ptr,
+            cmd_10: zslba as u32,
+            cmd_11: (zslba >> 32) as u32,
+            cmd_12: block_count as u32,
+            ..Default::default()
+        }
+    }
+
     pub fn create_submission_queue(
         cmd_id: u16,
         queue_id: u16,
         address: usize,
         size: u16,
         cqueue_id: u16,
+        priority: u8,
+        contiguous: bool,
     ) -> Command {
         Self {
             opcode: OPCODE_SUB_QUEUE_CREATE,
             cmd_id,
             data_ptr: [address as u64, 0],
             cmd_10: ((size as u32) << 16) | (queue_id as u32),
-            cmd_11: ((cqueue_id as u32) << 16) | 1,
+            // Physically contiguous (PC) and the priority (QPRIO).
+            cmd_11: ((cqueue_id as u32) << 16) | ((priority as u32 & 0x3) << 1) | contiguous as u32,
             ..Default::default()
         }
     }
@@ -88,13 +432,19 @@ impl Command {
         queue_id: u16,
         address: usize,
         size: u16,
+        interrupt_vector: u16,
+        interrupts_enabled: bool,
+        contiguous: bool,
     ) -> Command {
         Self {
             opcode: OPCODE_COMP_QUEUE_CREATE,
             cmd_id,
             data_ptr: [address as u64, 0],
             cmd_10: ((size as u32) << 16) | (queue_id as u32),
-            cmd_11: 1,
+            // Physically contiguous (PC), interrupts enabled (IEN) and the vector (IV).
+            cmd_11: ((interrupt_vector as u32) << 16)
+                | ((interrupts_enabled as u32) << 1)
+                | contiguous as u32,
             ..Default::default()
         }
     }
@@ -121,7 +471,9 @@ impl Command {
         let (ns_id, cmd_10) = match target {
             IdentifyType::Namespace(id) => (id, 0),
             IdentifyType::Controller => (0, 1),
-            IdentifyType::NamespaceList(base) => (base, 2),
+            IdentifyType::NamespaceList(NamespaceList::Active, base) => (base, 0x02),
+            IdentifyType::NamespaceList(NamespaceList::Allocated, base) => (base, 0x10),
+            IdentifyType::NamespaceDescriptors(id) => (id, 3),
         };
 
         Self {
@@ -133,4 +485,187 @@ impl Command {
             ..Default::default()
         }
     }
+
+    pub fn get_features(cmd_id: u16, ns_id: u32, address: usize, fid: u8, select: u8) -> Self {
+        Self {
+            opcode: OPCODE_GET_FEATURES,
+            cmd_id,
+            ns_id,
+            data_ptr: [address as u64, 0],
+            cmd_10: ((select as u32 & 0x7) << 8) | fid as u32,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_features(
+        cmd_id: u16,
+        ns_id: u32,
+        address: usize,
+        fid: u8,
+        save: bool,
+        value: u32,
+    ) -> Self {
+        Self {
+            opcode: OPCODE_SET_FEATURES,
+            cmd_id,
+            ns_id,
+            data_ptr: [address as u64, 0],
+            cmd_10: ((save as u32) << 31) | fid as u32,
+            cmd_11: value,
+            ..Default::default()
+        }
+    }
+
+    pub fn get_log_page(
+        cmd_id: u16,
+        address: usize,
+        log_id: u8,
+        lsp: u8,
+        dwords: u32,
+        offset: u64,
+    ) -> Self {
+        let numd = dwords - 1;
+        Self {
+            opcode: OPCODE_GET_LOG_PAGE,
+            cmd_id,
+            ns_id: 0xFFFF_FFFF,
+            data_ptr: [address as u64, 0],
+            cmd_10: ((numd & 0xFFFF) << 16) | ((lsp as u32 & 0x7F) << 8) | log_id as u32,
+            cmd_11: numd >> 16,
+            cmd_12: offset as u32,
+            cmd_13: (offset >> 32) as u32,
+            ..Default::default()
+        }
+    }
+
+    pub fn firmware_download(cmd_id: u16, data_ptr: [u64; 2], dwords: u32, offset: u32) -> Self {
+        Self {
+            opcode: OPCODE_FIRMWARE_DOWNLOAD,
+            cmd_id,
+            data_ptr,
+            cmd_10: dwords - 1,
+            cmd_11: offset,
+            ..Default::default()
+        }
+    }
+
+    pub fn firmware_commit(cmd_id: u16, slot: u8, action: u8) -> Self {
+        Self {
+            opcode: OPCODE_FIRMWARE_COMMIT,
+            cmd_id,
+            cmd_10: ((action as u32 & 0x7) << 3) | (slot as u32 & 0x7),
+            ..Default::default()
+        }
+    }
+
+    pub fn abort(cmd_id: u16, sqid: u16, cid_to_abort: u16) -> Self {
+        Self {
+            opcode: OPCODE_ABORT,
+            cmd_id,
+            cmd_10: (cid_to_abort as u32) << 16 | sqid as u32,
+            ..Default::default()
+        }
+    }
+
+    pub fn device_self_test(cmd_id: u16, ns_id: u32, stc: u8) -> Self {
+        Self {
+            opcode: OPCODE_DEVICE_SELF_TEST,
+            cmd_id,
+            ns_id,
+            cmd_10: stc as u32 & 0xF,
+            ..Default::default()
+        }
+    }
+
+    pub fn sanitize(
+        cmd_id: u16,
+        sanact: u8,
+        ause: bool,
+        owpass: u8,
+        oipbp: bool,
+        ndas: bool,
+        pattern: u32,
+    ) -> Self {
+        Self {
+            opcode: OPCODE_SANITIZE,
+            cmd_id,
+            cmd_10: (ndas as u32) << 9
+                | (oipbp as u32) << 8
+                | (owpass as u32 & 0xF) << 4
+                | (ause as u32) << 3
+                | sanact as u32 & 0x7,
+            cmd_11: pattern,
+            ..Default::default()
+        }
+    }
+
+    pub fn namespace_management(cmd_id: u16, ns_id: u32, sel: u8, address: usize) -> Self {
+        Self {
+            opcode: OPCODE_NAMESPACE_MANAGEMENT,
+            cmd_id,
+            ns_id,
+            data_ptr: [address as u64, 0],
+            cmd_10: sel as u32 & 0xF,
+            ..Default::default()
+        }
+    }
+
+    pub fn namespace_attachment(cmd_id: u16, ns_id: u32, sel: u8, address: usize) -> Self {
+        Self {
+            opcode: OPCODE_NAMESPACE_ATTACHMENT,
+            cmd_id,
+            ns_id,
+            data_ptr: [address as u64, 0],
+            cmd_10: sel as u32 & 0xF,
+            ..Default::default()
+        }
+    }
+
+    pub fn keep_alive(cmd_id: u16) -> Self {
+        Self {
+            opcode: OPCODE_KEEP_ALIVE,
+            cmd_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn doorbell_buffer_config(cmd_id: u16, data_ptr: [u64; 2]) -> Self {
+        Self {
+            opcode: OPCODE_DOORBELL_BUFFER_CONFIG,
+            cmd_id,
+            data_ptr,
+            ..Default::default()
+        }
+    }
+
+    pub fn async_event_request(cmd_id: u16) -> Self {
+        Self {
+            opcode: OPCODE_ASYNC_EVENT_REQUEST,
+            cmd_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn format_nvm(
+        cmd_id: u16,
+        ns_id: u32,
+        lbaf: u8,
+        ses: u8,
+        pil: bool,
+        pi: u8,
+        mset: bool,
+    ) -> Self {
+        Self {
+            opcode: OPCODE_FORMAT_NVM,
+            cmd_id,
+            ns_id,
+            cmd_10: ((lbaf as u32 >> 4 & 0x3) << 12)
+                | ((ses as u32 & 0x7) << 9)
+                | ((pil as u32) << 8)
+                | ((pi as u32 & 0x7) << 5)
+                | ((mset as u32) << 4)
+                | (lbaf as u32 & 0xF),
+            ..Default::default()
+        }
+    }
 }
@@ -1,13 +1,22 @@
+use alloc::collections::btree_set::BTreeSet;
+use alloc::collections::vec_deque::VecDeque;
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::hint::spin_loop;
+use core::fmt::{self, Display};
 
-use crate::cmd::{Command, IdentifyType};
-use crate::error::{Error, Result};
+use crate::cmb::{Cmb, CmbInfo};
+use crate::cmd::{Command, IdentifyType, NamespaceList, RawCommand};
+use crate::error::{DeleteQueuePairError, Error, Result};
+use crate::events::{ASYNC_EVENT_CMD_ID, AsyncEvents};
+use crate::features::{FEATURE_NUMBER_OF_QUEUES, QueuePriority};
 use crate::io::{IoQueueId, IoQueuePair};
-use crate::memory::{Allocator, Dma};
+use crate::memory::{Allocator, Dma, PrpManager};
+use crate::mmio::{Mmio, MmioRegion, RegisterValue};
+use crate::power::PowerStateDescriptor;
 use crate::queues::{CompQueue, Completion, SubQueue};
+use crate::shadow::ShadowDoorbells;
+use crate::timeout::{ADMIN_TIMEOUT_MS, Timeout};
 
 /// Default size of an admin queue.
 ///
@@ -15,6 +24,50 @@ use crate::queues::{CompQueue, Completion, SubQueue};
 /// which is usually enough for most cases.
 const ADMIN_QUEUE_SIZE: usize = 64;
 
+/// Number of I/O queue pairs requested from the controller.
+///
+/// The controller may grant fewer, see `ControllerData::max_io_queues`.
+const IO_QUEUE_COUNT: u16 = 64;
+
+/// OACS bit: Format NVM is supported.
+const OACS_FORMAT: u16 = 1 << 1;
+
+/// OACS bit: Namespace Management and Attachment are supported.
+const OACS_NAMESPACE_MANAGEMENT: u16 = 1 << 3;
+
+/// OACS bit: Device Self-test is supported.
+const OACS_SELF_TEST: u16 = 1 << 4;
+
+/// OACS bit: Doorbell Buffer Config is supported.
+const OACS_DOORBELL_BUFFER_CONFIG: u16 = 1 << 8;
+
+/// ONCS bit: Compare is supported.
+pub(crate) const ONCS_COMPARE: u16 = 1;
+
+/// ONCS bit: Dataset Management is supported.
+pub(crate) const ONCS_DATASET_MANAGEMENT: u16 = 1 << 2;
+
+/// ONCS bit: Write Zeroes is supported.
+pub(crate) const ONCS_WRITE_ZEROES: u16 = 1 << 3;
+
+/// ONCS bit: reservations are supported.
+const ONCS_RESERVATIONS: u16 = 1 << 5;
+
+/// ONCS bit: the Timestamp feature is supported.
+const ONCS_TIMESTAMP: u16 = 1 << 6;
+
+/// FUSES bit: the fused Compare and Write operation is supported.
+const FUSES_COMPARE_AND_WRITE: u16 = 1;
+
+/// Command set identifier of the Zoned Namespace command set.
+const CSI_ZONED: u8 = 2;
+
+/// Maximum number of IDs in a namespace list.
+pub(crate) const NAMESPACE_LIST_SIZE: usize = 1024;
+
+/// The value written to NSSR to initiate an NVM subsystem reset ("NVMe").
+pub(crate) const NSSR_RESET: u32 = 0x4E56_4D65;
+
 /// NVMe controller registers.
 #[derive(Debug)]
 #[allow(unused, clippy::upper_case_acronyms)]
@@ -39,6 +92,12 @@ pub enum Register {
     ASQ = 0x28,
     /// Admin Completion Queue Base Address
     ACQ = 0x30,
+    /// Controller Memory Buffer Location
+    CMBLOC = 0x38,
+    /// Controller Memory Buffer Size
+    CMBSZ = 0x3C,
+    /// Controller Memory Buffer Memory Space Control
+    CMBMSC = 0x50,
 }
 
 /// NVMe doorbell register.
@@ -53,42 +112,102 @@ pub(crate) enum Doorbell {
 /// It is separate so that the `IoQueuePair` can reference it
 /// instead of the entire controller, thus not resulting into
 /// the problem of creating mutable references multiple times.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub(crate) struct DoorbellHelper {
-    address: usize,
+    mmio: Arc<dyn Mmio>,
     stride: u8,
+    queue_count: u16,
+    shadow: Option<ShadowDoorbells>,
 }
 
 impl DoorbellHelper {
     /// Create a new `DoorbellHelper` instance.
-    pub fn new(address: usize, stride: u8) -> Self {
-        Self { address, stride }
+    ///
+    /// It covers the admin queue pair and up to `queue_count` I/O queue pairs.
+    pub fn new(mmio: Arc<dyn Mmio>, stride: u8, queue_count: u16) -> Self {
+        Self {
+            mmio,
+            stride,
+            queue_count,
+            shadow: None,
+        }
+    }
+
+    /// Use shadow doorbells for the I/O queues from now on.
+    pub fn set_shadow(&mut self, shadow: ShadowDoorbells) {
+        self.shadow = Some(shadow);
+    }
+
+    /// Reset the shadow doorbells of I/O queue `qid`, if they are used.
+    pub fn clear_shadow(&self, qid: u16) {
+        if let Some(shadow) = &self.shadow {
+            let index = qid as usize * 2 * self.stride() / 4;
+            shadow.clear(index);
+            shadow.clear(index + self.stride() / 4);
+        }
+    }
+
+    /// Get the distance between two doorbell registers (in bytes).
+    pub fn stride(&self) -> usize {
+        4 << self.stride
+    }
+
+    /// Check whether the controller reported a fatal status (CSTS.CFS).
+    pub fn is_fatal(&self) -> bool {
+        self.mmio.read32(Register::CSTS as usize) & (1 << 1) != 0
     }
 
     /// Write a value to specified doorbell register.
     pub fn write(&self, bell: Doorbell, val: u32) {
-        let stride = 4 << self.stride;
-        let base = self.address + 0x1000;
-        let index = match bell {
-            Doorbell::SubTail(qid) => qid * 2,
-            Doorbell::CompHead(qid) => qid * 2 + 1,
+        let (qid, index) = match bell {
+            Doorbell::SubTail(qid) => (qid, qid as usize * 2),
+            Doorbell::CompHead(qid) => (qid, qid as usize * 2 + 1),
         };
+        debug_assert!(
+            index < 2 * (self.queue_count as usize + 1),
+            "doorbell index {index} out of range"
+        );
 
-        let addr = base + (index * stride) as usize;
-        unsafe { (addr as *mut u32).write_volatile(val) }
+        // The admin queue always uses the doorbell registers.
+        let offset = index * self.stride();
+        if let Some(shadow) = &self.shadow
+            && qid != 0
+            && !shadow.update(offset / 4, val)
+        {
+            return;
+        }
+        self.mmio.write32(0x1000 + offset, val);
     }
 }
 
 /// NVMe namespace data structure.
 #[derive(Debug, Clone)]
 #[repr(C, packed)]
-struct NamespaceData {
-    _ignore1: u64,
+pub(crate) struct NamespaceData {
+    size: u64,
     capacity: u64,
-    _ignore2: [u8; 10],
+    utilization: u64,
+    features: u8,
+    pub lba_format_count: u8,
     lba_size: u8,
-    _ignore3: [u8; 101],
-    lba_format_support: [u32; 16],
+    _ignore4: [u8; 2],
+    data_protection: u8,
+    _ignore7: u8,
+    reservation_capabilities: u8,
+    _ignore8: [u8; 2],
+    atomic_write_normal: u16,
+    atomic_write_power_fail: u16,
+    _ignore5: [u8; 4],
+    atomic_boundary_offset: u16,
+    atomic_boundary_power_fail: u16,
+    optimal_io_boundary: u16,
+    _ignore6: [u8; 16],
+    preferred_write_granularity: u16,
+    preferred_write_alignment: u16,
+    _ignore9: [u8; 31],
+    attributes: u8,
+    _ignore10: [u8; 28],
+    pub lba_format_support: [u32; 16],
 }
 
 /// A data structure that holds some
@@ -98,6 +217,14 @@ struct NamespaceData {
 /// identified controller data structure.
 #[derive(Default, Debug, Clone)]
 pub struct ControllerData {
+    /// PCI vendor ID
+    pub vendor_id: u16,
+    /// PCI subsystem vendor ID
+    pub subsystem_vendor_id: u16,
+    /// IEEE OUI identifier of the vendor
+    pub ieee_oui: [u8; 3],
+    /// Controller ID, unique within the NVM subsystem
+    pub controller_id: u16,
     /// Serial number
     pub serial_number: String,
     /// Model number
@@ -107,11 +234,193 @@ pub struct ControllerData {
     /// Maximum transfer size (in bytes)
     pub max_transfer_size: usize,
     /// Minimum page size (in bytes)
+    ///
+    /// The controller is configured to use this as its memory page size.
     pub min_pagesize: usize,
     /// Maximum queue entries
+    ///
+    /// This is the largest accepted `len` of `Device::create_io_queue_pair`.
     pub max_queue_entries: u16,
+    /// Maximum number of I/O queue pairs granted by the controller
+    pub max_io_queues: u16,
+    /// Optional admin commands supported by the controller (OACS)
+    pub optional_admin_commands: u16,
+    /// Optional NVM commands and features supported by the controller (ONCS)
+    pub optional_nvm_commands: u16,
+    /// Fused operations supported by the controller (FUSES)
+    pub fused_operations: u16,
+    /// Log page attributes of the controller (LPA)
+    pub log_page_attributes: u8,
+    /// The power states supported by the controller, see `Device::set_power_state`
+    pub power_states: Vec<PowerStateDescriptor>,
+    /// Granularity of the keep alive timeout (in milliseconds)
+    ///
+    /// It is 0 if the controller does not support keep alive.
+    pub keep_alive_granularity_ms: u32,
+    /// Keep alive timeout negotiated with the controller (in milliseconds)
+    ///
+    /// It is 0 if keep alive is not used, see `Device::enable_keep_alive`.
+    pub keep_alive_timeout_ms: u32,
+    /// Sanitize operations supported by the controller (SANICAP)
+    pub sanitize_capabilities: u32,
+    /// Granularity of firmware image downloads (in bytes)
+    ///
+    /// The offset and size of each downloaded part must be a multiple of it,
+    /// except for the last part of the image.
+    pub firmware_update_granularity: usize,
+    /// Maximum number of outstanding Abort commands
+    pub abort_command_limit: u16,
+    /// Maximum number of outstanding Asynchronous Event Requests
+    pub max_async_events: u16,
+    /// Maximum namespace ID supported by the controller
+    pub number_of_namespaces: u32,
+    /// SGLs are supported for I/O commands
+    pub sgl_supported: bool,
+    /// SGL data blocks must be aligned to a dword
+    pub sgl_dword_aligned: bool,
     /// Host memory buffer size (in bytes)
     pub hmb_size: u32,
+    /// Atomic write unit during normal operation (in blocks)
+    pub atomic_write_unit_normal: u32,
+    /// Atomic write unit during a power failure (in blocks)
+    pub atomic_write_unit_power_fail: u32,
+}
+
+impl ControllerData {
+    /// Checks whether the Format NVM command is supported.
+    pub fn supports_format(&self) -> bool {
+        self.optional_admin_commands & OACS_FORMAT != 0
+    }
+
+    /// Checks whether the Namespace Management and Attachment commands are supported.
+    pub fn supports_namespace_management(&self) -> bool {
+        self.optional_admin_commands & OACS_NAMESPACE_MANAGEMENT != 0
+    }
+
+    /// Checks whether the Device Self-test command is supported.
+    pub fn supports_self_test(&self) -> bool {
+        self.optional_admin_commands & OACS_SELF_TEST != 0
+    }
+
+    /// Checks whether the Doorbell Buffer Config command is supported.
+    pub fn supports_doorbell_buffer_config(&self) -> bool {
+        self.optional_admin_commands & OACS_DOORBELL_BUFFER_CONFIG != 0
+    }
+
+    /// Checks whether the Compare command is supported.
+    pub fn supports_compare(&self) -> bool {
+        self.optional_nvm_commands & ONCS_COMPARE != 0
+    }
+
+    /// Checks whether the Dataset Management command is supported.
+    pub fn supports_dataset_management(&self) -> bool {
+        self.optional_nvm_commands & ONCS_DATASET_MANAGEMENT != 0
+    }
+
+    /// Checks whether the Write Zeroes command is supported.
+    pub fn supports_write_zeroes(&self) -> bool {
+        self.optional_nvm_commands & ONCS_WRITE_ZEROES != 0
+    }
+
+    /// Checks whether reservations are supported.
+    pub fn supports_reservations(&self) -> bool {
+        self.optional_nvm_commands & ONCS_RESERVATIONS != 0
+    }
+
+    /// Checks whether the Timestamp feature is supported.
+    pub fn supports_timestamp(&self) -> bool {
+        self.optional_nvm_commands & ONCS_TIMESTAMP != 0
+    }
+
+    /// Checks whether the fused Compare and Write operation is supported.
+    pub fn supports_compare_and_write(&self) -> bool {
+        self.fused_operations & FUSES_COMPARE_AND_WRITE != 0
+    }
+}
+
+/// The capabilities of a controller, reported by the CAP register.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerCapabilities {
+    /// Maximum number of entries of an I/O queue (MQES + 1)
+    pub max_queue_entries: u32,
+    /// The queues must be physically contiguous (CQR)
+    pub contiguous_queues_required: bool,
+    /// Supported arbitration mechanisms besides round robin (AMS)
+    ///
+    /// Bit 0 is weighted round robin with urgent priority class,
+    /// bit 1 is vendor specific.
+    pub arbitration_mechanisms: u8,
+    /// Worst case time to change the ready state (in milliseconds)
+    pub timeout_ms: u32,
+    /// Distance between two doorbell registers (in bytes)
+    pub doorbell_stride: usize,
+    /// NVM subsystem reset is supported (NSSRS)
+    pub subsystem_reset: bool,
+    /// The NVM command set is supported
+    pub nvm_command_set: bool,
+    /// Boot partitions are supported (BPS)
+    pub boot_partitions: bool,
+    /// The CMB has to be enabled through CMBMSC (CMBS)
+    pub cmb_supported: bool,
+    /// Minimum memory page size (in bytes)
+    pub min_page_size: usize,
+    /// Maximum memory page size (in bytes)
+    pub max_page_size: usize,
+}
+
+impl ControllerCapabilities {
+    /// Parses the value of the CAP register.
+    pub fn from_raw(cap: u64) -> Self {
+        Self {
+            max_queue_entries: (cap & 0xFFFF) as u32 + 1,
+            contiguous_queues_required: cap & (1 << 16) != 0,
+            arbitration_mechanisms: (cap >> 17) as u8 & 0x3,
+            timeout_ms: ((cap >> 24) & 0xFF) as u32 * 500,
+            doorbell_stride: 4 << ((cap >> 32) & 0xF),
+            subsystem_reset: cap & (1 << 36) != 0,
+            nvm_command_set: cap & (1 << 37) != 0,
+            boot_partitions: cap & (1 << 45) != 0,
+            cmb_supported: cap & (1 << 57) != 0,
+            min_page_size: 1 << (((cap >> 48) & 0xF) + 12),
+            max_page_size: 1 << (((cap >> 52) & 0xF) + 12),
+        }
+    }
+}
+
+/// The version of the NVMe specification supported by a controller.
+///
+/// Versions are ordered, so features can be gated on the reported
+/// level, e.g. `device.version() >= Version::new(1, 3, 0)`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    /// Major version number
+    pub major: u16,
+    /// Minor version number
+    pub minor: u8,
+    /// Tertiary version number
+    pub tertiary: u8,
+}
+
+impl Version {
+    /// Creates a new version.
+    pub const fn new(major: u16, minor: u8, tertiary: u8) -> Self {
+        Self {
+            major,
+            minor,
+            tertiary,
+        }
+    }
+
+    /// Parses the value of the VS register.
+    pub fn from_raw(vs: u32) -> Self {
+        Self::new((vs >> 16) as u16, (vs >> 8) as u8, vs as u8)
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.tertiary)
+    }
 }
 
 /// A structure representing an NVMe namespace.
@@ -120,6 +429,18 @@ pub struct Namespace {
     id: u32,
     block_count: u64,
     block_size: u64,
+    capacity_blocks: u64,
+    used_blocks: u64,
+    metadata_size: u16,
+    extended_metadata: bool,
+    protection_type: u8,
+    reservation_capabilities: u8,
+    atomic_write_blocks: u32,
+    atomic_boundary: Option<(u64, u64)>,
+    optimal_boundary: Option<u64>,
+    optimal_write: Option<(u64, u64)>,
+    read_only: bool,
+    command_set: u8,
 }
 
 impl Namespace {
@@ -137,17 +458,200 @@ impl Namespace {
     pub fn block_size(&self) -> u64 {
         self.block_size
     }
+
+    /// Get the capacity (in blocks).
+    ///
+    /// This is the number of blocks that may be allocated at a time,
+    /// which is less than the block count for thin provisioned namespaces.
+    pub fn capacity_blocks(&self) -> u64 {
+        self.capacity_blocks
+    }
+
+    /// Get the number of blocks currently allocated.
+    ///
+    /// Deallocated blocks are not counted, so this reflects
+    /// the actual usage of a thin provisioned namespace.
+    pub fn used_blocks(&self) -> u64 {
+        self.used_blocks
+    }
+
+    /// Get the size of the metadata of each block (in bytes).
+    ///
+    /// It is 0 if the namespace is formatted without metadata.
+    pub fn metadata_size(&self) -> u16 {
+        self.metadata_size
+    }
+
+    /// Checks whether the metadata is transferred at the end of each block,
+    /// instead of in a separate buffer.
+    pub fn extended_metadata(&self) -> bool {
+        self.extended_metadata
+    }
+
+    /// Get the type of end-to-end protection information (1 to 3).
+    ///
+    /// It is 0 if the namespace is not formatted with protection information.
+    pub fn protection_type(&self) -> u8 {
+        self.protection_type
+    }
+
+    /// Get the reservation capabilities of the namespace (RESCAP).
+    ///
+    /// It is 0 if the namespace does not support reservations.
+    pub fn reservation_capabilities(&self) -> u8 {
+        self.reservation_capabilities
+    }
+
+    /// Get the number of blocks that are written atomically.
+    ///
+    /// A write of at most this many blocks, which does not cross an
+    /// atomic boundary, is guaranteed not to be torn even on power failure.
+    pub fn atomic_write_blocks(&self) -> u32 {
+        self.atomic_write_blocks
+    }
+
+    /// Get the atomic boundary of the namespace.
+    ///
+    /// Returns the boundary size and the offset of the first boundary
+    /// (both in blocks) if the namespace reports one.
+    pub fn atomic_boundary(&self) -> Option<(u64, u64)> {
+        self.atomic_boundary
+    }
+
+    /// Get the optimal I/O boundary of the namespace (in blocks).
+    ///
+    /// Commands crossing a multiple of the boundary may be slower, so large
+    /// transfers are best split at these multiples, e.g. by making the first
+    /// chunk end on the next boundary and the following chunks a multiple of
+    /// it. Returns `None` if the namespace does not report one.
+    pub fn optimal_boundary(&self) -> Option<u64> {
+        self.optimal_boundary
+    }
+
+    /// Get the preferred write granularity of the namespace (in blocks).
+    ///
+    /// Writes whose size is a multiple of it, and which start at a multiple
+    /// of `optimal_write_alignment`, avoid a read-modify-write inside the
+    /// drive. Returns `None` if the namespace does not report it.
+    pub fn optimal_write_size(&self) -> Option<u64> {
+        self.optimal_write.map(|(size, _)| size)
+    }
+
+    /// Get the preferred write alignment of the namespace (in blocks).
+    ///
+    /// See `optimal_write_size` for more details.
+    pub fn optimal_write_alignment(&self) -> Option<u64> {
+        self.optimal_write.map(|(_, alignment)| alignment)
+    }
+
+    /// Checks whether the namespace is write protected (NSATTR).
+    ///
+    /// Writes to a read-only namespace fail with `Error::NamespaceReadOnly`
+    /// without being sent to the controller. The state is the one at the
+    /// time the namespace was identified.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Get the command set of the namespace (CSI).
+    ///
+    /// It is 0 for the NVM command set, which is assumed if the controller
+    /// does not report the command set.
+    pub fn command_set(&self) -> u8 {
+        self.command_set
+    }
+
+    /// Checks whether the namespace uses the Zoned Namespace command set.
+    pub fn is_zoned(&self) -> bool {
+        self.command_set == CSI_ZONED
+    }
+}
+
+/// The unique identifiers of an NVMe namespace.
+///
+/// A namespace reports any number of them, which stay the same
+/// across resets and controllers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NamespaceIds {
+    /// IEEE Extended Unique Identifier
+    pub eui64: Option<[u8; 8]>,
+    /// Namespace Globally Unique Identifier
+    pub nguid: Option<[u8; 16]>,
+    /// Namespace UUID
+    pub uuid: Option<[u8; 16]>,
+    /// Command set identifier (CSI), e.g. 0 for NVM and 2 for Zoned Namespace
+    pub command_set: Option<u8>,
+}
+
+/// An iterator over the active namespaces of a device.
+///
+/// Returned by `Device::namespaces`, it stops after the first error.
+pub struct NamespaceIter<'a, A: Allocator> {
+    device: &'a mut Device<A>,
+    /// The IDs fetched but not identified yet
+    ids: VecDeque<u32>,
+    /// The last fetched ID, from which the next list starts
+    base: u32,
+    done: bool,
+}
+
+impl<A: Allocator> Iterator for NamespaceIter<'_, A> {
+    type Item = Result<Namespace>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ids.is_empty() && !self.done {
+            match self.device.namespace_list(NamespaceList::Active, self.base) {
+                Ok(ids) => {
+                    // A list which is not full is the last one.
+                    self.done = ids.len() < NAMESPACE_LIST_SIZE;
+                    self.base = ids.last().copied().unwrap_or_default();
+                    self.ids.extend(ids);
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        let id = self.ids.pop_front()?;
+        let namespace = self.device.identify_namespace(id);
+        if namespace.is_err() {
+            self.ids.clear();
+            self.done = true;
+        }
+        Some(namespace)
+    }
 }
 
 /// A structure representing an NVMe controller device.
+///
+/// All admin operations (identify, queue creation and deletion) block
+/// until their command completes, but never longer than the admin timeout,
+/// after which `Error::ControllerTimeout` is returned.
 pub struct Device<A> {
-    address: *mut u8,
+    mmio: Arc<dyn Mmio>,
     pub(crate) allocator: Arc<A>,
     pub(crate) admin_sq: SubQueue,
-    admin_cq: CompQueue,
-    admin_buffer: Dma<u8>,
-    doorbell_helper: DoorbellHelper,
-    data: ControllerData,
+    pub(crate) admin_cq: CompQueue,
+    /// The command ID of the next admin command
+    next_cmd_id: u16,
+    pub(crate) admin_buffer: Dma<u8>,
+    pub(crate) prp_manager: PrpManager,
+    pub(crate) doorbell_helper: DoorbellHelper,
+    pub(crate) async_events: AsyncEvents,
+    pub(crate) io_queue_ids: BTreeSet<u16>,
+    pub(crate) shadow_buffers: Option<[Dma<u32>; 2]>,
+    pub(crate) cmb_info: Option<CmbInfo>,
+    pub(crate) cmb: Option<Cmb>,
+    shutdown_on_drop: bool,
+    /// I/O queues may be described by PRP lists
+    noncontiguous_queues: bool,
+    /// The keep alive timeout requested by the host, 0 if disabled
+    pub(crate) keep_alive_request_ms: u32,
+    capabilities: ControllerCapabilities,
+    version: Version,
+    pub(crate) data: ControllerData,
 }
 
 unsafe impl<A> Send for Device<A> {}
@@ -162,69 +666,50 @@ impl<A: Allocator> Device<A> {
     /// The `allocator` is a DMA allocator that implements
     /// the `Allocator` trait used for the entire NVMe device.
     pub fn init(address: usize, allocator: A) -> Result<Self> {
+        Self::init_with_mmio(MmioRegion::new(address), allocator)
+    }
+
+    /// Initialize a NVMe controller device with custom register access.
+    ///
+    /// All register and doorbell accesses go through `mmio` instead of
+    /// volatile accesses to a mapped address, see `init` for `allocator`.
+    pub fn init_with_mmio<M: Mmio + 'static>(mmio: M, allocator: A) -> Result<Self> {
+        let mmio: Arc<dyn Mmio> = Arc::new(mmio);
         let mut device = Self {
-            address: address as _,
+            mmio: mmio.clone(),
             admin_sq: SubQueue::new(ADMIN_QUEUE_SIZE, &allocator),
             admin_cq: CompQueue::new(ADMIN_QUEUE_SIZE, &allocator),
-            admin_buffer: Dma::allocate(4096, &allocator),
-            doorbell_helper: DoorbellHelper::new(address, 0),
+            next_cmd_id: 0,
+            admin_buffer: Dma::allocate_zeroed(4096, &allocator),
+            prp_manager: PrpManager::new(4096),
+            doorbell_helper: DoorbellHelper::new(mmio.clone(), 0, 0),
+            async_events: Default::default(),
+            io_queue_ids: BTreeSet::new(),
+            shadow_buffers: None,
+            cmb_info: None,
+            cmb: None,
+            shutdown_on_drop: true,
+            noncontiguous_queues: false,
+            keep_alive_request_ms: 0,
+            capabilities: Default::default(),
+            version: Default::default(),
             data: Default::default(),
             allocator: Arc::new(allocator),
         };
 
         let cap = device.get_reg::<u64>(Register::CAP);
+        device.capabilities = ControllerCapabilities::from_raw(cap);
+        device.version = Version::from_raw(device.get_reg::<u32>(Register::VS));
         let doorbell_stride = (cap >> 32) as u8 & 0xF;
-        device.data.min_pagesize = 1 << (((cap >> 48) as u8 & 0xF) + 12);
-        device.data.max_queue_entries = (cap & 0x7FFF) as u16 + 1;
-        device.doorbell_helper = DoorbellHelper::new(address, doorbell_stride);
-
-        device.set_reg::<u32>(Register::CC, device.get_reg::<u32>(Register::CC) & !1);
-        while device.get_reg::<u32>(Register::CSTS) & 1 == 1 {
-            spin_loop();
-        }
-
-        device.set_reg::<u64>(Register::ASQ, device.admin_sq.data.phys_addr as u64);
-        device.set_reg::<u64>(Register::ACQ, device.admin_cq.data.phys_addr as u64);
-        let aqa = (ADMIN_QUEUE_SIZE as u32 - 1) << 16 | (ADMIN_QUEUE_SIZE as u32 - 1);
-        device.set_reg::<u32>(Register::AQA, aqa);
-
-        let cc = device.get_reg::<u32>(Register::CC) & 0xFF00_000F;
-        device.set_reg::<u32>(Register::CC, cc | (4 << 20) | (6 << 16));
-
-        device.set_reg::<u32>(Register::CC, device.get_reg::<u32>(Register::CC) | 1);
-        while device.get_reg::<u32>(Register::CSTS) & 1 == 0 {
-            spin_loop();
-        }
-
-        device.exec_admin(Command::identify(
-            device.admin_sq.tail as u16,
-            device.admin_buffer.phys_addr,
-            IdentifyType::Controller,
-        ))?;
-
-        let extract_string = |start: usize, end: usize| -> String {
-            str::from_utf8(&device.admin_buffer[start..end])
-                .unwrap_or_default()
-                .trim()
-                .to_string()
-        };
-
-        device.data.serial_number = extract_string(4, 24);
-        device.data.model_number = extract_string(24, 64);
-        device.data.firmware_revision = extract_string(64, 72);
-
-        let extract_u32_number = |start: usize, end: usize| -> u32 {
-            let bytes = &device.admin_buffer[start..end];
-            u32::from_le_bytes(bytes.try_into().unwrap())
-        };
-
-        let hmpre = extract_u32_number(272, 276);
-        let hmmin = extract_u32_number(276, 280);
-        device.data.hmb_size = if hmpre != 0 { hmmin * 4096 } else { 0 };
-
-        let max_pages = 1 << device.admin_buffer[77];
-        device.data.max_transfer_size = max_pages as usize * device.data.min_pagesize;
+        device.data.min_pagesize = device.capabilities.min_page_size;
+        device.prp_manager = PrpManager::new(device.data.min_pagesize);
+        // Saturate the rare 65536 entries to fit.
+        let max_queue_entries = device.capabilities.max_queue_entries;
+        device.data.max_queue_entries = max_queue_entries.min(u16::MAX as u32) as u16;
+        device.doorbell_helper = DoorbellHelper::new(mmio, doorbell_stride, 0);
+        device.cmb_info = device.read_cmb_info();
 
+        device.initialize()?;
         Ok(device)
     }
 }
@@ -240,6 +725,36 @@ impl<A: Allocator> Device<A> {
     pub fn controller_data(&self) -> &ControllerData {
         &self.data
     }
+
+    /// Get the capabilities of the controller.
+    ///
+    /// They are read from the CAP register during initialization.
+    pub fn capabilities(&self) -> &ControllerCapabilities {
+        &self.capabilities
+    }
+
+    /// Get the NVMe version supported by the controller.
+    ///
+    /// It is read from the VS register during initialization.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Get the doorbell stride (in bytes).
+    ///
+    /// This is the distance between two adjacent doorbell registers,
+    /// decoded from the CAP.DSTRD field (`4 << DSTRD`).
+    pub fn doorbell_stride(&self) -> usize {
+        self.doorbell_helper.stride()
+    }
+
+    /// Check whether the controller reported a fatal status (CSTS.CFS).
+    ///
+    /// The controller does not process commands anymore, and `reset`
+    /// is required to recover it.
+    pub fn is_fatal(&self) -> bool {
+        self.get_reg::<u32>(Register::CSTS) & (1 << 1) != 0
+    }
 }
 
 impl<A: Allocator> Device<A> {
@@ -249,10 +764,102 @@ impl<A: Allocator> Device<A> {
     /// that contain information about each namespace which is supposed to
     /// be seen as a separate disk.
     pub fn identify_namespaces(&mut self, base: u32) -> Result<Vec<Namespace>> {
+        let ids = self.namespace_list(NamespaceList::Active, base)?;
+        ids.into_iter()
+            .map(|id| self.identify_namespace(id))
+            .collect()
+    }
+
+    /// Iterate over all active namespaces on the NVMe device.
+    ///
+    /// Unlike `identify_namespaces`, the namespaces are identified one at
+    /// a time and the namespace IDs are fetched in lists of up to 1024 as
+    /// needed, so stopping early saves the remaining admin commands.
+    pub fn namespaces(&mut self) -> NamespaceIter<'_, A> {
+        NamespaceIter {
+            device: self,
+            ids: VecDeque::new(),
+            base: 0,
+            done: false,
+        }
+    }
+
+    /// Identify the namespace `id`.
+    fn identify_namespace(&mut self, id: u32) -> Result<Namespace> {
+        self.admin_buffer.zero();
+        let cmd_id = self.next_cmd_id();
+        self.exec_admin(Command::identify(
+            cmd_id,
+            self.admin_buffer.phys_addr,
+            IdentifyType::Namespace(id),
+        ))?;
+
+        let data = unsafe { &*(self.admin_buffer.addr as *const NamespaceData) };
+        let flba_index = (data.lba_size & 0xF) as usize;
+        let flba_data = (data.lba_format_support[flba_index] >> 16) & 0xFF;
+        let metadata_size = data.lba_format_support[flba_index] as u16;
+
+        // The namespace atomicity fields are only valid if NSFEAT.NSABP is set.
+        let (atomic_write_blocks, atomic_boundary) = if data.features & (1 << 1) != 0 {
+            let boundary = (data.atomic_boundary_power_fail != 0).then(|| {
+                let size = data.atomic_boundary_power_fail as u64 + 1;
+                (size, data.atomic_boundary_offset as u64)
+            });
+            (data.atomic_write_power_fail as u32 + 1, boundary)
+        } else {
+            (self.data.atomic_write_unit_power_fail, None)
+        };
+        // The preferred write fields are only valid if NSFEAT.OPTPERF is set.
+        let optimal_write = (data.features & (1 << 4) != 0).then(|| {
+            let granularity = data.preferred_write_granularity as u64 + 1;
+            (granularity, data.preferred_write_alignment as u64 + 1)
+        });
+
+        let mut namespace = Namespace {
+            id,
+            block_size: 1 << flba_data,
+            block_count: data.size,
+            capacity_blocks: data.capacity,
+            used_blocks: data.utilization,
+            metadata_size,
+            extended_metadata: data.lba_size & (1 << 4) != 0,
+            protection_type: data.data_protection & 0x7,
+            reservation_capabilities: data.reservation_capabilities,
+            atomic_write_blocks,
+            atomic_boundary,
+            optimal_boundary: (data.optimal_io_boundary != 0)
+                .then_some(data.optimal_io_boundary as u64),
+            optimal_write,
+            read_only: data.attributes & 1 != 0,
+            command_set: 0,
+        };
+
+        // The command set is reported by the descriptor list since NVMe 1.3,
+        // and it is fine for older controllers to not support it.
+        if self.version >= Version::new(1, 3, 0)
+            && let Ok(ids) = self.namespace_identifiers(id)
+        {
+            namespace.command_set = ids.command_set.unwrap_or_default();
+        }
+        Ok(namespace)
+    }
+
+    /// Get the IDs of all namespaces allocated on the NVM subsystem,
+    /// including those not attached to this controller.
+    ///
+    /// Only IDs greater than `base` are returned, at most 1024 of them.
+    pub fn allocated_namespaces(&mut self, base: u32) -> Result<Vec<u32>> {
+        self.namespace_list(NamespaceList::Allocated, base)
+    }
+
+    /// Get a list of namespace IDs greater than `base`.
+    pub(crate) fn namespace_list(&mut self, list: NamespaceList, base: u32) -> Result<Vec<u32>> {
+        self.admin_buffer.zero();
+        let cmd_id = self.next_cmd_id();
         self.exec_admin(Command::identify(
-            self.admin_sq.tail as u16,
+            cmd_id,
             self.admin_buffer.phys_addr,
-            IdentifyType::NamespaceList(base),
+            IdentifyType::NamespaceList(list, base),
         ))?;
 
         let ids = self
@@ -260,59 +867,515 @@ impl<A: Allocator> Device<A> {
             .chunks_exact(4)
             .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
             .filter(|&id| id != 0)
-            .collect::<Vec<u32>>();
-
-        let get_namespace = |&id| {
-            self.exec_admin(Command::identify(
-                self.admin_sq.tail as u16,
-                self.admin_buffer.phys_addr,
-                IdentifyType::Namespace(id),
-            ))?;
-
-            let data = unsafe { &*(self.admin_buffer.addr as *const NamespaceData) };
-            let flba_index = (data.lba_size & 0xF) as usize;
-            let flba_data = (data.lba_format_support[flba_index] >> 16) & 0xFF;
-
-            Ok(Namespace {
-                id,
-                block_size: 1 << flba_data,
-                block_count: data.capacity,
-            })
-        };
+            .collect();
+        Ok(ids)
+    }
+
+    /// Get the unique identifiers of the namespace `ns_id`.
+    pub fn namespace_identifiers(&mut self, ns_id: u32) -> Result<NamespaceIds> {
+        self.admin_buffer.zero();
+        let cmd_id = self.next_cmd_id();
+        self.exec_admin(Command::identify(
+            cmd_id,
+            self.admin_buffer.phys_addr,
+            IdentifyType::NamespaceDescriptors(ns_id),
+        ))?;
+
+        let mut ids = NamespaceIds::default();
+        let mut offset = 0;
+        // Each descriptor is a type, a length, two reserved bytes and the identifier.
+        while let [kind, len, _, _, rest @ ..] = &self.admin_buffer[offset..] {
+            // A zero type or length terminates the list.
+            if *kind == 0 || *len == 0 {
+                break;
+            }
+            let Some(id) = rest.get(..*len as usize) else {
+                break;
+            };
+            match (kind, id.len()) {
+                (1, 8) => ids.eui64 = id.try_into().ok(),
+                (2, 16) => ids.nguid = id.try_into().ok(),
+                (3, 16) => ids.uuid = id.try_into().ok(),
+                (4, 1) => ids.command_set = Some(id[0]),
+                _ => {}
+            }
+            offset += 4 + id.len();
+        }
 
-        ids.iter().map(get_namespace).collect()
+        Ok(ids)
     }
 }
 
-impl<A: Allocator> Device<A> {
+impl<A> Device<A> {
     /// Helper function to read a NVMe register.
-    fn get_reg<T>(&self, reg: Register) -> T {
-        let address = self.address as usize + reg as usize;
-        unsafe { (address as *const T).read_volatile() }
+    pub(crate) fn get_reg<T: RegisterValue>(&self, reg: Register) -> T {
+        T::read(self.mmio.as_ref(), reg as usize)
     }
 
     /// Helper function to write a NVMe register.
-    fn set_reg<T>(&self, reg: Register, value: T) {
-        let address = self.address as usize + reg as usize;
-        unsafe { (address as *mut T).write_volatile(value) }
+    pub(crate) fn set_reg<T: RegisterValue>(&self, reg: Register, value: T) {
+        value.write(self.mmio.as_ref(), reg as usize)
+    }
+
+    /// Get the worst case time for the controller to change its state.
+    ///
+    /// This is the CAP.TO field, which is reported in 500 ms units.
+    /// At least one unit is waited even if the controller reports zero.
+    pub(crate) fn ready_timeout(&self) -> Timeout {
+        Timeout::from_millis(self.capabilities.timeout_ms.max(500) as usize)
+    }
+
+    /// Checks whether weighted round robin arbitration is supported.
+    fn weighted_round_robin(&self) -> bool {
+        self.capabilities.arbitration_mechanisms & 1 != 0
+    }
+}
+
+impl<A> Device<A> {
+    /// Shut down the controller.
+    ///
+    /// A normal shutdown lets the controller flush its volatile write cache
+    /// and save its state, after which the controller is disabled. It should
+    /// be done before the power is removed, e.g. on reboot.
+    ///
+    /// All I/O queue pairs should be deleted beforehand, as no command can be
+    /// processed afterwards. Only `Drop` is expected after a shutdown.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::ControllerTimeout` if the shutdown does not complete
+    /// within the timeout reported by the controller (CAP.TO), and
+    /// `Error::ControllerFatal` if the controller reports a fatal status.
+    pub fn shutdown(&mut self) -> Result<()> {
+        // CC.SHN = 01b: Normal shutdown notification
+        let cc = self.get_reg::<u32>(Register::CC) & !(0x3 << 14);
+        self.set_reg::<u32>(Register::CC, cc | (0b01 << 14));
+
+        // CSTS.SHST = 10b: Shutdown processing complete
+        let mut timeout = self.ready_timeout();
+        while (self.get_reg::<u32>(Register::CSTS) >> 2) & 0x3 != 0b10 {
+            if self.doorbell_helper.is_fatal() {
+                return Err(Error::ControllerFatal);
+            }
+            timeout.spin()?;
+        }
+
+        self.set_reg::<u32>(Register::CC, self.get_reg::<u32>(Register::CC) & !1);
+        Ok(())
+    }
+
+    /// Set whether the controller is shut down when the device is dropped.
+    ///
+    /// This is enabled by default, so that the write cache is not lost.
+    pub fn set_shutdown_on_drop(&mut self, enabled: bool) {
+        self.shutdown_on_drop = enabled;
+    }
+
+    /// Set whether I/O queues may be physically non-contiguous.
+    ///
+    /// If enabled, queues larger than a memory page are described by a PRP
+    /// list translating each page on its own, so their memory only has to be
+    /// contiguous in virtual memory. This allows deep queues on systems with
+    /// fragmented physical memory. It applies to queue pairs created afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::FeatureNotSupported` if the controller requires
+    /// physically contiguous queues (CAP.CQR).
+    pub fn set_noncontiguous_queues(&mut self, enabled: bool) -> Result<()> {
+        if enabled && self.capabilities.contiguous_queues_required {
+            return Err(Error::FeatureNotSupported);
+        }
+        self.noncontiguous_queues = enabled;
+        Ok(())
+    }
+}
+
+impl<A> Drop for Device<A> {
+    fn drop(&mut self) {
+        if self.shutdown_on_drop && self.get_reg::<u32>(Register::CC) & 1 == 1 {
+            let _ = self.shutdown();
+        }
+    }
+}
+
+impl<A: Allocator> Device<A> {
+    /// Reset the NVM subsystem and bring the controller up again.
+    ///
+    /// This recovers a controller which stopped responding without
+    /// a PCIe function level reset. The NVM subsystem reset also resets
+    /// the other controllers of the subsystem, if any.
+    ///
+    /// All I/O queue pairs are lost by the reset and must not be used anymore,
+    /// they have to be created again with `create_io_queue_pair`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::FeatureNotSupported` if the controller does not
+    /// support NVM subsystem resets (CAP.NSSRS).
+    pub fn subsystem_reset(&mut self) -> Result<()> {
+        if !self.capabilities.subsystem_reset {
+            return Err(Error::FeatureNotSupported);
+        }
+        self.set_reg::<u32>(Register::NSSR, NSSR_RESET);
+        self.initialize()
+    }
+}
+
+impl<A: Allocator> Device<A> {
+    /// Reset the controller and bring it up again.
+    ///
+    /// This recovers the controller after a fatal error (see `is_fatal`),
+    /// which is reported as `Error::ControllerFatal` by admin commands.
+    /// The controller is disabled, the admin queues are reprogrammed
+    /// reusing their memory, and the controller is enabled and identified
+    /// again.
+    ///
+    /// All I/O queue pairs are lost by the reset and must not be used anymore,
+    /// they have to be created again with `create_io_queue_pair`.
+    pub fn reset(&mut self) -> Result<()> {
+        self.initialize()
+    }
+
+    /// Bring the controller up and negotiate its settings.
+    ///
+    /// This is done at initialization and again after every reset.
+    pub(crate) fn initialize(&mut self) -> Result<()> {
+        self.enable()?;
+        self.identify_controller()?;
+        self.request_io_queues()?;
+        self.restore_keep_alive();
+        Ok(())
+    }
+
+    /// Reset the controller and bring it up with empty admin queues.
+    ///
+    /// The controller is disabled, the admin queues are reprogrammed
+    /// and then it is enabled again. All I/O queues are lost.
+    pub(crate) fn enable(&mut self) -> Result<()> {
+        self.set_reg::<u32>(Register::CC, self.get_reg::<u32>(Register::CC) & !1);
+        let mut timeout = self.ready_timeout();
+        while self.get_reg::<u32>(Register::CSTS) & 1 == 1 {
+            timeout.spin()?;
+        }
+
+        self.admin_sq.reset();
+        self.admin_cq.reset();
+        self.async_events.reset();
+        self.set_reg::<u64>(Register::ASQ, self.admin_sq.data.phys_addr as u64);
+        self.set_reg::<u64>(Register::ACQ, self.admin_cq.data.phys_addr as u64);
+        let aqa = (ADMIN_QUEUE_SIZE as u32 - 1) << 16 | (ADMIN_QUEUE_SIZE as u32 - 1);
+        self.set_reg::<u32>(Register::AQA, aqa);
+
+        // Use the minimum memory page size, which PRPs are built upon,
+        // and weighted round robin arbitration if it is supported.
+        let mps = self.data.min_pagesize.trailing_zeros() - 12;
+        let ams = (self.weighted_round_robin() as u32) << 11;
+        let cc = self.get_reg::<u32>(Register::CC) & 0xFF00_000F;
+        self.set_reg::<u32>(Register::CC, cc | (4 << 20) | (6 << 16) | ams | (mps << 7));
+
+        self.set_reg::<u32>(Register::CC, self.get_reg::<u32>(Register::CC) | 1);
+        let mut timeout = self.ready_timeout();
+        while self.get_reg::<u32>(Register::CSTS) & 1 == 0 {
+            // A controller failing to start never becomes ready.
+            if self.is_fatal() {
+                return Err(Error::ControllerFatal);
+            }
+            timeout.spin()?;
+        }
+
+        Ok(())
+    }
+
+    /// Identify the controller and fill in the controller data.
+    pub(crate) fn identify_controller(&mut self) -> Result<()> {
+        self.admin_buffer.zero();
+        let cmd_id = self.next_cmd_id();
+        self.exec_admin(Command::identify(
+            cmd_id,
+            self.admin_buffer.phys_addr,
+            IdentifyType::Controller,
+        ))?;
+
+        let extract_string = |start: usize, end: usize| -> String {
+            str::from_utf8(&self.admin_buffer[start..end])
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        };
+
+        self.data.serial_number = extract_string(4, 24);
+        self.data.model_number = extract_string(24, 64);
+        self.data.firmware_revision = extract_string(64, 72);
+
+        let extract_u32_number = |start: usize, end: usize| -> u32 {
+            let bytes = &self.admin_buffer[start..end];
+            u32::from_le_bytes(bytes.try_into().unwrap())
+        };
+
+        let hmpre = extract_u32_number(272, 276);
+        let hmmin = extract_u32_number(276, 280);
+        self.data.hmb_size = if hmpre != 0 { hmmin * 4096 } else { 0 };
+
+        let extract_u16_number = |start: usize| -> u16 {
+            let bytes = &self.admin_buffer[start..start + 2];
+            u16::from_le_bytes(bytes.try_into().unwrap())
+        };
+
+        self.data.vendor_id = extract_u16_number(0);
+        self.data.subsystem_vendor_id = extract_u16_number(2);
+        self.data.ieee_oui = self.admin_buffer[73..76].try_into().unwrap();
+        self.data.controller_id = extract_u16_number(78);
+        self.data.number_of_namespaces = extract_u32_number(516, 520);
+        self.data.optional_nvm_commands = extract_u16_number(520);
+        self.data.fused_operations = extract_u16_number(522);
+
+        self.data.atomic_write_unit_normal = extract_u16_number(526) as u32 + 1;
+        self.data.atomic_write_unit_power_fail = extract_u16_number(528) as u32 + 1;
+
+        // SGLS: 01 is supported, 10 is supported with dword alignment.
+        let sgls = extract_u32_number(536, 540);
+        self.data.sgl_supported = sgls & 0x3 != 0;
+        self.data.sgl_dword_aligned = sgls & 0x3 == 0x2;
+
+        self.data.optional_admin_commands = extract_u16_number(256);
+        self.data.sanitize_capabilities = extract_u32_number(328, 332);
+        // KAS is in 100 milliseconds units.
+        self.data.keep_alive_granularity_ms = extract_u16_number(320) as u32 * 100;
+
+        // FWUG is in 4 KiB units, 0 gives no information and 0xFF no restriction.
+        self.data.firmware_update_granularity = match self.admin_buffer[319] {
+            0 => 4096,
+            0xFF => 4,
+            units => units as usize * 4096,
+        };
+
+        // NPSS is zero-based, the descriptors start at byte 2048.
+        let power_states = self.admin_buffer[263] as usize + 1;
+        self.data.power_states = self.admin_buffer[2048..2048 + power_states * 32]
+            .chunks_exact(32)
+            .map(PowerStateDescriptor::from_raw)
+            .collect();
+
+        self.data.log_page_attributes = self.admin_buffer[261];
+        self.data.abort_command_limit = self.admin_buffer[258] as u16 + 1;
+        self.data.max_async_events = self.admin_buffer[259] as u16 + 1;
+
+        let max_pages = 1 << self.admin_buffer[77];
+        self.data.max_transfer_size = max_pages as usize * self.data.min_pagesize;
+
+        Ok(())
+    }
+
+    /// Request the I/O queues from the controller.
+    ///
+    /// This must be done before any I/O queue is created, and again after
+    /// each reset, which also discards all existing I/O queues.
+    pub(crate) fn request_io_queues(&mut self) -> Result<()> {
+        // Both counts are zero-based, submission queues in the low word.
+        let count = (IO_QUEUE_COUNT - 1) as u32;
+        let granted =
+            self.set_features(FEATURE_NUMBER_OF_QUEUES, count << 16 | count, false, None)?;
+        let granted = (granted as u16)
+            .min((granted >> 16) as u16)
+            .saturating_add(1);
+
+        self.data.max_io_queues = granted;
+        self.io_queue_ids.clear();
+        self.doorbell_helper =
+            DoorbellHelper::new(self.mmio.clone(), self.doorbell_helper.stride, granted);
+        Ok(())
+    }
+
+    /// Get the command ID for the next admin command.
+    ///
+    /// The IDs increase with every command, so that no two commands in
+    /// flight share an ID, and never collide with the IDs of Asynchronous
+    /// Event Requests.
+    pub(crate) fn next_cmd_id(&mut self) -> u16 {
+        let cmd_id = self.next_cmd_id;
+        self.next_cmd_id = (cmd_id + 1) % ASYNC_EVENT_CMD_ID;
+        cmd_id
     }
 
     /// Execute an admin command.
-    fn exec_admin(&mut self, cmd: Command) -> Result<Completion> {
-        let tail = self.admin_sq.push(cmd);
+    ///
+    /// This is the blocking variant used by all admin operations of `Device`:
+    /// it waits for a free slot in the admin submission queue and then for
+    /// the completion, but gives up with `Error::ControllerTimeout` once the
+    /// admin timeout expires instead of hanging forever.
+    pub(crate) fn exec_admin(&mut self, cmd: Command) -> Result<Completion> {
+        self.exec_admin_with_timeout(cmd, Timeout::from_millis(ADMIN_TIMEOUT_MS))
+    }
+
+    /// Execute an admin command which may take longer than the admin timeout.
+    pub(crate) fn exec_admin_with_timeout(
+        &mut self,
+        cmd: Command,
+        mut timeout: Timeout,
+    ) -> Result<Completion> {
+        let tail = self.push_admin(cmd, &mut timeout)?;
+        self.complete_admin(tail, cmd.cmd_id(), &mut timeout)
+    }
+
+    /// Push an admin command, waiting for a free slot if the queue is full.
+    ///
+    /// The head of the submission queue only advances as completions are
+    /// reaped, so while waiting, the completions of commands which have been
+    /// given up on after a timeout are acknowledged and dropped.
+    fn push_admin(&mut self, cmd: Command, timeout: &mut Timeout) -> Result<usize> {
+        loop {
+            if let Ok(tail) = self.admin_sq.try_push(cmd) {
+                return Ok(tail);
+            }
+            match self.admin_cq.try_pop() {
+                Some((head, entry)) => {
+                    self.reap_admin(head, entry)?;
+                }
+                None => timeout.spin()?,
+            }
+        }
+    }
+
+    /// Execute an admin command without blocking on a full queue.
+    ///
+    /// Unlike `exec_admin`, it returns `Error::AdminQueueFull` immediately
+    /// if there is no free slot in the admin submission queue. Waiting for
+    /// the completion is still bounded by the admin timeout.
+    fn try_exec_admin(&mut self, cmd: Command) -> Result<Completion> {
+        let mut timeout = Timeout::from_millis(ADMIN_TIMEOUT_MS);
+        let tail = self
+            .admin_sq
+            .try_push(cmd)
+            .map_err(|_| Error::AdminQueueFull)?;
+        self.complete_admin(tail, cmd.cmd_id(), &mut timeout)
+    }
+
+    /// Ring the admin doorbell and wait for the completion of the command `cmd_id`.
+    ///
+    /// Completions of other commands, which have been given up on after
+    /// a timeout, are acknowledged and dropped.
+    fn complete_admin(
+        &mut self,
+        tail: usize,
+        cmd_id: u16,
+        timeout: &mut Timeout,
+    ) -> Result<Completion> {
         self.doorbell_helper
             .write(Doorbell::SubTail(0), tail as u32);
 
-        let (head, entry) = self.admin_cq.pop();
+        loop {
+            let doorbell_helper = &self.doorbell_helper;
+            let (head, entry) = self.admin_cq.pop(timeout, &|| doorbell_helper.is_fatal())?;
+            if let Some(entry) = self.reap_admin(head, entry)?
+                && entry.cmd_id() == cmd_id
+            {
+                entry.check()?;
+                return Ok(entry);
+            }
+        }
+    }
+
+    /// Acknowledge an admin completion popped at `head`.
+    ///
+    /// Returns `None` if it completes an Asynchronous Event Request,
+    /// whose event is kept for `poll_async_event` instead.
+    pub(crate) fn reap_admin(
+        &mut self,
+        head: usize,
+        entry: Completion,
+    ) -> Result<Option<Completion>> {
         self.doorbell_helper
             .write(Doorbell::CompHead(0), head as u32);
 
-        let status = (entry.status >> 1) & 0xff;
-        if status != 0 {
-            return Err(Error::CommandFailed(status));
+        // The controller reports how far it has consumed the submission queue,
+        // without this the queue would look full after a few commands.
+        if !self.admin_sq.is_valid_head(entry.sq_head as usize) {
+            return Err(Error::CompletionQueueOverrun);
+        }
+        self.admin_sq.head = entry.sq_head as usize;
+
+        match self.queue_async_event(&entry) {
+            true => Ok(None),
+            false => Ok(Some(entry)),
+        }
+    }
+}
+
+impl<A: Allocator> Device<A> {
+    /// Send a raw admin command to the controller.
+    ///
+    /// This is an escape hatch for commands that are not modeled by the driver,
+    /// e.g. vendor specific commands (opcodes C0h to FFh) or standard commands
+    /// added by later revisions of the specification. If `data` is given, it
+    /// is copied into a DMA buffer which the command points to, and copied
+    /// back once the command completes, so it can be used for transfers in
+    /// both directions.
+    ///
+    /// Returns the completion entry so that its result can be inspected,
+    /// e.g. dword 0 of the completion with `Completion::result`.
+    ///
+    /// Like all admin operations except `abort` and `exec_admin_try`,
+    /// it waits for a free slot in the admin submission queue if it is
+    /// full, bounded by the admin timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `data` exceeds the maximum transfer size
+    /// or the command fails.
+    pub fn admin_passthru(
+        &mut self,
+        cmd: RawCommand,
+        data: Option<&mut [u8]>,
+    ) -> Result<Completion> {
+        let Some(data) = data.filter(|data| !data.is_empty()) else {
+            let cmd_id = self.next_cmd_id();
+            return self.exec_admin(Command::raw(cmd_id, &cmd, [0, 0]));
+        };
+        if data.len() > self.data.max_transfer_size {
+            return Err(Error::IoSizeExceedsMdts);
         }
 
-        Ok(entry)
+        let page_size = self.prp_manager.page_size();
+        let mut buffer =
+            Dma::<u8>::allocate_aligned(data.len(), page_size, self.allocator.as_ref());
+        buffer.copy_from_slice(data);
+
+        let result = self
+            .prp_manager
+            .create(self.allocator.as_ref(), buffer.addr as usize, data.len())
+            .and_then(|prp_result| {
+                let prp = prp_result.get_prp();
+                let command = Command::raw(self.next_cmd_id(), &cmd, [prp.0 as u64, prp.1 as u64]);
+                let result = self.exec_admin(command);
+                self.prp_manager
+                    .release(prp_result, self.allocator.as_ref());
+                result
+            });
+
+        data.copy_from_slice(&buffer);
+        buffer.deallocate(self.allocator.as_ref());
+        result
+    }
+
+    /// Send an admin command without data, unless the admin queue is full.
+    ///
+    /// The admin operations of `Device`, including `admin_passthru`, block
+    /// until there is a free slot in the admin submission queue and then
+    /// until the command completes, both bounded by the admin timeout after
+    /// which `Error::ControllerTimeout` is returned. This variant, which
+    /// `abort` uses as well, does not wait for a free slot but returns
+    /// `Error::AdminQueueFull` right away, e.g. for callers that must not
+    /// stall behind a controller which stopped consuming admin commands.
+    /// Waiting for the completion is still bounded by the admin timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::AdminQueueFull` if the admin submission queue is full,
+    /// or an error if the command fails.
+    pub fn exec_admin_try(&mut self, cmd: RawCommand) -> Result<Completion> {
+        let cmd_id = self.next_cmd_id();
+        self.try_exec_admin(Command::raw(cmd_id, &cmd, [0, 0]))
     }
 }
 
@@ -328,14 +1391,112 @@ impl<A: Allocator> Device<A> {
     /// All your I/O operations should be done through this queue pair, and
     /// you can create multiple queue pairs if needed (e.g. per thread).
     ///
+    /// The completion queue has the same depth as the submission queue and at
+    /// most `len - 1` commands can be in flight, so the completion queue cannot
+    /// be overrun by this driver. Choose `len` for the deepest batch you intend
+    /// to submit before calling `flush`, otherwise submission fails with
+    /// `Error::SubQueueFull` rather than losing completions.
+    ///
+    /// Completions of the queue pair raise the MSI-X (or MSI) interrupt
+    /// `vector`, so that the interrupts of each queue pair can be routed
+    /// to a different core. The vector must be below the number of vectors
+    /// enabled on the PCI function, and 0 is shared with the admin queue.
+    ///
+    /// The `priority` of the submission queue is used if the controller
+    /// supports weighted round robin arbitration, and `None` is the medium
+    /// priority. Otherwise all queues are served in turn.
+    ///
     /// # Errors
     ///
     /// Returns an error if the queue size is less than 2 or exceeds the
-    /// maximum number of queue entries.
+    /// maximum number of queue entries, `Error::TooManyQueues` if all
+    /// I/O queues granted by the controller (`max_io_queues`) are in use,
+    /// and `Error::FeatureNotSupported` if a priority is given but
+    /// weighted round robin arbitration is not supported.
     pub fn create_io_queue_pair(
         &mut self,
         namespace: Namespace,
         len: usize,
+        vector: u16,
+        priority: Option<QueuePriority>,
+    ) -> Result<IoQueuePair<A>> {
+        if priority.is_some() && !self.weighted_round_robin() {
+            return Err(Error::FeatureNotSupported);
+        }
+        self.create_queue_pair(namespace, len, Some(vector), priority, false)
+    }
+
+    /// Create an I/O queue pair whose submission queue is in the CMB.
+    ///
+    /// The CMB must be mapped by `map_cmb` beforehand. The submission queue
+    /// is placed in host memory instead if there is no CMB or it is full.
+    /// See `create_io_queue_pair` for more details.
+    pub fn create_io_queue_pair_in_cmb(
+        &mut self,
+        namespace: Namespace,
+        len: usize,
+        vector: u16,
+        priority: Option<QueuePriority>,
+    ) -> Result<IoQueuePair<A>> {
+        if priority.is_some() && !self.weighted_round_robin() {
+            return Err(Error::FeatureNotSupported);
+        }
+        self.create_queue_pair(namespace, len, Some(vector), priority, true)
+    }
+
+    /// Create an I/O queue pair whose completions raise no interrupt.
+    ///
+    /// This is meant for dedicated pollers, which avoid the interrupt
+    /// overhead entirely. The queue pair must keep the default
+    /// `PollingWaiter`, as a `CompletionWaiter` waiting for an interrupt
+    /// would never be woken up. See `create_io_queue_pair` for more details.
+    pub fn create_polling_queue_pair(
+        &mut self,
+        namespace: Namespace,
+        len: usize,
+    ) -> Result<IoQueuePair<A>> {
+        self.create_queue_pair(namespace, len, None, None, false)
+    }
+
+    /// Build a PRP list for `bytes` of queue slots at `addr`.
+    ///
+    /// Returns `None` if the queue has to be physically contiguous,
+    /// which is the case unless enabled by `set_noncontiguous_queues`,
+    /// or if it fits into a single memory page anyway.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::NotAlignedToPage` if a PRP list is needed but the queue
+    /// does not start at a memory page boundary, as it cannot be described
+    /// by one then.
+    fn queue_prp_list(&self, addr: usize, bytes: usize) -> Result<Option<Dma<u64>>> {
+        let page_size = self.data.min_pagesize;
+        if !self.noncontiguous_queues || bytes <= page_size {
+            return Ok(None);
+        }
+        if !addr.is_multiple_of(page_size) {
+            return Err(Error::NotAlignedToPage);
+        }
+
+        let pages = bytes.div_ceil(page_size);
+        let mut list = Dma::<u64>::allocate_aligned(pages, page_size, self.allocator.as_ref());
+        for (index, entry) in list.iter_mut().enumerate() {
+            *entry = self.allocator.translate(addr + index * page_size) as u64;
+        }
+        Ok(Some(list))
+    }
+
+    /// Create an I/O queue pair raising the interrupt `vector` if given.
+    ///
+    /// The submission queue is placed in the CMB if `in_cmb` is set
+    /// and there is room for it.
+    fn create_queue_pair(
+        &mut self,
+        namespace: Namespace,
+        len: usize,
+        vector: Option<u16>,
+        priority: Option<QueuePriority>,
+        in_cmb: bool,
     ) -> Result<IoQueuePair<A>> {
         if len < 2 {
             return Err(Error::QueueSizeTooSmall);
@@ -344,24 +1505,77 @@ impl<A: Allocator> Device<A> {
             return Err(Error::QueueSizeExceedsMqes);
         }
 
-        let queue_id = IoQueueId::new();
+        let queue_id = (1..=self.data.max_io_queues)
+            .find(|id| !self.io_queue_ids.contains(id))
+            .map(IoQueueId::new)
+            .ok_or(Error::TooManyQueues)?;
 
-        let comp_queue = CompQueue::new(len, self.allocator.as_ref());
-        self.exec_admin(Command::create_completion_queue(
-            self.admin_sq.tail as u16,
+        self.doorbell_helper.clear_shadow(*queue_id);
+        // Queues are page aligned, so that they can be described by a PRP list.
+        let page_size = self.data.min_pagesize;
+        let mut comp_queue = CompQueue::new_aligned(len, page_size, self.allocator.as_ref());
+        match self.queue_prp_list(comp_queue.data.addr as usize, len * 16) {
+            Ok(prp_list) => comp_queue.prp_list = prp_list,
+            Err(err) => {
+                comp_queue.free(self.allocator.as_ref());
+                return Err(err);
+            }
+        }
+        let command = Command::create_completion_queue(
+            self.next_cmd_id(),
             *queue_id,
-            comp_queue.data.phys_addr,
+            comp_queue.address(),
             (len - 1) as u16,
-        ))?;
+            vector.unwrap_or(0),
+            vector.is_some(),
+            comp_queue.prp_list.is_none(),
+        );
+        if let Err(err) = self.exec_admin(command) {
+            comp_queue.free(self.allocator.as_ref());
+            return Err(err);
+        }
 
-        let sub_queue = SubQueue::new(len, self.allocator.as_ref());
-        self.exec_admin(Command::create_submission_queue(
-            self.admin_sq.tail as u16,
+        // Queues in the CMB are always contiguous.
+        let cmb_queue = self
+            .cmb
+            .as_mut()
+            .filter(|_| in_cmb)
+            .and_then(|cmb| cmb.allocate_sub_queue(len));
+        let sub_queue = match cmb_queue {
+            Some(sub_queue) => sub_queue,
+            None => {
+                let mut sub_queue = SubQueue::new_aligned(len, page_size, self.allocator.as_ref());
+                match self.queue_prp_list(sub_queue.data.addr as usize, len * 64) {
+                    Ok(prp_list) => sub_queue.prp_list = prp_list,
+                    Err(err) => {
+                        let command =
+                            Command::delete_completion_queue(self.next_cmd_id(), *queue_id);
+                        let _ = self.exec_admin(command);
+                        sub_queue.free(None, self.allocator.as_ref());
+                        comp_queue.free(self.allocator.as_ref());
+                        return Err(err);
+                    }
+                }
+                sub_queue
+            }
+        };
+        let command = Command::create_submission_queue(
+            self.next_cmd_id(),
             *queue_id,
-            sub_queue.data.phys_addr,
+            sub_queue.address(),
             (len - 1) as u16,
             *queue_id,
-        ))?;
+            priority.unwrap_or(QueuePriority::Medium) as u8,
+            sub_queue.prp_list.is_none(),
+        );
+        if let Err(err) = self.exec_admin(command) {
+            let command = Command::delete_completion_queue(self.next_cmd_id(), *queue_id);
+            let _ = self.exec_admin(command);
+            sub_queue.free(self.cmb.as_mut(), self.allocator.as_ref());
+            comp_queue.free(self.allocator.as_ref());
+            return Err(err);
+        }
+        self.io_queue_ids.insert(*queue_id);
 
         Ok(IoQueuePair::new(
             queue_id,
@@ -370,7 +1584,7 @@ impl<A: Allocator> Device<A> {
             sub_queue,
             comp_queue,
             self.allocator.clone(),
-            self.data.max_transfer_size,
+            &self.data,
         ))
     }
 
@@ -379,12 +1593,307 @@ impl<A: Allocator> Device<A> {
     /// This function will delete the submission queue and completion queue
     /// associated with the given `IoQueuePair`. It will also free the resources
     /// allocated for the queues.
-    pub fn delete_io_queue_pair(&mut self, qpair: IoQueuePair<A>) -> Result<()> {
-        let cmd_id = self.admin_sq.tail as u16;
-        let command = Command::delete_submission_queue(cmd_id, *qpair.id());
-        self.exec_admin(command)?;
-        let command = Command::delete_completion_queue(cmd_id, *qpair.id());
-        self.exec_admin(command)?;
+    ///
+    /// The queue pair is quiesced first so that no command is still in flight
+    /// when the queues are deleted.
+    ///
+    /// # Errors
+    ///
+    /// If the queue pair is not deleted, it is handed back with the error
+    /// instead of being freed, so that deleting it can be tried again.
+    /// The error is `Error::QueueNotEmpty` if the in-flight commands could
+    /// not be drained or one of them failed, in which case the queues are
+    /// left untouched, otherwise the error of the failed delete command.
+    pub fn delete_io_queue_pair(
+        &mut self,
+        mut qpair: IoQueuePair<A>,
+    ) -> core::result::Result<(), DeleteQueuePairError<A>> {
+        if qpair.quiesce().is_err() || !qpair.is_idle() {
+            return Err(DeleteQueuePairError::new(Error::QueueNotEmpty, qpair));
+        }
+
+        // The submission queue has to be deleted before its completion queue.
+        if !qpair.sub_queue_deleted {
+            let command = Command::delete_submission_queue(self.next_cmd_id(), *qpair.id());
+            if let Err(error) = self.exec_admin(command) {
+                return Err(DeleteQueuePairError::new(error, qpair));
+            }
+            qpair.sub_queue_deleted = true;
+        }
+        let command = Command::delete_completion_queue(self.next_cmd_id(), *qpair.id());
+        if let Err(error) = self.exec_admin(command) {
+            return Err(DeleteQueuePairError::new(error, qpair));
+        }
+
+        self.io_queue_ids.remove(&*qpair.id());
+        qpair.free(self.cmb.as_mut());
         Ok(())
     }
 }
+
+impl<A: Allocator> Device<A> {
+    /// Abort the command `cid` submitted to the submission queue `sqid`.
+    ///
+    /// Returns whether the controller aborted the command. Aborting is best
+    /// effort: the command may complete anyway, and an aborted command still
+    /// posts a completion (with status Command Abort Requested), which must
+    /// be reaped by flushing the queue pair as usual.
+    ///
+    /// # Errors
+    ///
+    /// Unlike other admin operations it does not wait for a free slot,
+    /// but returns `Error::AdminQueueFull` if the admin queue is full.
+    pub fn abort(&mut self, sqid: u16, cid: u16) -> Result<bool> {
+        let command = Command::abort(self.next_cmd_id(), sqid, cid);
+        let completion = self.try_exec_admin(command)?;
+        // Bit 0 is cleared if the command was aborted.
+        Ok(completion.result() & 1 == 0)
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use std::sync::Mutex;
+    use std::vec;
+
+    use super::*;
+    use crate::mock::MockController;
+
+    /// Records the offsets of register writes, reads return 0.
+    #[derive(Default)]
+    struct RecordingMmio {
+        offsets: Mutex<Vec<usize>>,
+    }
+
+    impl Mmio for RecordingMmio {
+        fn read32(&self, _offset: usize) -> u32 {
+            0
+        }
+
+        fn write32(&self, offset: usize, _value: u32) {
+            self.offsets.lock().unwrap().push(offset);
+        }
+    }
+
+    impl RecordingMmio {
+        fn offsets(&self) -> Vec<usize> {
+            self.offsets.lock().unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn admin_queue_wraps_around() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+
+        // Several times the admin queue size, so that the head has to advance.
+        let commands = mock.command_count();
+        for _ in 0..8 * ADMIN_QUEUE_SIZE {
+            device.identify_controller().unwrap();
+        }
+        assert_eq!(mock.command_count() - commands, 8 * ADMIN_QUEUE_SIZE);
+    }
+
+    #[test]
+    fn admin_queue_full_of_abandoned_commands() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+
+        // Commands which have been given up on, e.g. after a timeout:
+        // they complete, but their completions are never reaped.
+        while device.admin_sq.available() > 0 {
+            let cmd_id = device.next_cmd_id();
+            let address = device.admin_buffer.phys_addr;
+            let cmd = Command::identify(cmd_id, address, IdentifyType::Controller);
+            let tail = device.admin_sq.try_push(cmd).unwrap();
+            device
+                .doorbell_helper
+                .write(Doorbell::SubTail(0), tail as u32);
+        }
+
+        let commands = mock.command_count();
+        device.identify_controller().unwrap();
+        assert_eq!(mock.command_count() - commands, 1);
+        assert_eq!(device.admin_sq.available(), ADMIN_QUEUE_SIZE - 1);
+    }
+
+    #[test]
+    fn doorbell_offsets() {
+        // DSTRD and the doorbells of submission queues 0 and 3
+        // and completion queues 0 and 4.
+        let cases = [
+            (0, [0x1000, 0x1004, 0x1018, 0x1024]),
+            (1, [0x1000, 0x1008, 0x1030, 0x1048]),
+            (2, [0x1000, 0x1010, 0x1060, 0x1090]),
+            (4, [0x1000, 0x1040, 0x1180, 0x1240]),
+        ];
+        for (dstrd, offsets) in cases {
+            let mmio = Arc::new(RecordingMmio::default());
+            let doorbell_helper = DoorbellHelper::new(mmio.clone(), dstrd, 4);
+            assert_eq!(doorbell_helper.stride(), 4 << dstrd);
+            doorbell_helper.write(Doorbell::SubTail(0), 1);
+            doorbell_helper.write(Doorbell::CompHead(0), 1);
+            doorbell_helper.write(Doorbell::SubTail(3), 1);
+            doorbell_helper.write(Doorbell::CompHead(4), 1);
+            assert_eq!(mmio.offsets(), offsets, "DSTRD {dstrd}");
+
+            // The mock panics on doorbell writes which are not aligned to the stride.
+            let mock = MockController::new(1024, 512).with_doorbell_stride(dstrd);
+            let mut device = mock.device();
+            assert_eq!(device.doorbell_stride(), 4 << dstrd);
+            let namespace = device.identify_namespaces(0).unwrap().remove(0);
+            let mut qpair = device.create_io_queue_pair(namespace, 64, 0, None).unwrap();
+            qpair.write_at(0, b"stride").unwrap();
+            let mut buf = [0u8; 6];
+            qpair.read_at(0, &mut buf).unwrap();
+            assert_eq!(&buf, b"stride");
+            device.delete_io_queue_pair(qpair).unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "out of range")]
+    fn doorbell_out_of_range() {
+        let doorbell_helper = DoorbellHelper::new(Arc::new(RecordingMmio::default()), 2, 4);
+        doorbell_helper.write(Doorbell::SubTail(5), 1);
+    }
+
+    #[test]
+    fn queue_size_limited_by_mqes() {
+        let mock = MockController::new(1024, 512).with_max_queue_entries(32);
+        let mut device = mock.device();
+        assert_eq!(device.controller_data().max_queue_entries, 32);
+
+        let namespace = device.identify_namespaces(0).unwrap().remove(0);
+        let mut create = |len| device.create_io_queue_pair(namespace.clone(), len, 0, None);
+        assert!(matches!(create(1), Err(Error::QueueSizeTooSmall)));
+        assert!(matches!(create(33), Err(Error::QueueSizeExceedsMqes)));
+        let qpairs = [create(2).unwrap(), create(32).unwrap()];
+        assert_eq!(mock.io_queue_count(), 2);
+        for qpair in qpairs {
+            device.delete_io_queue_pair(qpair).unwrap();
+        }
+    }
+
+    #[test]
+    fn capabilities_of_emulated_controller() {
+        // MQES = 2047, CQR, TO = 7.5 s, NVM and I/O command sets, MPSMAX = 64K
+        let capabilities = ControllerCapabilities::from_raw(0x0040_0820_0F01_07FF);
+        assert_eq!(
+            capabilities,
+            ControllerCapabilities {
+                max_queue_entries: 2048,
+                contiguous_queues_required: true,
+                arbitration_mechanisms: 0,
+                timeout_ms: 7500,
+                doorbell_stride: 4,
+                subsystem_reset: false,
+                nvm_command_set: true,
+                boot_partitions: false,
+                cmb_supported: false,
+                min_page_size: 4096,
+                max_page_size: 65536,
+            }
+        );
+    }
+
+    #[test]
+    fn capabilities_of_weighted_round_robin_controller() {
+        // MQES = 16383, CQR, AMS = WRR, TO = 30 s, NSSRS, NVM command set
+        let capabilities = ControllerCapabilities::from_raw(0x0000_0030_3C03_3FFF);
+        assert_eq!(
+            capabilities,
+            ControllerCapabilities {
+                max_queue_entries: 16384,
+                contiguous_queues_required: true,
+                arbitration_mechanisms: 0b01,
+                timeout_ms: 30000,
+                doorbell_stride: 4,
+                subsystem_reset: true,
+                nvm_command_set: true,
+                boot_partitions: false,
+                cmb_supported: false,
+                min_page_size: 4096,
+                max_page_size: 4096,
+            }
+        );
+    }
+
+    #[test]
+    fn capabilities_with_every_field() {
+        // MQES = 255, AMS = WRR and vendor specific, TO = 127.5 s,
+        // DSTRD = 3, NSSRS, NVM command set, BPS, MPSMIN = 8K,
+        // MPSMAX = 2M, CMBS
+        let capabilities = ControllerCapabilities::from_raw(0x0291_2033_FF06_00FF);
+        assert_eq!(
+            capabilities,
+            ControllerCapabilities {
+                max_queue_entries: 256,
+                contiguous_queues_required: false,
+                arbitration_mechanisms: 0b11,
+                timeout_ms: 127500,
+                doorbell_stride: 32,
+                subsystem_reset: true,
+                nvm_command_set: true,
+                boot_partitions: true,
+                cmb_supported: true,
+                min_page_size: 8192,
+                max_page_size: 2 << 20,
+            }
+        );
+    }
+
+    /// An Identify Controller page laid out like the one of a consumer drive.
+    fn identify_controller_fixture() -> Vec<u8> {
+        let mut page = vec![0u8; 4096];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            page[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(0, &0x144Du16.to_le_bytes());
+        put(2, &0x144Du16.to_le_bytes());
+        put(4, b"S4EWNX0R123456      ");
+        put(24, b"Samsung SSD 970 EVO Plus 1TB            ");
+        put(64, b"2B2QEXM7");
+        // IEEE OUI, MDTS = 512 pages, CNTLID and VER = 1.3
+        put(73, &[0x38, 0x25, 0x00]);
+        put(77, &[9]);
+        put(78, &4u16.to_le_bytes());
+        put(80, &0x0001_0300u32.to_le_bytes());
+        // OACS, ACL = 8, AERL = 4, FRMW, LPA and NPSS = 5 power states
+        put(256, &0x0017u16.to_le_bytes());
+        put(258, &[7, 3, 0x16, 0x03, 0, 4]);
+        // SQES, CQES, NN, ONCS and FUSES
+        put(512, &[0x66, 0x44]);
+        put(516, &1u32.to_le_bytes());
+        put(520, &0x005Fu16.to_le_bytes());
+        put(522, &0u16.to_le_bytes());
+        page
+    }
+
+    #[test]
+    fn controller_data_from_fixture() {
+        let mock =
+            MockController::new(1024, 512).with_identify_controller(&identify_controller_fixture());
+        let device = mock.device();
+        let data = device.controller_data();
+
+        assert_eq!(data.vendor_id, 0x144D);
+        assert_eq!(data.subsystem_vendor_id, 0x144D);
+        assert_eq!(data.serial_number, "S4EWNX0R123456");
+        assert_eq!(data.model_number, "Samsung SSD 970 EVO Plus 1TB");
+        assert_eq!(data.firmware_revision, "2B2QEXM7");
+        assert_eq!(data.ieee_oui, [0x38, 0x25, 0x00]);
+        assert_eq!(data.max_transfer_size, 512 * 4096);
+        assert_eq!(data.controller_id, 4);
+        assert_eq!(data.optional_admin_commands, 0x0017);
+        assert_eq!(data.abort_command_limit, 8);
+        assert_eq!(data.max_async_events, 4);
+        assert_eq!(data.log_page_attributes, 0x03);
+        assert_eq!(data.power_states.len(), 5);
+        assert_eq!(data.number_of_namespaces, 1);
+        assert_eq!(data.optional_nvm_commands, 0x005F);
+        assert!(!data.supports_compare_and_write());
+        assert_eq!(data.keep_alive_granularity_ms, 0);
+    }
+}
@@ -1,5 +1,10 @@
+use alloc::boxed::Box;
 use core::fmt::{self, Display};
 
+use crate::io::IoQueuePair;
+use crate::memory::Allocator;
+use crate::status::Status;
+
 /// Contains all possible errors that can occur in the NVMe driver.
 #[derive(Debug)]
 pub enum Error {
@@ -17,12 +22,87 @@ pub enum Error {
     QueueSizeTooSmall,
     /// The queue size exceeds the maximum queue entry size (MQES).
     QueueSizeExceedsMqes,
-    /// Command failed with a specific status code.
-    CommandFailed(u16),
+    /// The buffer address or length is zero.
+    InvalidDmaRegion,
+    /// The allocator could not translate the buffer address.
+    UnmappedAddress,
+    /// Command failed with a specific status, retrying it may succeed.
+    CommandFailedRetryable(Status),
+    /// Command failed with a specific status, retrying it is expected to fail again.
+    CommandFailedFatal(Status),
+    /// The admin submission queue is full.
+    AdminQueueFull,
+    /// The controller did not respond before the timeout expired.
+    ControllerTimeout,
+    /// The controller reported a fatal status (CSTS.CFS) and must be reset.
+    ControllerFatal,
+    /// The controller does not support the requested feature.
+    FeatureNotSupported,
+    /// The write exceeds the atomic write unit or crosses an atomic boundary.
+    NotAtomic,
+    /// The namespace is not ready yet, the command may be retried later.
+    NamespaceNotReady,
+    /// The completion queue was overrun and is out of sync.
+    CompletionQueueOverrun,
+    /// The queue still has commands in flight.
+    QueueNotEmpty,
+    /// The LBA range exceeds the capacity of the namespace.
+    LbaOutOfRange {
+        /// The first block of the range
+        lba: u64,
+        /// The number of blocks in the range
+        blocks: u64,
+        /// The number of blocks of the namespace
+        capacity: u64,
+    },
+    /// The compared data does not match the data on the media.
+    CompareMismatch,
+    /// All I/O queues granted by the controller are in use.
+    TooManyQueues,
+    /// I/O queue pairs exist, which must be deleted first.
+    IoQueuesExist,
+    /// The LBA format is not supported by the namespace.
+    InvalidLbaFormat,
+    /// The controller does not support SGLs.
+    SglNotSupported,
+    /// The namespace has no separate metadata.
+    MetadataNotSupported,
+    /// The namespace is not formatted with protection information.
+    ProtectionNotSupported,
+    /// The firmware image part is not aligned to the update granularity.
+    FirmwareNotAligned,
+    /// The namespace is write protected.
+    NamespaceReadOnly,
+    /// The LBA is not the first block of a zone.
+    InvalidZone,
+    /// The write exceeds the remaining capacity of the zone.
+    ZoneCapacityExceeded,
+    /// The zone is full and cannot be written.
+    ZoneFull,
+    /// The zone is read only and cannot be written.
+    ZoneReadOnly,
+    /// The zone is offline and cannot be accessed.
+    ZoneOffline,
+    /// The zone cannot be activated because too many zones are active.
+    TooManyActiveZones,
+    /// The zone cannot be opened because too many zones are open.
+    TooManyOpenZones,
+    /// The zone cannot change from its current state to the requested state.
+    InvalidZoneStateTransition,
 }
 
 impl core::error::Error for Error {}
 
+impl From<Status> for Error {
+    /// Choose between the retryable and the fatal error by the Do Not Retry bit.
+    fn from(status: Status) -> Self {
+        match status.do_not_retry {
+            true => Error::CommandFailedFatal(status),
+            false => Error::CommandFailedRetryable(status),
+        }
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -47,12 +127,157 @@ impl Display for Error {
             Error::QueueSizeExceedsMqes => {
                 write!(f, "The queue size exceeds the maximum queue entry size")
             }
-            Error::CommandFailed(code) => {
-                write!(f, "Command failed with status code: {code:x}")
+            Error::InvalidDmaRegion => {
+                write!(f, "The buffer address or length is zero")
+            }
+            Error::UnmappedAddress => {
+                write!(f, "The buffer address is not mapped")
+            }
+            Error::CommandFailedRetryable(status) => {
+                write!(f, "Command failed: {status}")
+            }
+            Error::CommandFailedFatal(status) => {
+                write!(f, "Command failed permanently: {status}")
+            }
+            Error::AdminQueueFull => {
+                write!(f, "The admin submission queue is full")
+            }
+            Error::ControllerTimeout => {
+                write!(f, "The controller did not respond in time")
+            }
+            Error::ControllerFatal => {
+                write!(f, "The controller reported a fatal status")
+            }
+            Error::FeatureNotSupported => {
+                write!(f, "The controller does not support the requested feature")
+            }
+            Error::NamespaceNotReady => {
+                write!(f, "The namespace is not ready")
+            }
+            Error::CompletionQueueOverrun => {
+                write!(f, "The completion queue was overrun")
+            }
+            Error::QueueNotEmpty => {
+                write!(f, "The queue still has commands in flight")
+            }
+            Error::LbaOutOfRange {
+                lba,
+                blocks,
+                capacity,
+            } => {
+                write!(
+                    f,
+                    "The LBA range {lba}+{blocks} exceeds the namespace capacity of {capacity} blocks"
+                )
+            }
+            Error::CompareMismatch => {
+                write!(f, "The compared data does not match")
+            }
+            Error::TooManyQueues => {
+                write!(f, "All I/O queues granted by the controller are in use")
+            }
+            Error::IoQueuesExist => {
+                write!(f, "I/O queue pairs exist")
+            }
+            Error::InvalidLbaFormat => {
+                write!(f, "The LBA format is not supported by the namespace")
+            }
+            Error::SglNotSupported => {
+                write!(f, "The controller does not support SGLs")
+            }
+            Error::MetadataNotSupported => {
+                write!(f, "The namespace has no separate metadata")
+            }
+            Error::ProtectionNotSupported => {
+                write!(
+                    f,
+                    "The namespace is not formatted with protection information"
+                )
+            }
+            Error::FirmwareNotAligned => {
+                write!(
+                    f,
+                    "The firmware image part is not aligned to the update granularity"
+                )
+            }
+            Error::NotAtomic => {
+                write!(
+                    f,
+                    "The write exceeds the atomic write unit or crosses an atomic boundary"
+                )
+            }
+            Error::NamespaceReadOnly => {
+                write!(f, "The namespace is write protected")
             }
+            Error::InvalidZone => {
+                write!(f, "The LBA is not the first block of a zone")
+            }
+            Error::ZoneCapacityExceeded => {
+                write!(f, "The write exceeds the remaining capacity of the zone")
+            }
+            Error::ZoneFull => {
+                write!(f, "The zone is full")
+            }
+            Error::ZoneReadOnly => {
+                write!(f, "The zone is read only")
+            }
+            Error::ZoneOffline => {
+                write!(f, "The zone is offline")
+            }
+            Error::TooManyActiveZones => {
+                write!(f, "Too many zones are active")
+            }
+            Error::TooManyOpenZones => {
+                write!(f, "Too many zones are open")
+            }
+            Error::InvalidZoneStateTransition => {
+                write!(f, "Invalid zone state transition")
+            }
+        }
+    }
+}
+
+/// The error of `Device::delete_io_queue_pair`.
+///
+/// The queue pair could not be deleted and is handed back, so that it can
+/// be drained and deleted again later instead of being leaked.
+pub struct DeleteQueuePairError<A: Allocator> {
+    /// The reason the queue pair was not deleted
+    pub error: Error,
+    /// The queue pair which was not deleted
+    pub qpair: Box<IoQueuePair<A>>,
+}
+
+impl<A: Allocator> DeleteQueuePairError<A> {
+    pub(crate) fn new(error: Error, qpair: IoQueuePair<A>) -> Self {
+        Self {
+            error,
+            qpair: Box::new(qpair),
         }
     }
 }
 
+impl<A: Allocator> fmt::Debug for DeleteQueuePairError<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeleteQueuePairError")
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<A: Allocator> Display for DeleteQueuePairError<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.error, f)
+    }
+}
+
+impl<A: Allocator> core::error::Error for DeleteQueuePairError<A> {}
+
+impl<A: Allocator> From<DeleteQueuePairError<A>> for Error {
+    fn from(err: DeleteQueuePairError<A>) -> Self {
+        err.error
+    }
+}
+
 /// Result type for NVMe operations.
 pub type Result<T> = core::result::Result<T, Error>;
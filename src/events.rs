@@ -0,0 +1,143 @@
+use alloc::collections::vec_deque::VecDeque;
+
+use crate::cmd::Command;
+use crate::device::{Device, Doorbell};
+use crate::error::Result;
+use crate::memory::Allocator;
+use crate::queues::Completion;
+
+/// Command IDs of Asynchronous Event Requests are tagged with this bit,
+/// so that they never collide with other admin commands.
+pub(crate) const ASYNC_EVENT_CMD_ID: u16 = 0x8000;
+
+/// Maximum number of outstanding Asynchronous Event Requests.
+///
+/// They occupy completion queue slots once completed,
+/// so only a part of the admin queue is used for them.
+const MAX_ASYNC_REQUESTS: u16 = 16;
+
+/// An event reported asynchronously by the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AsyncEvent {
+    /// The type of the event (e.g. 1 for a SMART / health status event)
+    pub event_type: u8,
+    /// Information about the event, which depends on the type
+    pub info: u8,
+    /// The log page to read to get the details of the event
+    pub log_page: u8,
+}
+
+impl AsyncEvent {
+    /// Parses dword 0 of an Asynchronous Event Request completion.
+    fn from_raw(raw: u32) -> Self {
+        Self {
+            event_type: raw as u8 & 0x7,
+            info: (raw >> 8) as u8,
+            log_page: (raw >> 16) as u8,
+        }
+    }
+}
+
+/// The bookkeeping of Asynchronous Event Requests of a device.
+#[derive(Default)]
+pub(crate) struct AsyncEvents {
+    /// Events that have been reported but not polled yet
+    pending: VecDeque<AsyncEvent>,
+    /// Number of requests submitted to the controller
+    outstanding: u16,
+    /// Command ID of the next request
+    next_id: u16,
+}
+
+impl AsyncEvents {
+    /// Forgets all outstanding requests, e.g. after a reset.
+    pub fn reset(&mut self) {
+        self.outstanding = 0;
+    }
+}
+
+impl<A: Allocator> Device<A> {
+    /// Poll for an event reported asynchronously by the controller.
+    ///
+    /// Asynchronous Event Requests are submitted up to the limit of the
+    /// controller, and are completed by the controller once an event occurs
+    /// (e.g. a namespace change or a SMART warning). Events completed in the
+    /// meantime are returned one at a time, or `None` if there is none.
+    ///
+    /// The controller masks further events of the same type until the log
+    /// page of the event is read with `get_log_page`, so this should be done
+    /// for every event.
+    pub fn poll_async_event(&mut self) -> Result<Option<AsyncEvent>> {
+        let limit = self
+            .controller_data()
+            .max_async_events
+            .min(MAX_ASYNC_REQUESTS);
+        while self.async_events.outstanding < limit {
+            let cmd_id = ASYNC_EVENT_CMD_ID | self.async_events.next_id;
+            let Ok(tail) = self.admin_sq.try_push(Command::async_event_request(cmd_id)) else {
+                break;
+            };
+            self.doorbell_helper
+                .write(Doorbell::SubTail(0), tail as u32);
+            self.async_events.next_id = (self.async_events.next_id + 1) % ASYNC_EVENT_CMD_ID;
+            self.async_events.outstanding += 1;
+        }
+
+        // Only Asynchronous Event Requests can be outstanding here.
+        while let Some((head, entry)) = self.admin_cq.try_pop() {
+            self.reap_admin(head, entry)?;
+        }
+
+        Ok(self.async_events.pending.pop_front())
+    }
+
+    /// Keep the event if `entry` completes an Asynchronous Event Request.
+    ///
+    /// Returns whether it did, in which case it is no longer of interest
+    /// to the command waiting for its completion.
+    pub(crate) fn queue_async_event(&mut self, entry: &Completion) -> bool {
+        if entry.cmd_id() & ASYNC_EVENT_CMD_ID == 0 {
+            return false;
+        }
+
+        self.async_events.outstanding = self.async_events.outstanding.saturating_sub(1);
+        // Requests may fail, e.g. when they are aborted by a reset.
+        if entry.check().is_ok() {
+            let event = AsyncEvent::from_raw(entry.result());
+            self.async_events.pending.push_back(event);
+        }
+        true
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::mock::MockController;
+
+    #[test]
+    fn event_completed_before_blocking_commands() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+        assert_eq!(device.poll_async_event().unwrap(), None);
+
+        // The completions of the events are posted ahead of those
+        // of the blocking commands, which still get their own.
+        assert!(mock.trigger_async_event(2, 1, 0x02));
+        let serial_number = device.controller_data().serial_number.clone();
+        device.identify_controller().unwrap();
+        assert_eq!(device.controller_data().serial_number, serial_number);
+        assert!(mock.trigger_async_event(0, 3, 0x03));
+        let namespaces = device.identify_namespaces(0).unwrap();
+        assert_eq!(namespaces[0].block_count(), 1024);
+
+        let event = |event_type, info, log_page| AsyncEvent {
+            event_type,
+            info,
+            log_page,
+        };
+        assert_eq!(device.poll_async_event().unwrap(), Some(event(2, 1, 0x02)));
+        assert_eq!(device.poll_async_event().unwrap(), Some(event(0, 3, 0x03)));
+        assert_eq!(device.poll_async_event().unwrap(), None);
+    }
+}
@@ -0,0 +1,326 @@
+use crate::cmd::Command;
+use crate::device::{Device, Namespace};
+use crate::error::{Error, Result};
+use crate::memory::Allocator;
+use crate::status::StatusCodeType;
+
+/// Number of Queues feature identifier.
+pub(crate) const FEATURE_NUMBER_OF_QUEUES: u8 = 0x07;
+
+/// Error Recovery feature identifier.
+const FEATURE_ERROR_RECOVERY: u8 = 0x05;
+
+/// Arbitration feature identifier.
+const FEATURE_ARBITRATION: u8 = 0x01;
+
+/// Interrupt Coalescing feature identifier.
+const FEATURE_INTERRUPT_COALESCING: u8 = 0x08;
+
+/// Timestamp feature identifier.
+const FEATURE_TIMESTAMP: u8 = 0x0E;
+
+/// Generic status: Invalid Field in Command.
+const STATUS_INVALID_FIELD: u8 = 0x02;
+
+/// The timestamp is a 48-bit millisecond counter.
+const TIMESTAMP_MASK: u64 = (1 << 48) - 1;
+
+/// The attribute of a feature reported by Get Features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureSelect {
+    /// The value currently in use
+    Current = 0,
+    /// The default value of the controller
+    Default = 1,
+    /// The value that persists across power cycles
+    Saved = 2,
+    /// The capabilities of the feature (see `FeatureCapabilities`)
+    SupportedCapabilities = 3,
+}
+
+/// The priority class of an I/O submission queue.
+///
+/// With weighted round robin arbitration, urgent queues are always served
+/// first, and the other classes are served according to their weights set
+/// by `Device::set_arbitration`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePriority {
+    /// Served before all other I/O queues
+    Urgent = 0,
+    /// The class with the high weight
+    High = 1,
+    /// The class with the medium weight
+    Medium = 2,
+    /// The class with the low weight
+    Low = 3,
+}
+
+/// Capabilities of a feature.
+///
+/// Reported by Get Features with `FeatureSelect::SupportedCapabilities`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeatureCapabilities {
+    /// The feature value can be saved across power cycles
+    pub saveable: bool,
+    /// The feature is namespace specific
+    pub namespace_specific: bool,
+    /// The feature value can be changed by Set Features
+    pub changeable: bool,
+}
+
+impl FeatureCapabilities {
+    /// Decode the capabilities from dword 0 of the completion.
+    fn from_raw(raw: u32) -> Self {
+        Self {
+            saveable: raw & 1 != 0,
+            namespace_specific: raw & (1 << 1) != 0,
+            changeable: raw & (1 << 2) != 0,
+        }
+    }
+}
+
+/// The timestamp of a controller.
+///
+/// Reported by `Device::timestamp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    /// Milliseconds since the Unix epoch if it was set by the host,
+    /// otherwise milliseconds since the last reset
+    pub millis: u64,
+    /// The timestamp was set by `Device::set_timestamp` since the last reset
+    pub set_by_host: bool,
+    /// The timestamp may have stopped counting while the controller
+    /// was in a non-operational power state (Synch)
+    pub may_have_stopped: bool,
+}
+
+impl<A: Allocator> Device<A> {
+    /// Get the value of a feature.
+    ///
+    /// The `fid` is the feature identifier and `select` chooses which
+    /// attribute of the feature to return. Namespace specific features
+    /// require the `ns_id` of the target namespace, others take `None`.
+    ///
+    /// Returns dword 0 of the completion, whose meaning depends on the feature.
+    pub fn get_features(
+        &mut self,
+        fid: u8,
+        select: FeatureSelect,
+        ns_id: Option<u32>,
+    ) -> Result<u32> {
+        let cmd_id = self.next_cmd_id();
+        let completion = self.exec_admin(Command::get_features(
+            cmd_id,
+            ns_id.unwrap_or(0),
+            self.admin_buffer.phys_addr,
+            fid,
+            select as u8,
+        ))?;
+        Ok(completion.command_specific)
+    }
+
+    /// Set the value of a feature.
+    ///
+    /// The `value` is placed in dword 11 of the command and, if `save` is set,
+    /// the value persists across power cycles. See `get_features` for `ns_id`.
+    ///
+    /// Returns dword 0 of the completion, whose meaning depends on the feature.
+    pub fn set_features(
+        &mut self,
+        fid: u8,
+        value: u32,
+        save: bool,
+        ns_id: Option<u32>,
+    ) -> Result<u32> {
+        let cmd_id = self.next_cmd_id();
+        let completion = self.exec_admin(Command::set_features(
+            cmd_id,
+            ns_id.unwrap_or(0),
+            self.admin_buffer.phys_addr,
+            fid,
+            save,
+            value,
+        ))?;
+        Ok(completion.command_specific)
+    }
+
+    /// Get the capabilities of a feature.
+    ///
+    /// Useful to know whether a feature is saveable, namespace specific
+    /// or changeable before trying to set it.
+    pub fn feature_capabilities(&mut self, fid: u8) -> Result<FeatureCapabilities> {
+        let raw = self.get_features(fid, FeatureSelect::SupportedCapabilities, None)?;
+        Ok(FeatureCapabilities::from_raw(raw))
+    }
+}
+
+impl<A: Allocator> Device<A> {
+    /// Set the time limited error recovery of a namespace.
+    ///
+    /// The `deciseconds` is the time limit in 100 milliseconds units,
+    /// and 0 means there is no timeout.
+    ///
+    /// Returns `Error::FeatureNotSupported` if the controller reports
+    /// that error recovery is not namespace specific.
+    ///
+    /// This is a method of `Device` rather than `IoQueuePair`, as Set Features
+    /// is an admin command, which a queue pair cannot submit. Pass
+    /// `IoQueuePair::namespace` to tune the namespace a queue pair is bound to.
+    pub fn set_error_recovery_time(
+        &mut self,
+        namespace: &Namespace,
+        deciseconds: u16,
+    ) -> Result<()> {
+        // Not all controllers support reporting capabilities, they reject
+        // the select field as invalid. Only validate when they are available.
+        match self.feature_capabilities(FEATURE_ERROR_RECOVERY) {
+            Ok(caps) if !caps.namespace_specific => return Err(Error::FeatureNotSupported),
+            Ok(_) => {}
+            Err(Error::CommandFailedRetryable(status) | Error::CommandFailedFatal(status))
+                if status.code_type == StatusCodeType::Generic
+                    && status.code == STATUS_INVALID_FIELD => {}
+            Err(err) => return Err(err),
+        }
+
+        let current = self.get_features(
+            FEATURE_ERROR_RECOVERY,
+            FeatureSelect::Current,
+            Some(namespace.id()),
+        )?;
+        let value = (current & !0xFFFF) | deciseconds as u32;
+        self.set_features(FEATURE_ERROR_RECOVERY, value, false, Some(namespace.id()))?;
+        Ok(())
+    }
+
+    /// Set the arbitration between the I/O submission queues.
+    ///
+    /// The controller takes at most 2^`burst` commands from a queue at a
+    /// time, and 7 means there is no limit. The `low`, `medium` and `high`
+    /// weights are the zero-based numbers of commands of each priority class
+    /// per round, which are only used with weighted round robin arbitration.
+    pub fn set_arbitration(&mut self, burst: u8, low: u8, medium: u8, high: u8) -> Result<()> {
+        let value = (high as u32) << 24 | (medium as u32) << 16 | (low as u32) << 8;
+        self.set_features(
+            FEATURE_ARBITRATION,
+            value | (burst & 0x7) as u32,
+            false,
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// Set the interrupt coalescing of the I/O completion queues.
+    ///
+    /// The controller delays an interrupt until `aggregation_threshold`
+    /// (zero-based) completions have been posted or `aggregation_time`
+    /// has passed since the first one, in 100 microseconds units.
+    /// With both 0, every completion raises an interrupt.
+    ///
+    /// This trades latency for fewer interrupts. The admin queue is never
+    /// coalesced, and `create_polling_queue_pair` queues raise no interrupt.
+    pub fn set_interrupt_coalescing(
+        &mut self,
+        aggregation_threshold: u8,
+        aggregation_time: u8,
+    ) -> Result<()> {
+        let value = (aggregation_time as u32) << 8 | aggregation_threshold as u32;
+        self.set_features(FEATURE_INTERRUPT_COALESCING, value, false, None)?;
+        Ok(())
+    }
+
+    /// Set the timestamp of the controller, e.g. the current time.
+    ///
+    /// The controller keeps counting from `millis_since_epoch`, so that
+    /// the times in its log pages can be correlated with the host.
+    /// It has to be set again after a reset.
+    ///
+    /// Returns `Error::FeatureNotSupported` if the controller
+    /// does not support the timestamp feature.
+    pub fn set_timestamp(&mut self, millis_since_epoch: u64) -> Result<()> {
+        if !self.data.supports_timestamp() {
+            return Err(Error::FeatureNotSupported);
+        }
+
+        let timestamp = millis_since_epoch & TIMESTAMP_MASK;
+        self.admin_buffer[..8].copy_from_slice(&timestamp.to_le_bytes());
+        self.set_features(FEATURE_TIMESTAMP, 0, false, None)?;
+        Ok(())
+    }
+
+    /// Get the timestamp of the controller.
+    ///
+    /// See `set_timestamp` for more details.
+    pub fn timestamp(&mut self) -> Result<Timestamp> {
+        if !self.data.supports_timestamp() {
+            return Err(Error::FeatureNotSupported);
+        }
+
+        self.get_features(FEATURE_TIMESTAMP, FeatureSelect::Current, None)?;
+        let data = u64::from_le_bytes(self.admin_buffer[..8].try_into().unwrap());
+        let attributes = (data >> 48) as u8;
+        Ok(Timestamp {
+            millis: data & TIMESTAMP_MASK,
+            set_by_host: (attributes >> 1) & 0x7 == 1,
+            may_have_stopped: attributes & 1 != 0,
+        })
+    }
+
+    /// Get the time limited error recovery of a namespace.
+    ///
+    /// Returns the time limit in 100 milliseconds units.
+    /// See `set_error_recovery_time` for more details.
+    pub fn error_recovery_time(&mut self, namespace: &Namespace) -> Result<u16> {
+        let value = self.get_features(
+            FEATURE_ERROR_RECOVERY,
+            FeatureSelect::Current,
+            Some(namespace.id()),
+        )?;
+        Ok(value as u16)
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::mock::MockController;
+
+    #[test]
+    fn error_recovery_not_namespace_specific() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+        let namespace = device.identify_namespaces(0).unwrap().remove(0);
+
+        // The mock reports the feature as saveable and changeable only.
+        assert!(matches!(
+            device.set_error_recovery_time(&namespace, 70),
+            Err(Error::FeatureNotSupported)
+        ));
+    }
+
+    #[test]
+    fn error_recovery_without_capabilities() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+        let namespace = device.identify_namespaces(0).unwrap().remove(0);
+
+        // Reporting capabilities is rejected with Invalid Field in Command.
+        mock.fail_next_command(STATUS_INVALID_FIELD as u16);
+        device.set_error_recovery_time(&namespace, 70).unwrap();
+        assert_eq!(device.error_recovery_time(&namespace).unwrap(), 70);
+    }
+
+    #[test]
+    fn error_recovery_capabilities_failure() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+        let namespace = device.identify_namespaces(0).unwrap().remove(0);
+
+        // Any other failure is not taken for missing capabilities: Internal Error.
+        mock.fail_next_command(0x06);
+        assert!(matches!(
+            device.set_error_recovery_time(&namespace, 70),
+            Err(Error::CommandFailedRetryable(_))
+        ));
+        assert_eq!(device.error_recovery_time(&namespace).unwrap(), 0);
+    }
+}
@@ -0,0 +1,360 @@
+use alloc::string::{String, ToString};
+
+use crate::cmd::Command;
+use crate::device::{Device, NSSR_RESET, Register};
+use crate::error::{Error, Result};
+use crate::memory::{Allocator, Dma};
+use crate::status::StatusCodeType;
+
+/// Command specific status: Firmware Activation Requires Conventional Reset.
+const STATUS_REQUIRES_CONVENTIONAL_RESET: u8 = 0x0B;
+/// Command specific status: Firmware Activation Requires NVM Subsystem Reset.
+const STATUS_REQUIRES_SUBSYSTEM_RESET: u8 = 0x10;
+/// Command specific status: Firmware Activation Requires Controller Level Reset.
+const STATUS_REQUIRES_CONTROLLER_RESET: u8 = 0x11;
+
+/// Firmware Slot Information log page identifier.
+const LOG_FIRMWARE_SLOT: u8 = 0x03;
+
+/// Maximum number of firmware slots.
+const MAX_FIRMWARE_SLOTS: usize = 7;
+
+/// What a firmware commit does with the image in the slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitAction {
+    /// Store the downloaded image into the slot without activating it
+    Replace = 0,
+    /// Store the downloaded image and activate it at the next reset
+    ReplaceAndActivate = 1,
+    /// Activate the image already in the slot at the next reset
+    Activate = 2,
+    /// Store the downloaded image and activate it immediately
+    ReplaceAndActivateNow = 3,
+}
+
+/// The activation state of the firmware after a commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareActivation {
+    /// The image was stored and will not be activated
+    Stored,
+    /// The image will be activated at the next reset
+    NextReset,
+    /// The image was activated without a reset
+    Activated,
+    /// A conventional (PCIe) reset is required to activate the image
+    RequiresConventionalReset,
+    /// An NVM subsystem reset is required to activate the image
+    RequiresSubsystemReset,
+    /// A controller level reset is required to activate the image
+    RequiresControllerReset,
+}
+
+/// The firmware slots of a controller and the images in them.
+///
+/// Reported by the Firmware Slot Information log page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareSlots {
+    /// The slot of the running firmware (1 to 7)
+    pub active_slot: u8,
+    /// The slot that is activated at the next reset, if any
+    pub next_slot: Option<u8>,
+    /// The firmware revision in each slot, indexed by the slot number - 1
+    ///
+    /// It is `None` if the slot is empty or not supported.
+    pub revisions: [Option<String>; MAX_FIRMWARE_SLOTS],
+}
+
+impl<A: Allocator> Device<A> {
+    /// Get the firmware slots, e.g. to see which firmware is running
+    /// and which one is pending after `firmware_commit`.
+    pub fn firmware_slots(&mut self) -> Result<FirmwareSlots> {
+        // The active firmware info and the revision of each slot.
+        let mut log = [0u8; 64];
+        self.get_log_page(LOG_FIRMWARE_SLOT, 0, 0, &mut log)?;
+
+        let next_slot = (log[0] >> 4) & 0x7;
+        let revisions = core::array::from_fn(|slot| {
+            let revision = &log[8 + slot * 8..16 + slot * 8];
+            let revision = str::from_utf8(revision).unwrap_or_default();
+            let revision = revision.trim_matches(|c: char| c == ' ' || c == '\0');
+            (!revision.is_empty()).then(|| revision.to_string())
+        });
+
+        Ok(FirmwareSlots {
+            active_slot: log[0] & 0x7,
+            next_slot: (next_slot != 0).then_some(next_slot),
+            revisions,
+        })
+    }
+
+    /// Download a part of a firmware image to the controller.
+    ///
+    /// The `offset` is the byte offset of `image` in the whole image.
+    /// Large parts are split into multiple commands, each a multiple of the
+    /// firmware update granularity which fits into the maximum data transfer
+    /// size. The whole image may be passed at once.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the offset or the image size is not
+    /// a multiple of dword (4 bytes), and `Error::FirmwareNotAligned` if the
+    /// offset is not a multiple of `firmware_update_granularity`.
+    pub fn firmware_download(&mut self, offset: u32, image: &[u8]) -> Result<()> {
+        if offset & 0x3 != 0 {
+            return Err(Error::NotAlignedToDword);
+        }
+        if !image.len().is_multiple_of(4) {
+            return Err(Error::InvalidBufferSize);
+        }
+        let granularity = self.controller_data().firmware_update_granularity;
+        if !(offset as usize).is_multiple_of(granularity) {
+            return Err(Error::FirmwareNotAligned);
+        }
+
+        let max_transfer_size = self.controller_data().max_transfer_size;
+        let chunk_size = self
+            .admin_buffer
+            .len()
+            .max(granularity)
+            .min(max_transfer_size)
+            / granularity
+            * granularity;
+        if chunk_size == 0 {
+            return Err(Error::IoSizeExceedsMdts);
+        }
+
+        let page_size = self.prp_manager.page_size();
+        let buffer = Dma::<u8>::allocate_aligned(chunk_size, page_size, self.allocator.as_ref());
+        let mut result = Ok(());
+        for (index, chunk) in image.chunks(chunk_size).enumerate() {
+            unsafe {
+                buffer
+                    .addr
+                    .copy_from_nonoverlapping(chunk.as_ptr(), chunk.len())
+            };
+            result = self
+                .prp_manager
+                .create(self.allocator.as_ref(), buffer.addr as usize, chunk.len())
+                .and_then(|prp_result| {
+                    let prp = prp_result.get_prp();
+                    let cmd_id = self.next_cmd_id();
+                    let result = self.exec_admin(Command::firmware_download(
+                        cmd_id,
+                        [prp.0 as u64, prp.1 as u64],
+                        (chunk.len() / 4) as u32,
+                        (offset as usize + index * chunk_size) as u32 / 4,
+                    ));
+                    self.prp_manager
+                        .release(prp_result, self.allocator.as_ref());
+                    result
+                })
+                .map(|_| ());
+            if result.is_err() {
+                break;
+            }
+        }
+
+        buffer.deallocate(self.allocator.as_ref());
+        result
+    }
+
+    /// Commit the downloaded firmware image to `slot`.
+    ///
+    /// Slot 0 lets the controller choose the slot for `Replace` actions.
+    ///
+    /// The returned `FirmwareActivation` tells whether a reset is needed
+    /// before the new image is running, which can be performed by
+    /// `apply_firmware_reset`. Until then the controller keeps running
+    /// the old image.
+    pub fn firmware_commit(
+        &mut self,
+        slot: u8,
+        action: CommitAction,
+    ) -> Result<FirmwareActivation> {
+        let cmd_id = self.next_cmd_id();
+        let result = self.exec_admin(Command::firmware_commit(cmd_id, slot, action as u8));
+
+        match result {
+            Ok(_) => Ok(match action {
+                CommitAction::Replace => FirmwareActivation::Stored,
+                CommitAction::ReplaceAndActivate | CommitAction::Activate => {
+                    FirmwareActivation::NextReset
+                }
+                CommitAction::ReplaceAndActivateNow => FirmwareActivation::Activated,
+            }),
+            Err(Error::CommandFailedRetryable(status) | Error::CommandFailedFatal(status))
+                if status.code_type == StatusCodeType::CommandSpecific =>
+            {
+                match status.code {
+                    STATUS_REQUIRES_CONVENTIONAL_RESET => {
+                        Ok(FirmwareActivation::RequiresConventionalReset)
+                    }
+                    STATUS_REQUIRES_SUBSYSTEM_RESET => {
+                        Ok(FirmwareActivation::RequiresSubsystemReset)
+                    }
+                    STATUS_REQUIRES_CONTROLLER_RESET => {
+                        Ok(FirmwareActivation::RequiresControllerReset)
+                    }
+                    _ => Err(status.into()),
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Perform the reset required to activate a committed firmware image.
+    ///
+    /// Controller level and NVM subsystem resets are done by the driver,
+    /// after which the controller is enabled and identified again.
+    /// All I/O queue pairs are lost by the reset and must not be used anymore,
+    /// they have to be created again with `create_io_queue_pair`.
+    ///
+    /// Nothing is done if no reset is required.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::FeatureNotSupported` for a conventional reset, which
+    /// has to be done by the caller through the PCIe bus, and for an NVM
+    /// subsystem reset if the controller does not support it.
+    pub fn apply_firmware_reset(&mut self, activation: FirmwareActivation) -> Result<()> {
+        match activation {
+            FirmwareActivation::Stored | FirmwareActivation::Activated => return Ok(()),
+            FirmwareActivation::RequiresConventionalReset => {
+                return Err(Error::FeatureNotSupported);
+            }
+            FirmwareActivation::RequiresSubsystemReset => {
+                if !self.capabilities().subsystem_reset {
+                    return Err(Error::FeatureNotSupported);
+                }
+                self.set_reg::<u32>(Register::NSSR, NSSR_RESET);
+            }
+            FirmwareActivation::NextReset | FirmwareActivation::RequiresControllerReset => {}
+        }
+
+        self.initialize()
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::mmio::Mmio;
+    use crate::mock::MockController;
+
+    /// Status code type Command Specific, in the format of `fail_next_command`.
+    const COMMAND_SPECIFIC: u16 = 1 << 8;
+
+    /// Commits with the mock failing the commit with `status`, if given.
+    fn commit(
+        mock: &MockController,
+        action: CommitAction,
+        status: Option<u8>,
+    ) -> FirmwareActivation {
+        let mut device = mock.device();
+        if let Some(status) = status {
+            mock.fail_next_command(COMMAND_SPECIFIC | status as u16);
+        }
+        device.firmware_commit(1, action).unwrap()
+    }
+
+    #[test]
+    fn commit_without_reset() {
+        let mock = MockController::new(1024, 512);
+        let cases = [
+            (CommitAction::Replace, FirmwareActivation::Stored),
+            (
+                CommitAction::ReplaceAndActivate,
+                FirmwareActivation::NextReset,
+            ),
+            (CommitAction::Activate, FirmwareActivation::NextReset),
+            (
+                CommitAction::ReplaceAndActivateNow,
+                FirmwareActivation::Activated,
+            ),
+        ];
+        for (action, activation) in cases {
+            assert_eq!(commit(&mock, action, None), activation);
+        }
+    }
+
+    #[test]
+    fn commit_requiring_reset() {
+        let mock = MockController::new(1024, 512);
+        let cases = [
+            (
+                STATUS_REQUIRES_CONVENTIONAL_RESET,
+                FirmwareActivation::RequiresConventionalReset,
+            ),
+            (
+                STATUS_REQUIRES_SUBSYSTEM_RESET,
+                FirmwareActivation::RequiresSubsystemReset,
+            ),
+            (
+                STATUS_REQUIRES_CONTROLLER_RESET,
+                FirmwareActivation::RequiresControllerReset,
+            ),
+        ];
+        for (status, activation) in cases {
+            let action = CommitAction::ReplaceAndActivateNow;
+            assert_eq!(commit(&mock, action, Some(status)), activation);
+        }
+    }
+
+    #[test]
+    fn commit_failure() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+        // Invalid Firmware Slot
+        mock.fail_next_command(COMMAND_SPECIFIC | 0x06);
+        let result = device.firmware_commit(1, CommitAction::Activate);
+        assert!(matches!(result, Err(Error::CommandFailedRetryable(_))));
+    }
+
+    #[test]
+    fn reset_not_required() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+        let commands = mock.command_count();
+        for activation in [FirmwareActivation::Stored, FirmwareActivation::Activated] {
+            device.apply_firmware_reset(activation).unwrap();
+        }
+        assert_eq!(mock.command_count(), commands);
+    }
+
+    #[test]
+    fn conventional_reset() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+        let result = device.apply_firmware_reset(FirmwareActivation::RequiresConventionalReset);
+        assert!(matches!(result, Err(Error::FeatureNotSupported)));
+    }
+
+    #[test]
+    fn controller_reset() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+        for activation in [
+            FirmwareActivation::NextReset,
+            FirmwareActivation::RequiresControllerReset,
+        ] {
+            let namespace = device.identify_namespaces(0).unwrap().remove(0);
+            let _qpair = device.create_io_queue_pair(namespace, 64, 0, None).unwrap();
+            device.apply_firmware_reset(activation).unwrap();
+            // The reset lost the queues, and the controller is up again.
+            assert_eq!(mock.io_queue_count(), 0);
+            assert_eq!(device.firmware_slots().unwrap().active_slot, 1);
+        }
+    }
+
+    #[test]
+    fn subsystem_reset() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+        device
+            .apply_firmware_reset(FirmwareActivation::RequiresSubsystemReset)
+            .unwrap();
+        // CSTS.NSSRO reports that an NVM subsystem reset occurred.
+        assert_ne!(mock.read32(Register::CSTS as usize) & (1 << 4), 0);
+        assert_eq!(device.firmware_slots().unwrap().active_slot, 1);
+    }
+}
@@ -0,0 +1,83 @@
+use crate::cmd::{Command, IdentifyType};
+use crate::device::{Device, NamespaceData};
+use crate::error::{Error, Result};
+use crate::memory::Allocator;
+use crate::timeout::Timeout;
+
+/// Formatting may take much longer than a controller state change,
+/// so its timeout is this multiple of CAP.TO.
+const FORMAT_TIMEOUT_FACTOR: usize = 120;
+
+/// The secure erase performed by `Device::format_namespace`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecureErase {
+    /// No secure erase
+    None = 0,
+    /// All user data is erased
+    UserData = 1,
+    /// The encryption key is erased, making all user data unreadable
+    Cryptographic = 2,
+}
+
+impl<A: Allocator> Device<A> {
+    /// Format the namespace `ns_id` with the LBA format `lba_format_index`.
+    ///
+    /// The supported LBA formats (e.g. 512 or 4096 byte blocks) are those
+    /// reported by the namespace. Formatting may also erase the user data,
+    /// which is guaranteed with `SecureErase::UserData` and
+    /// `SecureErase::Cryptographic`. Metadata and protection information
+    /// are not used.
+    ///
+    /// It blocks until the format is done, which can take minutes. Afterwards
+    /// the namespace has to be identified again with `identify_namespaces`,
+    /// as its block size and count may have changed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::InvalidLbaFormat` if the namespace
+    /// does not support the LBA format, and `Error::FeatureNotSupported`
+    /// if the controller does not support Format NVM.
+    pub fn format_namespace(
+        &mut self,
+        ns_id: u32,
+        lba_format_index: u8,
+        secure_erase: SecureErase,
+    ) -> Result<()> {
+        if !self.data.supports_format() {
+            return Err(Error::FeatureNotSupported);
+        }
+
+        self.admin_buffer.zero();
+        let cmd_id = self.next_cmd_id();
+        self.exec_admin(Command::identify(
+            cmd_id,
+            self.admin_buffer.phys_addr,
+            IdentifyType::Namespace(ns_id),
+        ))?;
+
+        let data = unsafe { &*(self.admin_buffer.addr as *const NamespaceData) };
+        let formats = data.lba_format_support;
+        // The count is zero-based, and a format with no data size is unused.
+        let supported = formats
+            .get(lba_format_index as usize)
+            .filter(|_| lba_format_index <= data.lba_format_count)
+            .is_some_and(|format| (format >> 16) & 0xFF != 0);
+        if !supported {
+            return Err(Error::InvalidLbaFormat);
+        }
+
+        let command = Command::format_nvm(
+            self.next_cmd_id(),
+            ns_id,
+            lba_format_index,
+            secure_erase as u8,
+            false,
+            0,
+            false,
+        );
+        let timeout_ms = self.capabilities().timeout_ms.max(500) as usize;
+        let timeout = Timeout::from_millis(timeout_ms * FORMAT_TIMEOUT_FACTOR);
+        self.exec_admin_with_timeout(command, timeout)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use core::future::Future;
+use core::marker::PhantomData;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use crate::error::{Error, Result};
+use crate::io::IoQueuePair;
+use crate::memory::Allocator;
+
+/// A read or write request resolving once the device completed it.
+///
+/// Returned by `IoQueuePair::read_async` and `IoQueuePair::write_async`.
+/// The request is submitted when the future is polled first. While it is
+/// pending, the waker of the task is registered to the `CompletionWaiter`
+/// of the queue pair, which should wake it up on the completion interrupt
+/// (see `WakerWaiter`).
+///
+/// Dropping the future before it is ready blocks until the device
+/// no longer uses the buffer.
+#[must_use = "futures do nothing unless polled"]
+pub struct IoFuture<'a, A: Allocator> {
+    qpair: &'a mut IoQueuePair<A>,
+    address: usize,
+    bytes: usize,
+    lba: u64,
+    write: bool,
+    /// Number of bytes submitted so far
+    submitted: usize,
+    /// The error which stopped the submission
+    error: Option<Error>,
+    _buf: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a, A: Allocator> IoFuture<'a, A> {
+    fn new(
+        qpair: &'a mut IoQueuePair<A>,
+        address: usize,
+        bytes: usize,
+        lba: u64,
+        write: bool,
+    ) -> Self {
+        let block_size = qpair.namespace().block_size();
+        let error =
+            (!(bytes as u64).is_multiple_of(block_size)).then_some(Error::InvalidBufferSize);
+        Self {
+            qpair,
+            address,
+            bytes: if error.is_some() { 0 } else { bytes },
+            lba,
+            write,
+            submitted: 0,
+            error,
+            _buf: PhantomData,
+        }
+    }
+
+    /// Submits the remaining parts of the request while there is room.
+    ///
+    /// Each part is at most the maximum transfer size.
+    fn submit(&mut self) {
+        let block_size = self.qpair.namespace().block_size();
+        while self.submitted < self.bytes {
+            let len = (self.bytes - self.submitted).min(self.qpair.max_transfer_size);
+            let lba = self.lba + self.submitted as u64 / block_size;
+            let address = self.address + self.submitted;
+            match self
+                .qpair
+                .submit_and_track(len, lba, address, self.write, Default::default())
+            {
+                Ok(()) => self.submitted += len,
+                Err(Error::SubQueueFull) => break,
+                Err(err) => {
+                    // Only wait for the parts which are in flight.
+                    self.bytes = self.submitted;
+                    self.error = Some(err);
+                }
+            }
+        }
+    }
+}
+
+impl<A: Allocator> Future for IoFuture<'_, A> {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            this.submit();
+
+            this.qpair.waiter.register(cx.waker());
+            let result = match this.qpair.try_complete_all() {
+                Poll::Ready(result) => result,
+                Poll::Pending => return Poll::Pending,
+            };
+            // The queue was full, submit the next parts now that it is empty.
+            if result.is_ok() && this.submitted < this.bytes {
+                continue;
+            }
+
+            if let Some(err) = this.error.take() {
+                return Poll::Ready(Err(err));
+            }
+            return Poll::Ready(
+                result.and_then(|entry| entry.map_or(Ok(()), |entry| entry.check())),
+            );
+        }
+    }
+}
+
+impl<A: Allocator> Drop for IoFuture<'_, A> {
+    fn drop(&mut self) {
+        if !self.qpair.is_idle() {
+            let _ = self.qpair.flush();
+        }
+    }
+}
+
+impl<A: Allocator> IoQueuePair<A> {
+    /// Reads into `buf` starting from `lba` asynchronously.
+    ///
+    /// The returned future resolves once all the data has arrived, and the
+    /// buffer is split at the maximum transfer size like `read_blocks`.
+    /// Previously submitted requests are waited for as well, and the status
+    /// is the one of the last completed command like `flush`.
+    ///
+    /// # Errors
+    ///
+    /// The future resolves to `Error::InvalidBufferSize` if the length of
+    /// `buf` is not a multiple of the block size.
+    pub fn read_async<'a>(&'a mut self, buf: &'a mut [u8], lba: u64) -> IoFuture<'a, A> {
+        IoFuture::new(self, buf.as_mut_ptr() as usize, buf.len(), lba, false)
+    }
+
+    /// Writes `buf` to the blocks starting from `lba` asynchronously.
+    ///
+    /// See `read_async` for more details.
+    pub fn write_async<'a>(&'a mut self, buf: &'a [u8], lba: u64) -> IoFuture<'a, A> {
+        IoFuture::new(self, buf.as_ptr() as usize, buf.len(), lba, true)
+    }
+}
@@ -1,18 +1,39 @@
 use alloc::collections::vec_deque::VecDeque;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::ops::Deref;
-use core::sync::atomic::{AtomicU16, Ordering};
+#[cfg(feature = "async")]
+use core::task::Poll;
 
-use crate::cmd::Command;
-use crate::device::{Doorbell, DoorbellHelper, Namespace};
+use crate::cmb::Cmb;
+use crate::cmd::{Command, RawCommand};
+use crate::device::{
+    ControllerData, Doorbell, DoorbellHelper, Namespace, ONCS_COMPARE, ONCS_DATASET_MANAGEMENT,
+    ONCS_WRITE_ZEROES,
+};
 use crate::error::{Error, Result};
-use crate::memory::{Allocator, PrpManager, PrpResult};
-use crate::queues::{CompQueue, SubQueue};
+use crate::memory::{Allocator, Dma, PrpManager, PrpResult};
+use crate::queues::{CompQueue, Completion, SubQueue};
+use crate::sgl::Sgl;
+use crate::timeout::{IO_TIMEOUT_MS, NAMESPACE_READY_TIMEOUT_MS, Timeout};
+use crate::waiter::{CompletionWaiter, PollingWaiter};
+
+/// Maximum delay between two retries (in spin iterations).
+const MAX_RETRY_DELAY: usize = 1 << 20;
+
+/// Maximum number of blocks in a single Write Zeroes command.
+const MAX_WRITE_ZEROES_BLOCKS: u32 = 1 << 16;
+
+/// Maximum number of ranges in a single Dataset Management command.
+const MAX_DSM_RANGES: usize = 256;
+
+/// Dataset Management attribute: Deallocate (AD).
+const DSM_ATTRIBUTE_DEALLOCATE: u32 = 1 << 2;
 
 /// A unique identifier for an I/O queue.
 ///
-/// It self-increments starting from 1 and add each time
-/// a new queue is created. The 0 is reserved for the admin queue pair.
+/// It is allocated by the device from the I/O queues granted
+/// by the controller. The 0 is reserved for the admin queue pair.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct IoQueueId(u16);
 
@@ -24,17 +45,151 @@ impl Deref for IoQueueId {
     }
 }
 
-#[allow(clippy::new_without_default)]
 impl IoQueueId {
-    pub fn new() -> Self {
-        static NEXT_ID: AtomicU16 = AtomicU16::new(1);
-        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    pub(crate) fn new(id: u16) -> Self {
+        Self(id)
     }
 }
 
+/// Access latency hint of an I/O command.
+///
+/// NVMe has no per-command arbitration priority, as the arbitration class
+/// belongs to the submission queue. Instead, this hint is carried in the
+/// dataset management field of a read or write so that the controller can
+/// favor latency sensitive commands over bulk traffic on the same queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLatency {
+    /// Longer latency is acceptable
+    Idle = 1,
+    /// Typical latency
+    Normal = 2,
+    /// Smallest possible latency
+    Low = 3,
+}
+
+/// The kind of an `IoOp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoOpKind {
+    /// Read from the device into the buffer
+    Read,
+    /// Write the buffer to the device
+    Write,
+}
+
+/// A read or write request submitted by `IoQueuePair::submit_batch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoOp {
+    /// Whether to read or write
+    pub kind: IoOpKind,
+    /// The virtual address of the buffer
+    pub buf_addr: usize,
+    /// The size of the buffer (in bytes)
+    pub bytes: usize,
+    /// The first logical block to access
+    pub lba: u64,
+}
+
+/// A range of logical blocks used by `IoQueuePair::deallocate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LbaRange {
+    /// The first logical block of the range
+    pub starting_lba: u64,
+    /// The number of logical blocks in the range
+    pub length: u32,
+}
+
+/// A snapshot of the state of an I/O queue pair, see `IoQueuePair::debug_dump`.
+#[cfg(feature = "debug")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueDebugInfo {
+    /// The identifier of the queue pair
+    pub queue_id: u16,
+    /// The address of the submission queue given to the controller,
+    /// which is a PRP list if the queue is not physically contiguous
+    pub sub_queue_address: usize,
+    /// The number of submission queue slots
+    pub sub_queue_size: usize,
+    /// The last submission queue head reported by the controller
+    pub sub_queue_head: usize,
+    /// The submission queue tail, where the next command is placed
+    pub sub_queue_tail: usize,
+    /// The address of the completion queue given to the controller,
+    /// which is a PRP list if the queue is not physically contiguous
+    pub comp_queue_address: usize,
+    /// The number of completion queue slots
+    pub comp_queue_size: usize,
+    /// The completion queue head, where the next completion is expected
+    pub comp_queue_head: usize,
+    /// The phase tag of the next completion
+    pub phase: bool,
+    /// The command IDs of the commands in flight, in submission order
+    pub outstanding: Vec<u16>,
+}
+
+/// End-to-end protection information of a read or write.
+///
+/// It controls how the controller generates and checks the protection
+/// information (T10 DIF) of each block, see `IoQueuePair::read_with_protection`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtectionInfo {
+    /// The controller inserts (on writes) or strips (on reads) the
+    /// protection information instead of transferring it (PRACT)
+    pub action: bool,
+    /// Check the guard field (CRC) of each block
+    pub check_guard: bool,
+    /// Check the application tag of each block
+    pub check_app_tag: bool,
+    /// Check the reference tag of each block
+    pub check_reference_tag: bool,
+    /// The expected reference tag of the first block
+    pub reference_tag: u32,
+    /// The expected application tag
+    pub app_tag: u16,
+    /// The bits of `app_tag` that are checked
+    pub app_tag_mask: u16,
+}
+
+impl ProtectionInfo {
+    /// Get the value of the PRINFO field.
+    fn prinfo(&self) -> u8 {
+        (self.action as u8) << 3
+            | (self.check_guard as u8) << 2
+            | (self.check_app_tag as u8) << 1
+            | self.check_reference_tag as u8
+    }
+}
+
+/// Options of a write used by `IoQueuePair::write_blocks_with`.
+///
+/// The default is a plain write, which may be held in the write cache
+/// and is retried by the controller as usual.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// Force Unit Access: the data is written to non-volatile media
+    /// before the write completes, bypassing the volatile write cache
+    pub fua: bool,
+    /// Limited Retry: the controller applies limited retry efforts,
+    /// so that an error is reported early instead
+    pub limited_retry: bool,
+}
+
+/// Optional fields of a read or write command.
+#[derive(Default)]
+pub(crate) struct RequestOptions {
+    latency: Option<AccessLatency>,
+    metadata: Option<usize>,
+    protection: Option<ProtectionInfo>,
+    write: WriteOptions,
+}
+
 /// A queue pair for handling NVMe I/O operations.
 ///
 /// All your I/O operations should be done through this queue pair.
+///
+/// Requests submitted by `read` and `write` are not waited for, so several
+/// of them can be in flight at once. `flush` waits for all of them and
+/// releases their resources, while the blocking methods (`read_blocks`,
+/// `read_sync`, ...) flush the earlier requests before their own.
 pub struct IoQueuePair<A: Allocator> {
     id: IoQueueId,
     allocator: Arc<A>,
@@ -43,8 +198,24 @@ pub struct IoQueuePair<A: Allocator> {
     sub_queue: SubQueue,
     comp_queue: CompQueue,
     prp_manager: PrpManager,
-    max_transfer_size: usize,
-    submitted: VecDeque<PrpResult>,
+    pub(crate) max_transfer_size: usize,
+    sgl_support: Option<bool>,
+    /// Optional NVM commands supported by the controller (ONCS)
+    optional_nvm_commands: u16,
+    /// The fused Compare and Write operation is supported
+    compare_and_write: bool,
+    /// The command IDs and PRP results of the commands in flight
+    submitted: VecDeque<(u16, PrpResult)>,
+    /// The last completion reaped since the queue pair was idle
+    last: Option<Completion>,
+    /// The first failed completion reaped since the queue pair was idle
+    failed: Option<Completion>,
+    pub(crate) waiter: Arc<dyn CompletionWaiter>,
+    /// The submission queue is already deleted on the controller
+    pub(crate) sub_queue_deleted: bool,
+    /// The PRP results of the commands in flight when a completion was
+    /// implausible, which the controller may still access
+    quarantined: Vec<PrpResult>,
 }
 
 impl<A: Allocator> IoQueuePair<A> {
@@ -55,7 +226,7 @@ impl<A: Allocator> IoQueuePair<A> {
         sub_queue: SubQueue,
         comp_queue: CompQueue,
         allocator: Arc<A>,
-        max_transfer_size: usize,
+        data: &ControllerData,
     ) -> Self {
         Self {
             id,
@@ -63,37 +234,122 @@ impl<A: Allocator> IoQueuePair<A> {
             doorbell_helper,
             sub_queue,
             comp_queue,
-            prp_manager: Default::default(),
+            prp_manager: PrpManager::new(data.min_pagesize),
             allocator,
-            max_transfer_size,
+            max_transfer_size: data.max_transfer_size,
+            sgl_support: data.sgl_supported.then_some(data.sgl_dword_aligned),
+            optional_nvm_commands: data.optional_nvm_commands,
+            compare_and_write: data.supports_compare_and_write(),
             submitted: Default::default(),
+            last: None,
+            failed: None,
+            waiter: Arc::new(PollingWaiter),
+            sub_queue_deleted: false,
+            quarantined: Vec::new(),
         }
     }
 }
 
 impl<A: Allocator> IoQueuePair<A> {
-    fn submit_and_track(
+    /// Builds and submits a read or write command.
+    ///
+    /// On success the PRP result is tracked in `submitted` until the command
+    /// is completed by `flush`. On any error, including a full submission
+    /// queue, nothing is submitted and the PRP result is released here.
+    pub(crate) fn submit_and_track(
         &mut self,
         bytes: usize,
         lba: u64,
         address: usize,
         write: bool,
+        options: RequestOptions,
     ) -> Result<()> {
-        if bytes > self.max_transfer_size {
-            return Err(Error::IoSizeExceedsMdts);
+        let new_tail = self.push_and_track(bytes, lba, address, write, options)?;
+        self.doorbell_helper
+            .write(Doorbell::SubTail(*self.id), new_tail as u32);
+        Ok(())
+    }
+
+    /// Builds a read or write command and pushes it to the submission queue.
+    ///
+    /// Like `submit_and_track`, but the doorbell is not rung, so the command
+    /// is not seen by the controller yet. Returns the new tail.
+    fn push_and_track(
+        &mut self,
+        bytes: usize,
+        lba: u64,
+        address: usize,
+        write: bool,
+        options: RequestOptions,
+    ) -> Result<usize> {
+        self.check_transfer(bytes, lba)?;
+        if write {
+            self.check_writable()?;
         }
-        if bytes as u64 % self.namespace.block_size() != 0 {
-            return Err(Error::InvalidBufferSize);
+        if options.metadata.is_some_and(|metadata| metadata & 0x3 != 0) {
+            return Err(Error::NotAlignedToDword);
+        }
+        if options.protection.is_some() && self.namespace.protection_type() == 0 {
+            return Err(Error::ProtectionNotSupported);
         }
 
         let prp_result = self
             .prp_manager
             .create(self.allocator.as_ref(), address, bytes)?;
+        self.push_prp(bytes, lba, prp_result, write, options)
+    }
+
+    /// Checks that a transfer of `bytes` starting at `lba` fits in a single
+    /// command and into the namespace.
+    fn check_transfer(&self, bytes: usize, lba: u64) -> Result<()> {
+        if bytes > self.max_transfer_size {
+            return Err(Error::IoSizeExceedsMdts);
+        }
+        // The transfer is sized by the block size of the namespace,
+        // which is not necessarily 512 bytes.
+        let block_size = self.namespace.block_size();
+        if bytes == 0 || !(bytes as u64).is_multiple_of(block_size) {
+            return Err(Error::InvalidBufferSize);
+        }
+        self.check_range(lba, bytes as u64 / block_size)
+    }
+
+    /// Checks that the `blocks` starting at `lba` are within the namespace.
+    fn check_range(&self, lba: u64, blocks: u64) -> Result<()> {
+        let capacity = self.namespace.block_count();
+        match lba.checked_add(blocks) {
+            Some(end) if end <= capacity => Ok(()),
+            _ => Err(Error::LbaOutOfRange {
+                lba,
+                blocks,
+                capacity,
+            }),
+        }
+    }
 
+    /// Checks that the namespace is not write protected.
+    fn check_writable(&self) -> Result<()> {
+        if self.namespace.is_read_only() {
+            return Err(Error::NamespaceReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Pushes a read or write command with a created PRP result.
+    ///
+    /// See `push_and_track` for more details.
+    fn push_prp(
+        &mut self,
+        bytes: usize,
+        lba: u64,
+        prp_result: PrpResult,
+        write: bool,
+        options: RequestOptions,
+    ) -> Result<usize> {
         let prp = prp_result.get_prp();
         let blocks = bytes as u64 / self.namespace.block_size();
 
-        let command = Command::read_write(
+        let mut command = Command::read_write(
             self.sub_queue.tail as u16,
             self.namespace.id(),
             lba,
@@ -101,13 +357,36 @@ impl<A: Allocator> IoQueuePair<A> {
             [prp.0 as u64, prp.1 as u64],
             write,
         );
+        if let Some(latency) = options.latency {
+            command = command.with_access_latency(latency as u8);
+        }
+        if let Some(metadata) = options.metadata {
+            command = command.with_metadata(self.allocator.translate(metadata));
+        }
+        if options.write != WriteOptions::default() {
+            command = command.with_write_options(options.write.fua, options.write.limited_retry);
+        }
+        if let Some(info) = options.protection {
+            command = command.with_protection(
+                info.prinfo(),
+                info.reference_tag,
+                info.app_tag,
+                info.app_tag_mask,
+            );
+        }
+
+        self.push_command(command, prp_result)
+    }
 
+    /// Pushes `command` and tracks `prp_result` until the command completes.
+    ///
+    /// The PRP result is released here if the command cannot be pushed.
+    /// Returns the new tail.
+    fn push_command(&mut self, command: Command, prp_result: PrpResult) -> Result<usize> {
         match self.sub_queue.try_push(command) {
             Ok(new_tail) => {
-                self.doorbell_helper
-                    .write(Doorbell::SubTail(*self.id), new_tail as u32);
-                self.submitted.push_back(prp_result);
-                Ok(())
+                self.submitted.push_back((command.cmd_id(), prp_result));
+                Ok(new_tail)
             }
             Err(err) => {
                 self.prp_manager
@@ -124,55 +403,1342 @@ impl<A: Allocator> IoQueuePair<A> {
     /// This function will block until every command submitted via
     /// `read` or `write` has been completed by the device. It also handles
     /// resource cleanup for the completed requests.
+    ///
+    /// Note that this only drains the queue, data may still be held in
+    /// the volatile write cache of the controller, see `flush_namespace`.
+    ///
+    /// Returns `Error::ControllerTimeout` if the device does not complete
+    /// the commands in time, in which case they are kept in flight.
     pub fn flush(&mut self) -> Result<()> {
-        let num_to_complete = self.submitted.len();
+        match self.complete_all()? {
+            Some(entry) => entry.check(),
+            None => Ok(()),
+        }
+    }
 
-        if num_to_complete == 0 {
-            return Ok(());
+    /// Blocks until all in-flight commands have completed.
+    ///
+    /// This drains the queue pair before it is deleted, so that the controller
+    /// does not complete stale commands into freed memory. Returns the status
+    /// of the completed commands like `flush`.
+    pub fn quiesce(&mut self) -> Result<()> {
+        self.flush()
+    }
+
+    /// Get the number of commands that can be submitted without
+    /// returning `Error::SubQueueFull`.
+    ///
+    /// The controller reports the entries it has consumed in the completions,
+    /// so the slots are only freed up once their commands are reaped by `flush`.
+    pub fn available_slots(&self) -> usize {
+        self.sub_queue.available()
+    }
+
+    /// Get the number of submitted commands whose completion is not reaped yet.
+    pub fn in_flight(&self) -> usize {
+        self.submitted.len()
+    }
+
+    /// Get a snapshot of the queue positions and the commands in flight.
+    ///
+    /// This is meant for debugging DMA issues, e.g. to inspect the raw
+    /// queue memory of the controller.
+    #[cfg(feature = "debug")]
+    pub fn debug_dump(&self) -> QueueDebugInfo {
+        QueueDebugInfo {
+            queue_id: *self.id,
+            sub_queue_address: self.sub_queue.address(),
+            sub_queue_size: self.sub_queue.data.count,
+            sub_queue_head: self.sub_queue.head,
+            sub_queue_tail: self.sub_queue.tail,
+            comp_queue_address: self.comp_queue.address(),
+            comp_queue_size: self.comp_queue.data.count,
+            comp_queue_head: self.comp_queue.head,
+            phase: self.comp_queue.phase,
+            outstanding: self.submitted.iter().map(|&(cmd_id, _)| cmd_id).collect(),
         }
+    }
 
-        let (tail, entry) = self.comp_queue.pop_n(num_to_complete);
-        let doorbell = Doorbell::CompHead(*self.id);
-        self.doorbell_helper.write(doorbell, tail as u32);
+    /// Checks whether there are no commands in flight.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.submitted.is_empty()
+    }
 
-        while let Some(prp_result) = self.submitted.pop_front() {
+    /// Frees the memory of the queues.
+    ///
+    /// Must only be called once the queues are deleted on the controller.
+    /// The submission queue is released to the `cmb` if it is placed there.
+    pub(crate) fn free(mut self, cmb: Option<&mut Cmb>) {
+        for prp_result in self.quarantined.drain(..) {
             self.prp_manager
                 .release(prp_result, self.allocator.as_ref());
         }
+        self.prp_manager.clear(self.allocator.as_ref());
+        self.sub_queue.free(cmb, self.allocator.as_ref());
+        self.comp_queue.free(self.allocator.as_ref());
+    }
+
+    /// Waits for all in-flight commands and releases their resources.
+    ///
+    /// Returns the first failed completion entry, otherwise the last one,
+    /// or `None` if nothing was in flight.
+    ///
+    /// Returns `Error::CompletionQueueOverrun` if a completion reports an
+    /// implausible submission queue head or an unknown command ID, in which
+    /// case the queue pair should be deleted and recreated.
+    ///
+    /// Returns `Error::ControllerTimeout` if the commands do not complete
+    /// within the I/O timeout, those which did not are still in flight then,
+    /// or `Error::ControllerFatal` if the controller reports a fatal status.
+    fn complete_all(&mut self) -> Result<Option<Completion>> {
+        let mut timeout = Timeout::from_millis(IO_TIMEOUT_MS);
+        let waiter = self.waiter.clone();
+        let mut head = None;
+        let mut result = Ok(());
+
+        while !self.submitted.is_empty() && result.is_ok() {
+            result = self
+                .comp_queue
+                .pop_with(&mut timeout, waiter.as_ref(), &|| {
+                    self.doorbell_helper.is_fatal()
+                })
+                .and_then(|(new_head, entry)| {
+                    head = Some(new_head);
+                    self.reap(entry)
+                });
+        }
 
-        let status = (entry.status >> 1) & 0xff;
-        if status != 0 {
-            return Err(Error::CommandFailed(status));
+        // The doorbell is rung once for all completions popped.
+        if let Some(head) = head {
+            let doorbell = Doorbell::CompHead(*self.id);
+            self.doorbell_helper.write(doorbell, head as u32);
+        }
+        result?;
+        Ok(self.finish())
+    }
+
+    /// Pops the completions which have arrived without waiting.
+    ///
+    /// Like `complete_all` once all in-flight commands have completed,
+    /// and `Poll::Pending` while some are still in flight.
+    #[cfg(feature = "async")]
+    pub(crate) fn try_complete_all(&mut self) -> Poll<Result<Option<Completion>>> {
+        while !self.submitted.is_empty() {
+            let Some((head, entry)) = self.comp_queue.try_pop() else {
+                return Poll::Pending;
+            };
+            let doorbell = Doorbell::CompHead(*self.id);
+            self.doorbell_helper.write(doorbell, head as u32);
+            if let Err(err) = self.reap(entry) {
+                return Poll::Ready(Err(err));
+            }
         }
-        self.sub_queue.head = entry.sq_head as usize;
 
+        Poll::Ready(Ok(self.finish()))
+    }
+
+    /// Releases the resources of the command completed by `entry`.
+    ///
+    /// The command is looked up by its ID, since commands may complete in any
+    /// order. On an implausible completion, all in-flight commands are given
+    /// up on, as the controller cannot be trusted anymore. It may still access
+    /// their PRP lists though, so these are quarantined until the queue pair
+    /// is deleted rather than released.
+    fn reap(&mut self, entry: Completion) -> Result<()> {
+        // Commands mostly complete in order, so the front usually matches.
+        let index = self
+            .submitted
+            .iter()
+            .position(|&(cmd_id, _)| cmd_id == entry.cmd_id());
+        let Some(index) = index.filter(|_| self.sub_queue.is_valid_head(entry.sq_head as usize))
+        else {
+            let in_flight = self.submitted.drain(..).map(|(_, prp_result)| prp_result);
+            self.quarantined.extend(in_flight);
+            self.last = None;
+            self.failed = None;
+            return Err(Error::CompletionQueueOverrun);
+        };
+
+        let (_, prp_result) = self.submitted.remove(index).unwrap();
+        self.prp_manager
+            .release(prp_result, self.allocator.as_ref());
+        // A fused command aborted by its partner is not the cause of the failure.
+        let failed = self.failed.as_ref();
+        if entry.check().is_err() && failed.is_none_or(|failed| failed.is_fused_abort()) {
+            self.failed = Some(entry.clone());
+        }
+        self.last = Some(entry);
         Ok(())
     }
+
+    /// Takes the completion to report once no command is in flight.
+    ///
+    /// The submission queue head is only advanced here, so that no slot is
+    /// reused (and thus no command ID) while a command is still in flight.
+    fn finish(&mut self) -> Option<Completion> {
+        let last = self.last.take()?;
+        self.sub_queue.head = last.sq_head as usize;
+        Some(self.failed.take().unwrap_or(last))
+    }
+}
+
+impl<A: Allocator> IoQueuePair<A> {
+    /// Sends a raw I/O command and waits for its completion.
+    ///
+    /// This is an escape hatch for commands that are not modeled by the driver,
+    /// e.g. vendor specific opcodes (80h to FFh) of the NVM command set, the
+    /// namespace ID of `cmd` is used as is. Any previously submitted requests
+    /// are completed first. If `data` is given, it is copied into a DMA buffer
+    /// which the command points to, and copied back once the command completes.
+    /// The PRPs of the buffer are built by the `PrpManager` of the queue pair,
+    /// and released with the buffer once the command completes.
+    ///
+    /// Returns the completion entry so that its result can be inspected.
+    pub fn io_passthru(&mut self, cmd: RawCommand, data: Option<&mut [u8]>) -> Result<Completion> {
+        self.flush()?;
+
+        let Some(data) = data.filter(|data| !data.is_empty()) else {
+            return self.exec_raw(&cmd, PrpResult::Single(0));
+        };
+        self.exec_with_buffer(data, |cmd_id, data_ptr| {
+            Command::raw(cmd_id, &cmd, data_ptr)
+        })
+    }
+
+    /// Submits a command transferring `data` and waits for its completion.
+    ///
+    /// The data is copied into a DMA buffer which the command built by
+    /// `command` from the command ID and the data pointer points to,
+    /// and copied back once the command completes.
+    pub(crate) fn exec_with_buffer(
+        &mut self,
+        data: &mut [u8],
+        command: impl FnOnce(u16, [u64; 2]) -> Command,
+    ) -> Result<Completion> {
+        if data.len() > self.max_transfer_size {
+            return Err(Error::IoSizeExceedsMdts);
+        }
+
+        let page_size = self.prp_manager.page_size();
+        let mut buffer =
+            Dma::<u8>::allocate_aligned(data.len(), page_size, self.allocator.as_ref());
+        buffer.copy_from_slice(data);
+
+        let result = self
+            .prp_manager
+            .create(self.allocator.as_ref(), buffer.addr as usize, data.len())
+            .and_then(|prp_result| {
+                let prp = prp_result.get_prp();
+                let command = command(self.sub_queue.tail as u16, [prp.0 as u64, prp.1 as u64]);
+                self.exec_command(command, prp_result)
+            });
+
+        data.copy_from_slice(&buffer);
+        buffer.deallocate(self.allocator.as_ref());
+        result
+    }
+
+    /// Submits a command without data, built by `command` from
+    /// the command ID, and waits for its completion.
+    pub(crate) fn exec_without_data(
+        &mut self,
+        command: impl FnOnce(u16) -> Command,
+    ) -> Result<Completion> {
+        let command = command(self.sub_queue.tail as u16);
+        self.exec_command(command, PrpResult::Single(0))
+    }
+
+    /// Submits a raw command using `prp_result` and waits for its completion.
+    fn exec_raw(&mut self, cmd: &RawCommand, prp_result: PrpResult) -> Result<Completion> {
+        let prp = prp_result.get_prp();
+        let command = Command::raw(
+            self.sub_queue.tail as u16,
+            cmd,
+            [prp.0 as u64, prp.1 as u64],
+        );
+        self.exec_command(command, prp_result)
+    }
+
+    /// Submits `command` using `prp_result` and waits for its completion.
+    fn exec_command(&mut self, command: Command, prp_result: PrpResult) -> Result<Completion> {
+        let new_tail = self.push_command(command, prp_result)?;
+        self.doorbell_helper
+            .write(Doorbell::SubTail(*self.id), new_tail as u32);
+
+        let entry = self
+            .complete_all()?
+            .expect("the command was just submitted");
+        entry.check()?;
+        Ok(entry)
+    }
 }
 
 impl<A: Allocator> IoQueuePair<A> {
+    /// Sets how the queue pair waits for completions.
+    ///
+    /// Completions are polled by default, see `CompletionWaiter`
+    /// for waiting on interrupts instead.
+    pub fn set_completion_waiter(&mut self, waiter: Arc<dyn CompletionWaiter>) {
+        self.waiter = waiter;
+    }
+
+    /// Returns the namespace of the queue pair.
+    pub fn namespace(&self) -> &Namespace {
+        &self.namespace
+    }
+
     /// Returns the queue pair ID.
     ///
-    /// This ID is globally unique as it is a static counter.
+    /// This ID is unique among the queue pairs of the device,
+    /// but it is reused once the queue pair is deleted.
     pub fn id(&self) -> IoQueueId {
         self.id
     }
 
+    /// Commits the volatile write cache of the namespace to non-volatile media.
+    ///
+    /// Any previously submitted requests are completed first, so all of
+    /// their data is persistent once this returns.
+    pub fn flush_namespace(&mut self) -> Result<()> {
+        self.flush()?;
+        let command = Command::flush(self.sub_queue.tail as u16, self.namespace.id());
+        self.exec_command(command, PrpResult::Single(0))?;
+        Ok(())
+    }
+
+    /// Compares `bytes` from `src` with the data starting at `lba`.
+    ///
+    /// The controller reads the blocks and compares them itself, so no
+    /// buffer is needed to read the data back. Any previously submitted
+    /// requests are completed first.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::CompareMismatch` if the data does not match, and
+    /// `Error::FeatureNotSupported` if the controller does not support Compare.
+    pub fn compare(&mut self, src: *const u8, bytes: usize, lba: u64) -> Result<()> {
+        self.check_nvm_command(ONCS_COMPARE)?;
+        self.check_transfer(bytes, lba)?;
+        self.flush()?;
+
+        let prp_result = self
+            .prp_manager
+            .create(self.allocator.as_ref(), src as usize, bytes)?;
+
+        let prp = prp_result.get_prp();
+        let blocks = bytes as u64 / self.namespace.block_size();
+
+        let command = Command::compare(
+            self.sub_queue.tail as u16,
+            self.namespace.id(),
+            lba,
+            blocks as u16 - 1,
+            [prp.0 as u64, prp.1 as u64],
+        );
+        self.exec_command(command, prp_result)?;
+        Ok(())
+    }
+
+    /// Writes `new` to the blocks starting at `lba` if they contain `expected`.
+    ///
+    /// The Compare and the Write are submitted as a fused operation, so no
+    /// other command can access the blocks in between. The buffers are copied
+    /// into DMA buffers and must have the same length, which is a multiple of
+    /// the block size. Any previously submitted requests are completed first.
+    ///
+    /// Returns `false` if the data did not match, in which case nothing is written.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::FeatureNotSupported` if the controller does not
+    /// support the fused Compare and Write operation.
+    pub fn compare_and_write(&mut self, expected: &[u8], new: &[u8], lba: u64) -> Result<bool> {
+        if !self.compare_and_write {
+            return Err(Error::FeatureNotSupported);
+        }
+        if expected.len() != new.len() {
+            return Err(Error::InvalidBufferSize);
+        }
+        self.check_transfer(new.len(), lba)?;
+        self.check_writable()?;
+        self.flush()?;
+        if self.available_slots() < 2 {
+            return Err(Error::SubQueueFull);
+        }
+
+        let page_size = self.prp_manager.page_size();
+        let mut buffers = [expected, new].map(|data| {
+            let mut buffer =
+                Dma::<u8>::allocate_aligned(data.len(), page_size, self.allocator.as_ref());
+            buffer.copy_from_slice(data);
+            buffer
+        });
+        let result = self.submit_compare_and_write(&mut buffers, new.len(), lba);
+        for buffer in &buffers {
+            buffer.deallocate(self.allocator.as_ref());
+        }
+
+        match result?.map_or(Ok(()), |entry| entry.check()) {
+            Ok(()) => Ok(true),
+            Err(Error::CompareMismatch) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Submits the fused Compare and Write of `compare_and_write` and waits for them.
+    fn submit_compare_and_write(
+        &mut self,
+        buffers: &mut [Dma<u8>; 2],
+        bytes: usize,
+        lba: u64,
+    ) -> Result<Option<Completion>> {
+        let tail = self.sub_queue.tail;
+        let tracked = self.submitted.len();
+
+        if let Err(err) = self.push_compare_and_write(buffers, bytes, lba) {
+            // A lone first command of the fused pair must not reach the
+            // controller, and the doorbell is not rung yet, so take it back.
+            self.sub_queue.tail = tail;
+            for (_, prp_result) in self.submitted.drain(tracked..) {
+                self.prp_manager
+                    .release(prp_result, self.allocator.as_ref());
+            }
+            return Err(err);
+        }
+
+        self.doorbell_helper
+            .write(Doorbell::SubTail(*self.id), self.sub_queue.tail as u32);
+        self.complete_all()
+    }
+
+    /// Pushes the fused Compare and Write without ringing the doorbell.
+    fn push_compare_and_write(
+        &mut self,
+        buffers: &[Dma<u8>; 2],
+        bytes: usize,
+        lba: u64,
+    ) -> Result<()> {
+        let blocks = bytes as u64 / self.namespace.block_size();
+        for (index, buffer) in buffers.iter().enumerate() {
+            let prp_result =
+                self.prp_manager
+                    .create(self.allocator.as_ref(), buffer.addr as usize, bytes)?;
+            let prp = prp_result.get_prp();
+            let data_ptr = [prp.0 as u64, prp.1 as u64];
+            let (cmd_id, ns_id) = (self.sub_queue.tail as u16, self.namespace.id());
+            let command = match index {
+                0 => Command::compare(cmd_id, ns_id, lba, blocks as u16 - 1, data_ptr),
+                _ => Command::read_write(cmd_id, ns_id, lba, blocks as u16 - 1, data_ptr, true),
+            };
+            self.push_command(command.with_fuse(index as u8 + 1), prp_result)?;
+        }
+        Ok(())
+    }
+
+    /// Sets `blocks` logical blocks starting at `lba` to zero.
+    ///
+    /// No data is transferred, so this is much cheaper than writing a zeroed
+    /// buffer. Any previously submitted requests are completed first.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::InvalidBufferSize` if `blocks` is zero or exceeds
+    /// 65536 blocks of a single command, and `Error::LbaOutOfRange` if the
+    /// range does not fit into the namespace. Returns `Error::FeatureNotSupported`
+    /// if the controller does not support Write Zeroes.
+    pub fn write_zeroes(&mut self, lba: u64, blocks: u32) -> Result<()> {
+        self.check_nvm_command(ONCS_WRITE_ZEROES)?;
+        self.check_writable()?;
+        if blocks == 0 || blocks > MAX_WRITE_ZEROES_BLOCKS {
+            return Err(Error::InvalidBufferSize);
+        }
+        self.check_range(lba, blocks as u64)?;
+        self.flush()?;
+
+        let command = Command::write_zeroes(
+            self.sub_queue.tail as u16,
+            self.namespace.id(),
+            lba,
+            (blocks - 1) as u16,
+            false,
+        );
+        self.exec_command(command, PrpResult::Single(0))?;
+        Ok(())
+    }
+
+    /// Tells the controller that the data of `ranges` is no longer needed.
+    ///
+    /// The controller may deallocate the blocks (also known as TRIM),
+    /// after which reading them returns unspecified data. Any previously
+    /// submitted requests are completed first.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::InvalidBufferSize` if `ranges` is empty
+    /// or has more than 256 entries, `Error::LbaOutOfRange` if a range does
+    /// not fit into the namespace, and `Error::FeatureNotSupported`
+    /// if the controller does not support Dataset Management.
+    pub fn deallocate(&mut self, ranges: &[LbaRange]) -> Result<()> {
+        self.check_nvm_command(ONCS_DATASET_MANAGEMENT)?;
+        if ranges.is_empty() || ranges.len() > MAX_DSM_RANGES {
+            return Err(Error::InvalidBufferSize);
+        }
+        for range in ranges {
+            self.check_range(range.starting_lba, range.length as u64)?;
+        }
+        self.flush()?;
+
+        // Each range is a 16 byte context descriptor: attributes, length and LBA.
+        let page_size = self.prp_manager.page_size();
+        let mut buffer =
+            Dma::<u8>::allocate_aligned(ranges.len() * 16, page_size, self.allocator.as_ref());
+        for (chunk, range) in buffer.chunks_exact_mut(16).zip(ranges) {
+            chunk[..4].fill(0);
+            chunk[4..8].copy_from_slice(&range.length.to_le_bytes());
+            chunk[8..].copy_from_slice(&range.starting_lba.to_le_bytes());
+        }
+
+        let result = self
+            .prp_manager
+            .create(self.allocator.as_ref(), buffer.addr as usize, buffer.len())
+            .and_then(|prp_result| {
+                let prp = prp_result.get_prp();
+                let command = Command::dataset_management(
+                    self.sub_queue.tail as u16,
+                    self.namespace.id(),
+                    [prp.0 as u64, prp.1 as u64],
+                    ranges.len() as u16,
+                    DSM_ATTRIBUTE_DEALLOCATE,
+                );
+                self.exec_command(command, prp_result)
+            });
+
+        buffer.deallocate(self.allocator.as_ref());
+        result.map(|_| ())
+    }
+
     /// Submits a read request to the queue without blocking.
     ///
     /// This function adds a read command to the submission queue and returns immediately.
     /// The actual I/O operation happens in the background.
     /// Call `flush()` to wait for all submitted requests to complete.
     ///
-    /// Returns an error if the submission queue is full.
+    /// Returns an error if the submission queue is full, and
+    /// `Error::LbaOutOfRange` if the blocks exceed the namespace.
+    ///
+    /// A failed submission leaves no trace: the failed request is not sent
+    /// to the device and its resources are released immediately. Requests
+    /// submitted earlier in the same batch are still in flight and tracked,
+    /// so on `Error::SubQueueFull` call `flush()` to complete them and free
+    /// up the queue before submitting the remaining requests.
     pub fn read(&mut self, dest: *mut u8, bytes: usize, lba: u64) -> Result<()> {
-        self.submit_and_track(bytes, lba, dest as usize, false)
+        self.submit_and_track(bytes, lba, dest as usize, false, Default::default())
     }
 
     /// Submits a write request to the queue without blocking.
     ///
     /// See `read` for more details.
     pub fn write(&mut self, src: *const u8, bytes: usize, lba: u64) -> Result<()> {
-        self.submit_and_track(bytes, lba, src as usize, true)
+        self.submit_and_track(bytes, lba, src as usize, true, Default::default())
+    }
+
+    /// Checks that the controller supports the optional NVM command `oncs_bit`.
+    fn check_nvm_command(&self, oncs_bit: u16) -> Result<()> {
+        if self.optional_nvm_commands & oncs_bit == 0 {
+            return Err(Error::FeatureNotSupported);
+        }
+        Ok(())
+    }
+
+    /// Reads the blocks starting from `lba` into scattered buffers.
+    ///
+    /// Each segment is an `(address, length)` pair of a physically contiguous
+    /// buffer, which is described by an SGL, so unlike `read` the buffers need
+    /// no page alignment. Their total length must be a multiple of the block
+    /// size. Any previously submitted requests are completed first, and this
+    /// blocks until the data has arrived.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::SglNotSupported` if the controller does not support SGLs.
+    pub fn read_sgl(&mut self, segments: &[(usize, usize)], lba: u64) -> Result<()> {
+        self.exec_sgl(segments, lba, false)
+    }
+
+    /// Writes scattered buffers to the blocks starting from `lba`.
+    ///
+    /// See `read_sgl` for more details.
+    pub fn write_sgl(&mut self, segments: &[(usize, usize)], lba: u64) -> Result<()> {
+        self.exec_sgl(segments, lba, true)
+    }
+
+    /// Submits a read or write command using an SGL and waits for it.
+    fn exec_sgl(&mut self, segments: &[(usize, usize)], lba: u64, write: bool) -> Result<()> {
+        let Some(dword_aligned) = self.sgl_support else {
+            return Err(Error::SglNotSupported);
+        };
+        let bytes = segments.iter().map(|&(_, length)| length).sum::<usize>();
+        self.check_transfer(bytes, lba)?;
+        if write {
+            self.check_writable()?;
+        }
+        self.flush()?;
+
+        let sgl = Sgl::create(self.allocator.as_ref(), segments, dword_aligned)?;
+        let blocks = bytes as u64 / self.namespace.block_size();
+        let command = Command::read_write(
+            self.sub_queue.tail as u16,
+            self.namespace.id(),
+            lba,
+            blocks as u16 - 1,
+            sgl.data_ptr(),
+            write,
+        )
+        .with_sgl();
+
+        let result = self.exec_command(command, PrpResult::Single(0));
+        sgl.release(self.allocator.as_ref());
+        result.map(|_| ())
+    }
+
+    /// Reads the blocks starting from `lba` into several buffers in order.
+    ///
+    /// The buffers are filled by a single command if all boundaries between
+    /// them are page aligned, i.e. each buffer but the first starts on a page
+    /// and each buffer but the last ends on one. Otherwise, every buffer is
+    /// read by its own command, which is slower and requires each of them to
+    /// be a multiple of the block size. See `read` for the requirements of
+    /// the buffers. Any previously submitted requests are completed first,
+    /// and this blocks until the data has arrived.
+    pub fn read_vectored(&mut self, bufs: &mut [&mut [u8]], lba: u64) -> Result<()> {
+        let segments = bufs
+            .iter_mut()
+            .map(|buf| (buf.as_mut_ptr() as usize, buf.len()))
+            .collect::<Vec<_>>();
+        self.exec_vectored(&segments, lba, false)
+    }
+
+    /// Writes several buffers in order to the blocks starting from `lba`.
+    ///
+    /// See `read_vectored` for more details.
+    pub fn write_vectored(&mut self, bufs: &[&[u8]], lba: u64) -> Result<()> {
+        let segments = bufs
+            .iter()
+            .map(|buf| (buf.as_ptr() as usize, buf.len()))
+            .collect::<Vec<_>>();
+        self.exec_vectored(&segments, lba, true)
+    }
+
+    /// Transfers the buffers with a single command if possible and waits for it.
+    fn exec_vectored(&mut self, segments: &[(usize, usize)], lba: u64, write: bool) -> Result<()> {
+        let segments = segments
+            .iter()
+            .copied()
+            .filter(|&(_, bytes)| bytes != 0)
+            .collect::<Vec<_>>();
+        if segments.is_empty() {
+            return Ok(());
+        }
+        self.flush()?;
+
+        let bytes = segments.iter().map(|&(_, bytes)| bytes).sum::<usize>();
+        self.check_transfer(bytes, lba)?;
+        if write {
+            self.check_writable()?;
+        }
+        match self
+            .prp_manager
+            .create_vectored(self.allocator.as_ref(), &segments)
+        {
+            Ok(prp_result) => {
+                let new_tail = self.push_prp(bytes, lba, prp_result, write, Default::default())?;
+                self.doorbell_helper
+                    .write(Doorbell::SubTail(*self.id), new_tail as u32);
+            }
+            Err(Error::NotAlignedToPage) => {
+                let block_size = self.namespace.block_size();
+                let mut lba = lba;
+                for (address, bytes) in segments {
+                    let submit = |qpair: &mut Self| {
+                        qpair.submit_and_track(bytes, lba, address, write, Default::default())
+                    };
+                    match submit(self) {
+                        Err(Error::SubQueueFull) => {
+                            self.flush()?;
+                            submit(self)?;
+                        }
+                        result => result?,
+                    }
+                    lba += bytes as u64 / block_size;
+                }
+            }
+            Err(err) => return Err(err),
+        }
+        self.flush()
+    }
+
+    /// Submits a batch of requests to the queue without blocking.
+    ///
+    /// All requests are pushed to the submission queue first and the doorbell
+    /// is rung only once, which is cheaper than submitting them one by one.
+    /// Call `flush()` to wait for them to complete, see `read` for the
+    /// requirements of each buffer.
+    ///
+    /// The batch is submitted as a whole or not at all: if any request fails,
+    /// e.g. with `Error::SubQueueFull` mid-batch, none of them is sent to the
+    /// device. Requests submitted earlier are not affected.
+    pub fn submit_batch(&mut self, ops: &[IoOp]) -> Result<()> {
+        let tail = self.sub_queue.tail;
+        let tracked = self.submitted.len();
+
+        for op in ops {
+            let write = op.kind == IoOpKind::Write;
+            if let Err(err) =
+                self.push_and_track(op.bytes, op.lba, op.buf_addr, write, Default::default())
+            {
+                // The doorbell is not rung yet, so the batch can be taken back.
+                self.sub_queue.tail = tail;
+                for (_, prp_result) in self.submitted.drain(tracked..) {
+                    self.prp_manager
+                        .release(prp_result, self.allocator.as_ref());
+                }
+                return Err(err);
+            }
+        }
+
+        if !ops.is_empty() {
+            self.doorbell_helper
+                .write(Doorbell::SubTail(*self.id), self.sub_queue.tail as u32);
+        }
+        Ok(())
+    }
+
+    /// Submits a read request with an access latency hint.
+    ///
+    /// See `AccessLatency` and `read` for more details.
+    pub fn read_with_latency(
+        &mut self,
+        dest: *mut u8,
+        bytes: usize,
+        lba: u64,
+        latency: AccessLatency,
+    ) -> Result<()> {
+        let options = RequestOptions {
+            latency: Some(latency),
+            ..Default::default()
+        };
+        self.submit_and_track(bytes, lba, dest as usize, false, options)
+    }
+
+    /// Submits a write request with an access latency hint.
+    ///
+    /// See `AccessLatency` and `read` for more details.
+    pub fn write_with_latency(
+        &mut self,
+        src: *const u8,
+        bytes: usize,
+        lba: u64,
+        latency: AccessLatency,
+    ) -> Result<()> {
+        let options = RequestOptions {
+            latency: Some(latency),
+            ..Default::default()
+        };
+        self.submit_and_track(bytes, lba, src as usize, true, options)
+    }
+
+    /// Submits a read request with a separate metadata buffer.
+    ///
+    /// The metadata of the blocks is read into `metadata`, which must be
+    /// dword aligned, physically contiguous and hold `metadata_size` bytes
+    /// for each block. See `read` for more details.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::MetadataNotSupported` if the namespace is formatted
+    /// without metadata, or with metadata at the end of each block.
+    pub fn read_with_metadata(
+        &mut self,
+        dest: *mut u8,
+        bytes: usize,
+        lba: u64,
+        metadata: *mut u8,
+    ) -> Result<()> {
+        self.check_metadata()?;
+        let options = RequestOptions {
+            metadata: Some(metadata as usize),
+            ..Default::default()
+        };
+        self.submit_and_track(bytes, lba, dest as usize, false, options)
+    }
+
+    /// Submits a write request with a separate metadata buffer.
+    ///
+    /// See `read_with_metadata` for more details.
+    pub fn write_with_metadata(
+        &mut self,
+        src: *const u8,
+        bytes: usize,
+        lba: u64,
+        metadata: *const u8,
+    ) -> Result<()> {
+        self.check_metadata()?;
+        let options = RequestOptions {
+            metadata: Some(metadata as usize),
+            ..Default::default()
+        };
+        self.submit_and_track(bytes, lba, src as usize, true, options)
+    }
+
+    /// Checks that the namespace has a separate metadata buffer.
+    fn check_metadata(&self) -> Result<()> {
+        if self.namespace.metadata_size() == 0 || self.namespace.extended_metadata() {
+            return Err(Error::MetadataNotSupported);
+        }
+        Ok(())
+    }
+
+    /// Submits a read request with end-to-end protection information.
+    ///
+    /// The protection information of the blocks is checked (and stripped)
+    /// by the controller as configured by `protection`. See `read` for more
+    /// details.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::ProtectionNotSupported` if the namespace is
+    /// not formatted with protection information.
+    pub fn read_with_protection(
+        &mut self,
+        dest: *mut u8,
+        bytes: usize,
+        lba: u64,
+        protection: ProtectionInfo,
+    ) -> Result<()> {
+        let options = RequestOptions {
+            protection: Some(protection),
+            ..Default::default()
+        };
+        self.submit_and_track(bytes, lba, dest as usize, false, options)
+    }
+
+    /// Submits a write request with end-to-end protection information.
+    ///
+    /// See `read_with_protection` for more details.
+    pub fn write_with_protection(
+        &mut self,
+        src: *const u8,
+        bytes: usize,
+        lba: u64,
+        protection: ProtectionInfo,
+    ) -> Result<()> {
+        let options = RequestOptions {
+            protection: Some(protection),
+            ..Default::default()
+        };
+        self.submit_and_track(bytes, lba, src as usize, true, options)
+    }
+
+    /// Submits a write request that is guaranteed to be atomic.
+    ///
+    /// The write will not be torn even on power failure, because it is
+    /// rejected with `Error::NotAtomic` if it exceeds the atomic write
+    /// unit or crosses an atomic boundary of the namespace.
+    ///
+    /// See `read` for more details.
+    pub fn write_atomic(&mut self, src: *const u8, bytes: usize, lba: u64) -> Result<()> {
+        let blocks = (bytes as u64).div_ceil(self.namespace.block_size());
+        if blocks > self.namespace.atomic_write_blocks() as u64 {
+            return Err(Error::NotAtomic);
+        }
+        if let Some((size, offset)) = self.namespace.atomic_boundary()
+            && blocks != 0
+        {
+            // Boundaries are placed at `offset + n * size` blocks.
+            let shift = size - offset % size;
+            if (lba + shift) / size != (lba + blocks - 1 + shift) / size {
+                return Err(Error::NotAtomic);
+            }
+        }
+        self.write(src, bytes, lba)
+    }
+
+    /// Reads `buf.len()` bytes starting from `lba` into `buf`.
+    ///
+    /// This is the preferred way to read blocks: it blocks until the data
+    /// has arrived, so `buf` cannot be freed while the device still writes
+    /// into it. Buffers larger than the maximum transfer size are split into
+    /// multiple commands, which are submitted together.
+    ///
+    /// This is thus the entry point for transfers of any size, there is no
+    /// separate `read_large`: each command covers at most the maximum transfer
+    /// size, and the LBA and buffer advance by as much from one to the next.
+    /// `read` rejects such buffers with `Error::IoSizeExceedsMdts` instead.
+    ///
+    /// The raw pointer form `read` is left for callers that manage buffer
+    /// lifetimes themselves to keep several requests in flight.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::InvalidBufferSize` if the length of `buf`
+    /// is not a multiple of the block size.
+    ///
+    /// If one of the commands fails, the remaining chunks are not submitted
+    /// anymore, but those already in flight are still waited for, and the
+    /// first error is returned. The chunks are not undone, so part of the
+    /// range may have been transferred while the rest was not, and the
+    /// contents of the range are unspecified. As reads and writes of the
+    /// same data can be repeated, the whole request should be retried.
+    pub fn read_blocks(&mut self, buf: &mut [u8], lba: u64) -> Result<()> {
+        self.submit_blocks(buf.as_mut_ptr() as usize, buf.len(), lba, None)
+    }
+
+    /// Writes `buf` to the blocks starting from `lba`.
+    ///
+    /// Buffers larger than the maximum transfer size are split in the same
+    /// way, there is no separate `write_large`. See `read_blocks` for more
+    /// details, including which blocks are written if one of the commands fails.
+    pub fn write_blocks(&mut self, buf: &[u8], lba: u64) -> Result<()> {
+        self.write_blocks_with(buf, lba, WriteOptions::default())
+    }
+
+    /// Writes `buf` to the blocks starting from `lba` with `opts`.
+    ///
+    /// E.g. with `WriteOptions::fua` the data is persistent once this returns,
+    /// without flushing the whole write cache with `flush_namespace`.
+    /// See `read_blocks` for more details.
+    pub fn write_blocks_with(&mut self, buf: &[u8], lba: u64, opts: WriteOptions) -> Result<()> {
+        self.submit_blocks(buf.as_ptr() as usize, buf.len(), lba, Some(opts))
+    }
+
+    /// Submits a buffer in chunks of the maximum transfer size and waits.
+    ///
+    /// It is a write if `write` is given, and a read otherwise. All chunks
+    /// are completed before returning, even on error, so that the buffer
+    /// is no longer used by the device.
+    fn submit_blocks(
+        &mut self,
+        address: usize,
+        bytes: usize,
+        lba: u64,
+        write: Option<WriteOptions>,
+    ) -> Result<()> {
+        let block_size = self.namespace.block_size();
+        if !(bytes as u64).is_multiple_of(block_size) {
+            return Err(Error::InvalidBufferSize);
+        }
+        self.flush()?;
+
+        let mut result = Ok(());
+        for offset in (0..bytes).step_by(self.max_transfer_size) {
+            let len = (bytes - offset).min(self.max_transfer_size);
+            let lba = lba + offset as u64 / block_size;
+            result = loop {
+                let options = RequestOptions {
+                    write: write.unwrap_or_default(),
+                    ..Default::default()
+                };
+                match self.submit_and_track(len, lba, address + offset, write.is_some(), options) {
+                    Err(Error::SubQueueFull) => self.flush()?,
+                    result => break result,
+                }
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+
+        let flushed = self.flush();
+        result.and(flushed)
+    }
+
+    /// Reads into `buf` starting from `lba` and waits for the completion.
+    ///
+    /// Any previously submitted requests are completed first, so the
+    /// returned status is exactly the one of this read. The length of
+    /// `buf` follows the same rules as `read`.
+    ///
+    /// If the namespace is not ready yet (e.g. right after power up or while
+    /// being formatted), the read is retried with backoff until it becomes
+    /// ready or the timeout expires with `Error::NamespaceNotReady`.
+    ///
+    /// This is a convenience for simple one-shot reads,
+    /// use `read` and `flush` for better throughput.
+    pub fn read_sync(&mut self, buf: &mut [u8], lba: u64) -> Result<()> {
+        self.flush()?;
+        self.retry_not_ready(|qpair| {
+            qpair.read(buf.as_mut_ptr(), buf.len(), lba)?;
+            qpair.flush()
+        })
+    }
+
+    /// Writes `buf` starting from `lba` and waits for the completion.
+    ///
+    /// See `read_sync` for more details.
+    pub fn write_sync(&mut self, buf: &[u8], lba: u64) -> Result<()> {
+        self.flush()?;
+        self.retry_not_ready(|qpair| {
+            qpair.write(buf.as_ptr(), buf.len(), lba)?;
+            qpair.flush()
+        })
+    }
+
+    /// Reads into `buf` starting from `lba`, retrying transient failures.
+    ///
+    /// A read failing with `Error::CommandFailedRetryable`, whose status has
+    /// the Do Not Retry bit cleared, is retried up to `max_retries` times.
+    /// Any other error is returned at once. See `read_blocks` for more details.
+    pub fn read_with_retries(
+        &mut self,
+        buf: &mut [u8],
+        lba: u64,
+        max_retries: usize,
+    ) -> Result<()> {
+        let mut retries = 0;
+        loop {
+            match self.read_blocks(buf, lba) {
+                Err(Error::CommandFailedRetryable(_)) if retries < max_retries => retries += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs `op` until it no longer fails with `Error::NamespaceNotReady`.
+    ///
+    /// The delay between two attempts doubles each time, and it gives up
+    /// once the namespace ready timeout expires.
+    fn retry_not_ready(&mut self, mut op: impl FnMut(&mut Self) -> Result<()>) -> Result<()> {
+        let mut timeout = Timeout::from_millis(NAMESPACE_READY_TIMEOUT_MS);
+        let mut delay = 1;
+        loop {
+            match op(self) {
+                Err(Error::NamespaceNotReady) => {
+                    timeout.wait(delay).map_err(|_| Error::NamespaceNotReady)?;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<A: Allocator> IoQueuePair<A> {
+    /// Reads `buf.len()` bytes starting at an arbitrary `byte_offset`.
+    ///
+    /// The blocks covering the requested range are read into an internal
+    /// DMA buffer and the requested bytes are copied out, so neither the
+    /// offset nor the length has to be aligned to the block size, and `buf`
+    /// does not need to be DMA capable. Any previously submitted requests
+    /// are completed first.
+    ///
+    /// Unaligned access costs extra I/O: up to one additional block is read
+    /// at each edge, and requests larger than the maximum transfer size are
+    /// split into multiple commands.
+    pub fn read_at(&mut self, byte_offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.flush()?;
+        self.with_bounce_chunks(byte_offset, buf.len(), |qpair, bounce, chunk| {
+            qpair.read(bounce.addr, chunk.bytes, chunk.lba)?;
+            qpair.flush()?;
+            let data = &bounce[chunk.skip..chunk.skip + chunk.copy];
+            buf[chunk.done..chunk.done + chunk.copy].copy_from_slice(data);
+            Ok(())
+        })
+    }
+
+    /// Writes `buf` starting at an arbitrary `byte_offset`.
+    ///
+    /// Partial blocks at the edges are handled by read-modify-write: the edge
+    /// blocks are read first, merged with `buf` and written back together with
+    /// the fully covered blocks. See `read_at` for more details.
+    ///
+    /// Note that the read-modify-write is not atomic, concurrent writes to
+    /// the same edge blocks may be lost.
+    pub fn write_at(&mut self, byte_offset: u64, buf: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        self.flush()?;
+        let block_size = self.namespace.block_size() as usize;
+        self.with_bounce_chunks(byte_offset, buf.len(), |qpair, bounce, chunk| {
+            let last_block = chunk.bytes - block_size;
+            if chunk.skip != 0 {
+                qpair.read(bounce.addr, block_size, chunk.lba)?;
+            }
+            if (chunk.skip + chunk.copy) % block_size != 0 && (chunk.skip == 0 || last_block != 0) {
+                let lba = chunk.lba + (last_block / block_size) as u64;
+                qpair.read(bounce[last_block..].as_mut_ptr(), block_size, lba)?;
+            }
+            qpair.flush()?;
+
+            let data = &buf[chunk.done..chunk.done + chunk.copy];
+            bounce[chunk.skip..chunk.skip + chunk.copy].copy_from_slice(data);
+            qpair.write(bounce.addr, chunk.bytes, chunk.lba)?;
+            qpair.flush()
+        })
+    }
+
+    /// Splits a byte range into block aligned chunks and runs `op` on each.
+    ///
+    /// A single DMA buffer large enough for one chunk is allocated for all
+    /// chunks and released afterwards, even if `op` fails.
+    fn with_bounce_chunks(
+        &mut self,
+        byte_offset: u64,
+        len: usize,
+        mut op: impl FnMut(&mut Self, &mut Dma<u8>, &ByteChunk) -> Result<()>,
+    ) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        let block_size = self.namespace.block_size();
+        let chunk_size = self.max_transfer_size / block_size as usize * block_size as usize;
+        let first_skip = (byte_offset % block_size) as usize;
+        let needed = (first_skip + len).div_ceil(block_size as usize) * block_size as usize;
+
+        let page_size = self.prp_manager.page_size();
+        let mut bounce =
+            Dma::<u8>::allocate_aligned(needed.min(chunk_size), page_size, self.allocator.as_ref());
+        let mut done = 0;
+        let mut result = Ok(());
+
+        while done < len && result.is_ok() {
+            let position = byte_offset + done as u64;
+            let skip = (position % block_size) as usize;
+            let copy = (len - done).min(chunk_size - skip);
+            let chunk = ByteChunk {
+                lba: position / block_size,
+                bytes: (skip + copy).div_ceil(block_size as usize) * block_size as usize,
+                skip,
+                copy,
+                done,
+            };
+            result = op(self, &mut bounce, &chunk);
+            done += copy;
+        }
+
+        bounce.deallocate(self.allocator.as_ref());
+        result
+    }
+}
+
+/// A block aligned piece of a byte range transfer.
+struct ByteChunk {
+    /// The first block of the chunk
+    lba: u64,
+    /// The size of the chunk (in whole blocks)
+    bytes: usize,
+    /// The offset of the requested bytes in the first block
+    skip: usize,
+    /// The number of requested bytes in the chunk
+    copy: usize,
+    /// The number of bytes transferred before this chunk
+    done: usize,
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use std::vec;
+
+    use super::*;
+    use crate::device::Device;
+    use crate::memory::IdentityAllocator;
+    use crate::mock::{MockController, MockMemory};
+
+    type MockAllocator = IdentityAllocator<MockMemory>;
+
+    /// Brings up the mock controller with a queue pair of 64 entries.
+    fn queue_pair(mock: &MockController) -> (Device<MockAllocator>, IoQueuePair<MockAllocator>) {
+        let mut device = mock.device();
+        let namespace = device.identify_namespaces(0).unwrap().remove(0);
+        let qpair = device.create_io_queue_pair(namespace, 64, 0, None).unwrap();
+        (device, qpair)
+    }
+
+    #[test]
+    fn range_up_to_capacity() {
+        let mock = MockController::new(1024, 512);
+        let (_device, mut qpair) = queue_pair(&mock);
+        let memory = IdentityAllocator(MockMemory::default());
+        let mut data = Dma::<u8>::allocate_zeroed(1024, &memory);
+
+        // The last two blocks end exactly at the capacity.
+        qpair.write_blocks(&data, 1022).unwrap();
+        qpair.read_blocks(&mut data, 1022).unwrap();
+        qpair.write_zeroes(1023, 1).unwrap();
+    }
+
+    #[test]
+    fn range_past_capacity() {
+        let mock = MockController::new(1024, 512);
+        let (_device, mut qpair) = queue_pair(&mock);
+        let memory = IdentityAllocator(MockMemory::default());
+        let mut data = Dma::<u8>::allocate_zeroed(1024, &memory);
+
+        // One block past the capacity.
+        let out_of_range = |result| {
+            matches!(
+                result,
+                Err(Error::LbaOutOfRange {
+                    lba: 1023,
+                    blocks: 2,
+                    capacity: 1024
+                })
+            )
+        };
+        assert!(out_of_range(qpair.write_blocks(&data, 1023)));
+        assert!(out_of_range(qpair.read_blocks(&mut data, 1023)));
+        assert!(out_of_range(qpair.write_zeroes(1023, 2)));
+        assert_eq!(qpair.in_flight(), 0);
+    }
+
+    #[test]
+    fn transfer_sized_by_block_size() {
+        let mock = MockController::new(64, 4096);
+        let (_device, mut qpair) = queue_pair(&mock);
+        let memory = IdentityAllocator(MockMemory::default());
+        let mut data = Dma::<u8>::allocate_zeroed(3 * 4096, &memory);
+        data.fill(0xA5);
+
+        qpair.write(data.addr, data.len(), 1).unwrap();
+        // The PRPs cover all 3 blocks of 4096 bytes: the first page,
+        // and a list of the other two.
+        let Some((_, PrpResult::List(prp1, lists))) = qpair.submitted.back() else {
+            panic!("the transfer is not described by a PRP list");
+        };
+        assert_eq!(*prp1, data.phys_addr);
+        let pages = [data.phys_addr + 4096, data.phys_addr + 8192].map(|page| page as u64);
+        assert_eq!(lists[0][..3], [pages[0], pages[1], 0]);
+
+        qpair.flush().unwrap();
+        let disk = mock.disk();
+        assert_eq!(disk[4096..4 * 4096], data[..]);
+        assert!(
+            disk[..4096]
+                .iter()
+                .chain(&disk[4 * 4096..])
+                .all(|&byte| byte == 0)
+        );
+    }
+
+    #[test]
+    fn bounce_buffer_of_8k_pages() {
+        let mock = MockController::new(1024, 512).with_min_page_size(8192);
+        let (device, mut qpair) = queue_pair(&mock);
+        assert_eq!(device.controller_data().min_pagesize, 8192);
+        let pattern: Vec<u8> = (0..48 * 512).map(|index| index as u8 ^ 0x5A).collect();
+        mock.write_disk(0, &pattern);
+
+        // 48 blocks span 3 pages of 8 KiB, so the bounce buffer has to
+        // start on a page to be described by a PRP list.
+        let mut buf = vec![0u8; pattern.len()];
+        let cmd = RawCommand::new(0x02)
+            .ns_id(qpair.namespace().id())
+            .cdw12(47);
+        qpair.io_passthru(cmd, Some(&mut buf)).unwrap();
+        assert_eq!(buf, pattern);
+    }
+
+    #[test]
+    fn full_queue_keeps_prp_lists() {
+        let mock = MockController::new(1024, 512);
+        let memory = MockMemory::default();
+        let allocator = IdentityAllocator(memory.clone());
+        let mut device = Device::init_with_mmio(mock.clone(), allocator).unwrap();
+        let namespace = device.identify_namespaces(0).unwrap().remove(0);
+        let allocations = memory.allocation_count();
+        let mut qpair = device.create_io_queue_pair(namespace, 8, 0, None).unwrap();
+        let queue_allocations = memory.allocation_count();
+
+        // Transfers of 4 pages are described by a PRP list.
+        let buffers = IdentityAllocator(MockMemory::default());
+        let mut data = Dma::<u8>::allocate_zeroed(4 * 4096, &buffers);
+        data.fill(0x5A);
+        let mut submitted = 0;
+        let err = loop {
+            match qpair.write(data.addr, data.len(), submitted * 32) {
+                Ok(()) => submitted += 1,
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(err, Error::SubQueueFull));
+        assert_eq!(submitted, 7);
+        assert_eq!(qpair.in_flight(), 7);
+        // A list for each write in flight, and the one of the rejected write,
+        // which is cached for reuse.
+        assert_eq!(memory.allocation_count(), queue_allocations + 8);
+
+        // The writes already submitted are still completed.
+        qpair.flush().unwrap();
+        assert_eq!(qpair.in_flight(), 0);
+        assert_eq!(memory.allocation_count(), queue_allocations + 8);
+        let disk = mock.disk();
+        for index in 0..submitted as usize {
+            let offset = index * 32 * 512;
+            assert_eq!(disk[offset..offset + data.len()], data[..]);
+        }
+
+        device.delete_io_queue_pair(qpair).unwrap();
+        assert_eq!(memory.allocation_count(), allocations);
+    }
+
+    #[test]
+    fn implausible_completion_quarantines_prp_lists() {
+        let mock = MockController::new(1024, 512);
+        let memory = MockMemory::default();
+        let allocator = IdentityAllocator(memory.clone());
+        let mut device = Device::init_with_mmio(mock.clone(), allocator).unwrap();
+        let namespace = device.identify_namespaces(0).unwrap().remove(0);
+        let allocations = memory.allocation_count();
+        let mut qpair = device.create_io_queue_pair(namespace, 8, 0, None).unwrap();
+        let queue_allocations = memory.allocation_count();
+
+        // Transfers of 4 pages are described by a PRP list.
+        let buffers = IdentityAllocator(MockMemory::default());
+        let data = Dma::<u8>::allocate_zeroed(4 * 4096, &buffers);
+        qpair.write(data.addr, data.len(), 0).unwrap();
+        assert_eq!(memory.allocation_count(), queue_allocations + 1);
+
+        // A completion reporting a submission queue head past the queue.
+        qpair.comp_queue.data[0].sq_head = 100;
+        assert!(matches!(qpair.flush(), Err(Error::CompletionQueueOverrun)));
+        assert_eq!(qpair.in_flight(), 0);
+        assert_eq!(qpair.quarantined.len(), 1);
+
+        // The list may still be used by the controller, so it is not reused.
+        qpair.write(data.addr, data.len(), 0).unwrap();
+        assert_eq!(memory.allocation_count(), queue_allocations + 2);
+        qpair.flush().unwrap();
+
+        device.delete_io_queue_pair(qpair).unwrap();
+        assert_eq!(memory.allocation_count(), allocations);
+    }
+
+    #[test]
+    fn two_queue_pairs() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+        let namespace = device.identify_namespaces(0).unwrap().remove(0);
+        let mut first = device
+            .create_io_queue_pair(namespace.clone(), 16, 0, None)
+            .unwrap();
+        let mut second = device.create_io_queue_pair(namespace, 16, 0, None).unwrap();
+        assert_eq!(mock.io_queue_count(), 2);
+
+        // Both pairs are held at once and their I/O is interleaved.
+        let memory = IdentityAllocator(MockMemory::default());
+        let mut data = Dma::<u8>::allocate_zeroed(1024, &memory);
+        data.fill(0x11);
+        first.write_blocks(&data, 0).unwrap();
+        data.fill(0x22);
+        second.write_blocks(&data, 2).unwrap();
+
+        first.read_blocks(&mut data, 2).unwrap();
+        assert!(data.iter().all(|&byte| byte == 0x22));
+        second.read_blocks(&mut data, 0).unwrap();
+        assert!(data.iter().all(|&byte| byte == 0x11));
+
+        device.delete_io_queue_pair(first).unwrap();
+        assert_eq!(mock.io_queue_count(), 1);
+        device.delete_io_queue_pair(second).unwrap();
+        assert_eq!(mock.io_queue_count(), 0);
     }
 }
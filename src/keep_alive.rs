@@ -0,0 +1,62 @@
+use crate::cmd::Command;
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::features::FeatureSelect;
+use crate::memory::Allocator;
+
+/// Keep Alive Timer feature identifier.
+const FEATURE_KEEP_ALIVE_TIMER: u8 = 0x0F;
+
+impl<A: Allocator> Device<A> {
+    /// Send a keep alive to the controller.
+    ///
+    /// If a keep alive timeout was negotiated with `enable_keep_alive`,
+    /// the controller treats the host as gone when no admin command arrives
+    /// within the timeout, and it may reset the connection. Call this at least
+    /// twice per timeout, e.g. every `keep_alive_timeout_ms / 2` milliseconds,
+    /// from a timer.
+    pub fn keep_alive(&mut self) -> Result<()> {
+        let cmd_id = self.next_cmd_id();
+        self.exec_admin(Command::keep_alive(cmd_id))?;
+        Ok(())
+    }
+
+    /// Enable the keep alive timer with a timeout of `timeout_ms`, or
+    /// disable it if it is 0.
+    ///
+    /// The keep alive timer is disabled unless enabled here. The controller
+    /// rounds the timeout up to its granularity, the negotiated timeout is
+    /// returned and stored in `keep_alive_timeout_ms` of the controller data.
+    /// Once enabled, `keep_alive` has to be called periodically. The timeout
+    /// is negotiated again after a reset.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::FeatureNotSupported` if the controller does not
+    /// support keep alive (see `keep_alive_granularity_ms`).
+    pub fn enable_keep_alive(&mut self, timeout_ms: u32) -> Result<u32> {
+        if self.data.keep_alive_granularity_ms == 0 {
+            return Err(Error::FeatureNotSupported);
+        }
+
+        self.keep_alive_request_ms = timeout_ms;
+        self.data.keep_alive_timeout_ms = 0;
+        self.set_features(FEATURE_KEEP_ALIVE_TIMER, timeout_ms, false, None)?;
+        let timeout = self.get_features(FEATURE_KEEP_ALIVE_TIMER, FeatureSelect::Current, None)?;
+        self.data.keep_alive_timeout_ms = timeout;
+        Ok(timeout)
+    }
+
+    /// Negotiate the keep alive timeout requested by `enable_keep_alive`
+    /// again, after the controller was reset.
+    ///
+    /// This is best effort, so that a failure does not prevent the controller
+    /// from coming up: the timer is left disabled on error, which is seen
+    /// as a `keep_alive_timeout_ms` of 0.
+    pub(crate) fn restore_keep_alive(&mut self) {
+        self.data.keep_alive_timeout_ms = 0;
+        if self.keep_alive_request_ms != 0 && self.data.keep_alive_granularity_ms != 0 {
+            let _ = self.enable_keep_alive(self.keep_alive_request_ms);
+        }
+    }
+}
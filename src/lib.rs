@@ -3,19 +3,84 @@
 //! This crate provides functionality for interacting with NVMe
 //! (Non-Volatile Memory Express) storage devices in environments without
 //! the standard library, such as kernels, bootloaders, or embedded systems.
+//!
+//! The keep alive timer of the controller is only used once enabled with
+//! `Device::enable_keep_alive`, after which `Device::keep_alive` has to be
+//! called periodically within the negotiated timeout.
 #![no_std]
 #![deny(missing_docs)]
 
 extern crate alloc;
+#[cfg(feature = "test-util")]
+extern crate std;
 
+mod block;
+mod cmb;
 mod cmd;
 mod device;
 mod error;
+mod events;
+mod features;
+mod firmware;
+mod format;
+#[cfg(feature = "async")]
+mod future;
 mod io;
+mod keep_alive;
+mod log;
 mod memory;
+mod mmio;
+#[cfg(feature = "test-util")]
+mod mock;
+mod namespaces;
+mod power;
 mod queues;
+mod reservations;
+mod sanitize;
+mod self_test;
+mod sgl;
+mod shadow;
+mod shared;
+mod status;
+mod telemetry;
+mod temperature;
+mod timeout;
+mod waiter;
+mod zns;
 
-pub use device::{ControllerData, Device, Namespace};
-pub use error::Error;
-pub use io::IoQueuePair;
-pub use memory::Allocator;
+pub use block::{BlockDevice, Disk};
+pub use cmb::CmbInfo;
+pub use cmd::RawCommand;
+pub use device::{
+    ControllerCapabilities, ControllerData, Device, Namespace, NamespaceIds, NamespaceIter, Version,
+};
+pub use error::{DeleteQueuePairError, Error};
+pub use events::AsyncEvent;
+pub use features::{FeatureCapabilities, FeatureSelect, QueuePriority, Timestamp};
+pub use firmware::{CommitAction, FirmwareActivation, FirmwareSlots};
+pub use format::SecureErase;
+#[cfg(feature = "async")]
+pub use future::IoFuture;
+#[cfg(feature = "debug")]
+pub use io::QueueDebugInfo;
+pub use io::{AccessLatency, IoOp, IoOpKind, IoQueuePair, LbaRange, ProtectionInfo, WriteOptions};
+pub use memory::{Allocator, FrameAllocator, IdentityAllocator};
+pub use mmio::{Mmio, MmioRegion};
+#[cfg(feature = "test-util")]
+pub use mock::{MockController, MockMemory};
+pub use namespaces::NamespaceSpec;
+pub use power::PowerStateDescriptor;
+pub use queues::Completion;
+pub use reservations::{
+    AcquireAction, RegisterAction, RegisteredController, ReleaseAction, ReservationStatus,
+    ReservationType,
+};
+pub use sanitize::{SanitizeAction, SanitizeState, SanitizeStatus};
+pub use self_test::{SelfTestKind, SelfTestLog, SelfTestResult};
+pub use shared::{Lock, SharedIoQueuePair};
+pub use status::{Status, StatusCodeType};
+pub use temperature::{Temperatures, ThresholdKind};
+#[cfg(feature = "async")]
+pub use waiter::WakerWaiter;
+pub use waiter::{CompletionWaiter, PollingWaiter};
+pub use zns::{ZoneAction, ZoneDescriptor, ZoneState};
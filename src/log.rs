@@ -0,0 +1,60 @@
+use crate::cmd::Command;
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::memory::Allocator;
+
+/// Log page attributes: extended data for Get Log Page is supported.
+const LPA_EXTENDED_DATA: u8 = 1 << 2;
+
+impl<A: Allocator> Device<A> {
+    /// Read a log page into `buf`.
+    ///
+    /// The `log_id` is the log page identifier and `lsp` is the log specific
+    /// field whose meaning depends on the log page. Reading starts
+    /// at the byte `offset` of the log page and fills the whole `buf`.
+    ///
+    /// Large reads are split into multiple commands so that each transfer
+    /// fits into the admin buffer and the maximum data transfer size.
+    /// All but the first of them read at a non-zero offset, which like
+    /// a non-zero `offset` requires the controller to support extended data
+    /// for Get Log Page.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the offset or the buffer size is not
+    /// a multiple of dword (4 bytes), and `Error::FeatureNotSupported` if
+    /// the read needs an offset but the controller does not support extended
+    /// data (LPA bit 2).
+    pub fn get_log_page(&mut self, log_id: u8, lsp: u8, offset: u64, buf: &mut [u8]) -> Result<()> {
+        if offset & 0x3 != 0 {
+            return Err(Error::NotAlignedToDword);
+        }
+        if !buf.len().is_multiple_of(4) {
+            return Err(Error::InvalidBufferSize);
+        }
+
+        let chunk_size = self
+            .admin_buffer
+            .len()
+            .min(self.controller_data().max_transfer_size);
+        let extended_data = self.controller_data().log_page_attributes & LPA_EXTENDED_DATA != 0;
+        if (offset != 0 || buf.len() > chunk_size) && !extended_data {
+            return Err(Error::FeatureNotSupported);
+        }
+
+        for (index, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let cmd_id = self.next_cmd_id();
+            self.exec_admin(Command::get_log_page(
+                cmd_id,
+                self.admin_buffer.phys_addr,
+                log_id,
+                lsp,
+                (chunk.len() / 4) as u32,
+                offset + (index * chunk_size) as u64,
+            ))?;
+            chunk.copy_from_slice(&self.admin_buffer[..chunk.len()]);
+        }
+
+        Ok(())
+    }
+}
@@ -6,6 +6,13 @@ use core::slice::{from_raw_parts, from_raw_parts_mut};
 /// Allocates physically contiguous memory mapped into virtual address space.
 ///
 /// Used for DMA operations requiring contiguous physical memory.
+///
+/// # Alignment
+///
+/// Queues, PRP lists and other DMA buffers must start on a memory page
+/// boundary, so they are allocated with `allocate_aligned` and an
+/// alignment of the page size, which is a power of two of at least 4096.
+/// Data buffers passed to `IoQueuePair` don't have to come from the allocator.
 pub trait Allocator {
     /// Translates a virtual address to a physical address.
     ///
@@ -13,6 +20,9 @@ pub trait Allocator {
     /// instead of just subtracting an offset (e.g., `virt - HHDM_OFFSET`)
     /// if the address is allocated by a allocator based on virtual memory
     /// (e.g., kernel heap) rather than a frame allocator.
+    ///
+    /// Return 0 if the address is not mapped, the request using it
+    /// then fails with `Error::UnmappedAddress`.
     fn translate(&self, addr: usize) -> usize;
 
     /// Allocates a `size` byte region of memory.
@@ -27,9 +37,28 @@ pub trait Allocator {
     /// - It must be correctly mapped to virtual memory
     unsafe fn allocate(&self, size: usize) -> usize;
 
+    /// Allocates a `size` byte region of memory aligned to `align` bytes.
+    ///
+    /// The `align` is a power of two, and the physical address of the
+    /// region must be aligned as well. It is freed with `deallocate`.
+    ///
+    /// The default implementation forwards to `allocate`, which is only
+    /// correct if `allocate` returns page-aligned regions, as frame
+    /// allocators do. Override it if that's not the case.
+    ///
+    /// # Safety
+    ///
+    /// See `allocate`.
+    unsafe fn allocate_aligned(&self, size: usize, align: usize) -> usize {
+        let addr = unsafe { self.allocate(size) };
+        debug_assert!(addr % align == 0, "allocation is not aligned to {align}");
+        addr
+    }
+
     /// Deallocates a previously allocated region of memory.
     ///
-    /// The address must be the virtual address returned by `allocate`.
+    /// The address must be the virtual address returned by `allocate`
+    /// or `allocate_aligned`.
     ///
     /// # Safety
     ///
@@ -38,6 +67,66 @@ pub trait Allocator {
     unsafe fn deallocate(&self, addr: usize);
 }
 
+/// Allocates physically contiguous memory that is identity mapped.
+///
+/// Implement this instead of `Allocator` if the returned addresses
+/// are physical addresses which can also be accessed directly
+/// (i.e. virtual address equals physical address), and wrap it
+/// in an `IdentityAllocator`.
+pub trait FrameAllocator {
+    /// Allocates a `size` byte region of memory.
+    ///
+    /// Returns the address of the allocated region's start.
+    ///
+    /// # Safety
+    ///
+    /// See `Allocator::allocate`.
+    unsafe fn allocate(&self, size: usize) -> usize;
+
+    /// Allocates a `size` byte region of memory aligned to `align` bytes.
+    ///
+    /// # Safety
+    ///
+    /// See `Allocator::allocate_aligned`.
+    unsafe fn allocate_aligned(&self, size: usize, align: usize) -> usize {
+        let addr = unsafe { self.allocate(size) };
+        debug_assert!(addr % align == 0, "allocation is not aligned to {align}");
+        addr
+    }
+
+    /// Deallocates a previously allocated region of memory.
+    ///
+    /// # Safety
+    ///
+    /// See `Allocator::deallocate`.
+    unsafe fn deallocate(&self, addr: usize);
+}
+
+/// An `Allocator` for identity mapped memory.
+///
+/// It forwards allocations to the wrapped `FrameAllocator`
+/// and translates every address to itself.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdentityAllocator<F>(pub F);
+
+impl<F: FrameAllocator> Allocator for IdentityAllocator<F> {
+    fn translate(&self, addr: usize) -> usize {
+        addr
+    }
+
+    unsafe fn allocate(&self, size: usize) -> usize {
+        unsafe { self.0.allocate(size) }
+    }
+
+    unsafe fn allocate_aligned(&self, size: usize, align: usize) -> usize {
+        unsafe { self.0.allocate_aligned(size, align) }
+    }
+
+    unsafe fn deallocate(&self, addr: usize) {
+        unsafe { self.0.deallocate(addr) }
+    }
+}
+
 /// Represents a DMA (Direct Memory Access) buffer.
 ///
 /// This structure is a wrapper for the generic type `T` and contains
@@ -45,6 +134,9 @@ pub trait Allocator {
 /// and the corresponding physical memory address.
 ///
 /// The `T` stored in memory is page-aligned.
+///
+/// The buffer is always accessed through `addr`, so it works the same
+/// when the virtual and physical addresses are identical.
 pub(crate) struct Dma<T> {
     pub addr: *mut T,
     pub phys_addr: usize,
@@ -79,12 +171,22 @@ impl<T> DerefMut for Dma<T> {
 impl<T> Dma<T> {
     /// Allocates a new DMA buffer using the provided allocator.
     ///
-    /// The allocated memory is page-aligned and sized to fit the type T,
-    /// rounded up to the nearest page boundary.
+    /// The allocated memory is aligned to 4 KiB and sized to fit the type T,
+    /// rounded up to the nearest 4 KiB boundary. Buffers of transfers spanning
+    /// several pages of a controller with a larger memory page size must use
+    /// `allocate_aligned` with that page size instead.
     pub fn allocate<A: Allocator>(count: usize, allocator: &A) -> Dma<T> {
+        Self::allocate_aligned(count, 4096, allocator)
+    }
+
+    /// Allocates a new DMA buffer aligned to `align` bytes.
+    ///
+    /// The `align` must be a power of two of at least 4096,
+    /// and the size is rounded up to a multiple of it.
+    pub fn allocate_aligned<A: Allocator>(count: usize, align: usize, allocator: &A) -> Dma<T> {
         let size = core::mem::size_of::<T>() * count;
-        let aligned = size.div_ceil(4096) * 4096;
-        let addr = unsafe { allocator.allocate(aligned) };
+        let aligned = size.div_ceil(align) * align;
+        let addr = unsafe { allocator.allocate_aligned(aligned, align) };
 
         Self {
             addr: addr as *mut T,
@@ -93,6 +195,25 @@ impl<T> Dma<T> {
         }
     }
 
+    /// Allocates a new DMA buffer like `allocate` and fills it with zeros.
+    ///
+    /// The whole allocated region is cleared, including the padding
+    /// up to the page boundary.
+    pub fn allocate_zeroed<A: Allocator>(count: usize, allocator: &A) -> Dma<T> {
+        let size = core::mem::size_of::<T>() * count;
+        let buffer = Self::allocate(count, allocator);
+        unsafe { core::ptr::write_bytes(buffer.addr as *mut u8, 0, size.div_ceil(4096) * 4096) };
+        buffer
+    }
+
+    /// Fills the DMA buffer with zeros.
+    ///
+    /// Useful to clear a reused buffer before the controller writes into it,
+    /// so that nothing is left from a previous command.
+    pub fn zero(&mut self) {
+        unsafe { core::ptr::write_bytes(self.addr, 0, self.count) };
+    }
+
     /// Deallocates the DMA buffer using the provided allocator.
     ///
     /// # Safety
@@ -106,6 +227,14 @@ impl<T> Dma<T> {
     }
 }
 
+/// Translates `address` with `allocator`, which returns 0 for unmapped addresses.
+fn translate<A: Allocator>(allocator: &A, address: usize) -> Result<usize> {
+    match allocator.translate(address) {
+        0 => Err(Error::UnmappedAddress),
+        phys => Ok(phys),
+    }
+}
+
 /// Represents the result of the creation of a PRP.
 pub(crate) enum PrpResult {
     /// Address of PRP1
@@ -165,30 +294,41 @@ impl<T> FixedSizeQueue<T> {
 /// It will cache a number of PRP lists to avoid frequent allocations.
 pub(crate) struct PrpManager {
     list_pool: FixedSizeQueue<Dma<u64>>,
+    page_size: usize,
 }
 
-impl Default for PrpManager {
-    /// Creates a new `PrpManager` with a default list pool size.
+impl PrpManager {
+    /// Creates a new `PrpManager` for the given memory page size.
+    ///
+    /// The list pool size is 32, which can be adjusted based on the expected workload.
     ///
-    /// The default size is 32, which can be adjusted based on the expected workload.
-    fn default() -> Self {
+    /// Each PRP list occupies exactly one memory page, so the `page_size` decides
+    /// how many entries a list holds (e.g. 512 for 4K pages and 1024 for 8K pages).
+    pub fn new(page_size: usize) -> Self {
         Self {
             list_pool: FixedSizeQueue::new(32),
+            page_size,
         }
     }
-}
 
-impl PrpManager {
+    /// Get the memory page size the PRPs are built upon.
+    ///
+    /// Buffers of transfers spanning several pages must be aligned to it.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
     /// Creates a PRP result for the given address and byte count.
     ///
     /// The NVMe controller will read or write data starting from this address directly.
     ///
     /// # Arguments
     ///
-    /// The start address must be aligned to a 4-byte boundary in all situations.
+    /// The start address must be aligned to a 4-byte boundary in all situations,
+    /// and neither the address nor the byte count may be zero.
     ///
     /// And it must be aligned to a page boundary if read or write
-    /// more than a page (currently always 4096 bytes) because the NVMe controller
+    /// more than a memory page because the NVMe controller
     /// reads or writes data in block size which will cause unexpected memory access.
     pub(crate) fn create<A: Allocator>(
         &mut self,
@@ -196,52 +336,133 @@ impl PrpManager {
         address: usize,
         bytes: usize,
     ) -> Result<PrpResult> {
+        if address == 0 || bytes == 0 {
+            return Err(Error::InvalidDmaRegion);
+        }
         if (address & 0x3) != 0 {
             return Err(Error::NotAlignedToDword);
         }
 
-        let prp1 = allocator.translate(address);
-        let count = ((address & 0xfff) + bytes).div_ceil(4096);
+        let page_size = self.page_size;
+        let page_offset = address & (page_size - 1);
+
+        let prp1 = translate(allocator, address)?;
+        let count = (page_offset + bytes).div_ceil(page_size);
 
         if count == 1 {
             return Ok(PrpResult::Single(prp1));
         }
 
-        if (address & 0xfff) != 0 {
+        if page_offset != 0 {
+            return Err(Error::NotAlignedToPage);
+        }
+
+        let prp2_start = translate(allocator, address + page_size)?;
+        let page = |index| prp2_start + index * page_size;
+
+        Ok(self.build(allocator, prp1, count - 1, page))
+    }
+
+    /// Creates a PRP result for a transfer spanning several buffers.
+    ///
+    /// Each segment is an `(address, length)` pair. The buffers are joined
+    /// into one PRP list, which requires every boundary between them to be
+    /// page aligned: only the first segment may start within a page and only
+    /// the last one may end within a page. Otherwise `Error::NotAlignedToPage`
+    /// is returned. Unlike `create`, every page is translated on its own,
+    /// so the buffers only have to be contiguous in virtual memory.
+    pub(crate) fn create_vectored<A: Allocator>(
+        &mut self,
+        allocator: &A,
+        segments: &[(usize, usize)],
+    ) -> Result<PrpResult> {
+        let page_size = self.page_size;
+        let last = segments.len().saturating_sub(1);
+        let aligned = segments
+            .iter()
+            .enumerate()
+            .all(|(index, &(address, bytes))| {
+                (index == 0 || address & (page_size - 1) == 0)
+                    && (index == last || (address + bytes) & (page_size - 1) == 0)
+            });
+        if !aligned {
             return Err(Error::NotAlignedToPage);
         }
 
-        let prp2_start = allocator.translate(address + 4096);
+        let Some(&(address, _)) = segments.first() else {
+            return Err(Error::InvalidBufferSize);
+        };
+        if segments
+            .iter()
+            .any(|&(address, bytes)| address == 0 || bytes == 0)
+        {
+            return Err(Error::InvalidDmaRegion);
+        }
+        if (address & 0x3) != 0 {
+            return Err(Error::NotAlignedToDword);
+        }
+
+        let pages = segments
+            .iter()
+            .flat_map(|&(address, bytes)| {
+                let start = address & !(page_size - 1);
+                (start..address + bytes).step_by(page_size)
+            })
+            .map(|page| translate(allocator, page))
+            .collect::<Result<Vec<_>>>()?;
+        let prp1 = translate(allocator, address)?;
 
-        if count == 2 {
-            return Ok(PrpResult::Double(prp1, prp2_start));
+        Ok(self.build(allocator, prp1, pages.len() - 1, |index| pages[index + 1]))
+    }
+
+    /// Builds a PRP result from the first PRP and the `remaining` pages.
+    ///
+    /// The `page` closure returns the physical address of each page after the first.
+    fn build<A: Allocator>(
+        &mut self,
+        allocator: &A,
+        prp1: usize,
+        remaining: usize,
+        page: impl Fn(usize) -> usize,
+    ) -> PrpResult {
+        let page_size = self.page_size;
+        match remaining {
+            0 => return PrpResult::Single(prp1),
+            1 => return PrpResult::Double(prp1, page(0)),
+            _ => {}
         }
 
-        let remaining = count - 1;
-        let lists_needed = (remaining - 1).div_ceil(511);
+        // The last entry of each list page points to the next list
+        // if there are more entries than a single list can hold.
+        let list_entries = page_size / 8;
+        let chain_index = list_entries - 1;
+
+        let lists_needed = (remaining - 1).div_ceil(chain_index);
         let mut prp_lists = Vec::with_capacity(lists_needed);
 
         for list_idx in 0..lists_needed {
             let entries = if list_idx == lists_needed - 1 {
-                remaining - list_idx * 511
+                remaining - list_idx * chain_index
             } else {
-                511
+                chain_index
             };
-            let mut prp_list = self
-                .list_pool
-                .pop()
-                .unwrap_or_else(|| Dma::allocate(512, allocator));
+            let mut prp_list = self.list_pool.pop().unwrap_or_else(|| {
+                // A list must not cross a memory page boundary.
+                let mut list = Dma::allocate_aligned(list_entries, page_size, allocator);
+                list.zero();
+                list
+            });
             for i in 0..entries {
-                prp_list[i] = (prp2_start + (list_idx * 511 + i) * 4096) as u64;
+                prp_list[i] = page(list_idx * chain_index + i) as u64;
             }
             prp_lists.push(prp_list);
         }
 
         for index in 0..prp_lists.len() - 1 {
-            prp_lists[index][511] = prp_lists[index + 1].phys_addr as u64;
+            prp_lists[index][chain_index] = prp_lists[index + 1].phys_addr as u64;
         }
 
-        Ok(PrpResult::List(prp1, prp_lists))
+        PrpResult::List(prp1, prp_lists)
     }
 
     /// Releases the resources associated with a PRP result.
@@ -261,4 +482,65 @@ impl PrpManager {
             }
         }
     }
+
+    /// Deallocates all cached PRP lists.
+    pub(crate) fn clear<A: Allocator>(&mut self, allocator: &A) {
+        while let Some(prp) = self.list_pool.pop() {
+            prp.deallocate(allocator);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::mock::MockMemory;
+
+    /// A memory page size of 8 KiB, whose PRP lists hold 1024 entries.
+    const PAGE_SIZE: usize = 8192;
+
+    /// A page aligned address, which is translated but never accessed.
+    const ADDRESS: usize = 0x100_0000;
+
+    #[test]
+    fn list_of_8k_page() {
+        let allocator = IdentityAllocator(MockMemory::default());
+        let mut prp_manager = PrpManager::new(PAGE_SIZE);
+
+        // The first page and 1024 more, which fill a single list.
+        let result = prp_manager.create(&allocator, ADDRESS, 1025 * PAGE_SIZE);
+        let Ok(PrpResult::List(prp1, lists)) = &result else {
+            panic!("the transfer is not described by a PRP list");
+        };
+        assert_eq!(*prp1, ADDRESS);
+        assert_eq!(lists.len(), 1);
+        assert_eq!(lists[0].len(), 1024);
+        assert_eq!(lists[0].phys_addr % PAGE_SIZE, 0);
+        assert_eq!(lists[0][0], (ADDRESS + PAGE_SIZE) as u64);
+        assert_eq!(lists[0][1023], (ADDRESS + 1024 * PAGE_SIZE) as u64);
+
+        prp_manager.release(result.unwrap(), &allocator);
+        prp_manager.clear(&allocator);
+    }
+
+    #[test]
+    fn chained_lists_of_8k_page() {
+        let allocator = IdentityAllocator(MockMemory::default());
+        let mut prp_manager = PrpManager::new(PAGE_SIZE);
+
+        // One page more than a single list holds.
+        let result = prp_manager.create(&allocator, ADDRESS, 1026 * PAGE_SIZE);
+        let Ok(PrpResult::List(_, lists)) = &result else {
+            panic!("the transfer is not described by a PRP list");
+        };
+        assert_eq!(lists.len(), 2);
+        // The last entry of the first list points to the next list.
+        assert_eq!(lists[0][1022], (ADDRESS + 1023 * PAGE_SIZE) as u64);
+        assert_eq!(lists[0][1023], lists[1].phys_addr as u64);
+        assert_eq!(lists[1][0], (ADDRESS + 1024 * PAGE_SIZE) as u64);
+        assert_eq!(lists[1][1], (ADDRESS + 1025 * PAGE_SIZE) as u64);
+
+        prp_manager.release(result.unwrap(), &allocator);
+        prp_manager.clear(&allocator);
+    }
 }
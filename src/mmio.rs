@@ -0,0 +1,92 @@
+/// Accesses the memory mapped registers of an NVMe controller.
+///
+/// All register and doorbell accesses of the driver go through this trait,
+/// so it can be implemented to intercept them (e.g. by a mock controller).
+/// The `offset` is relative to the base address of the controller.
+pub trait Mmio: Send + Sync {
+    /// Reads a 32-bit register.
+    fn read32(&self, offset: usize) -> u32;
+
+    /// Writes a 32-bit register.
+    fn write32(&self, offset: usize, value: u32);
+
+    /// Reads a 64-bit register.
+    ///
+    /// The default implementation reads the lower dword first.
+    fn read64(&self, offset: usize) -> u64 {
+        let low = self.read32(offset) as u64;
+        let high = self.read32(offset + 4) as u64;
+        (high << 32) | low
+    }
+
+    /// Writes a 64-bit register.
+    ///
+    /// The default implementation writes the lower dword first.
+    fn write64(&self, offset: usize, value: u64) {
+        self.write32(offset, value as u32);
+        self.write32(offset + 4, (value >> 32) as u32);
+    }
+}
+
+/// Registers mapped into the virtual address space.
+///
+/// This is the default `Mmio` used by `Device::init`,
+/// which accesses the registers with volatile reads and writes.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioRegion {
+    address: usize,
+}
+
+impl MmioRegion {
+    /// Creates a new `MmioRegion` starting at the virtual `address`.
+    pub fn new(address: usize) -> Self {
+        Self { address }
+    }
+}
+
+impl Mmio for MmioRegion {
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { ((self.address + offset) as *const u32).read_volatile() }
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe { ((self.address + offset) as *mut u32).write_volatile(value) }
+    }
+
+    fn read64(&self, offset: usize) -> u64 {
+        unsafe { ((self.address + offset) as *const u64).read_volatile() }
+    }
+
+    fn write64(&self, offset: usize, value: u64) {
+        unsafe { ((self.address + offset) as *mut u64).write_volatile(value) }
+    }
+}
+
+/// A value that can be read from or written to a register.
+pub(crate) trait RegisterValue: Sized {
+    /// Reads the value at `offset`.
+    fn read(mmio: &dyn Mmio, offset: usize) -> Self;
+
+    /// Writes the value at `offset`.
+    fn write(self, mmio: &dyn Mmio, offset: usize);
+}
+
+impl RegisterValue for u32 {
+    fn read(mmio: &dyn Mmio, offset: usize) -> Self {
+        mmio.read32(offset)
+    }
+
+    fn write(self, mmio: &dyn Mmio, offset: usize) {
+        mmio.write32(offset, self)
+    }
+}
+
+impl RegisterValue for u64 {
+    fn read(mmio: &dyn Mmio, offset: usize) -> Self {
+        mmio.read64(offset)
+    }
+
+    fn write(self, mmio: &dyn Mmio, offset: usize) {
+        mmio.write64(offset, self)
+    }
+}
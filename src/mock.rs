@@ -0,0 +1,1229 @@
+use std::alloc::{Layout, alloc_zeroed, dealloc};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::vec;
+use std::vec::Vec;
+
+use crate::memory::FrameAllocator;
+use crate::mmio::Mmio;
+
+/// Alignment of every allocation of `MockMemory`.
+const PAGE_SIZE: usize = 4096;
+
+/// The only namespace ID of the mock controller.
+const NAMESPACE_ID: u32 = 1;
+/// The EUI64 reported for the namespace.
+const MOCK_EUI64: [u8; 8] = [0x00, 0x1B, 0x36, 0x00, 0x00, 0x00, 0x00, 0x01];
+/// The UUID reported for the namespace.
+const MOCK_UUID: [u8; 16] = *b"nvme-rs mock ns1";
+
+/// Status code of a successful command.
+const STATUS_SUCCESS: u16 = 0x0;
+/// Generic status: Invalid Command Opcode.
+const STATUS_INVALID_OPCODE: u16 = 0x01;
+/// Generic status: Invalid Field in Command.
+const STATUS_INVALID_FIELD: u16 = 0x02;
+/// Generic status: Invalid Namespace or Format.
+const STATUS_INVALID_NAMESPACE: u16 = 0x0B;
+/// Generic status: LBA Out of Range.
+const STATUS_LBA_OUT_OF_RANGE: u16 = 0x80;
+/// Command specific status: Completion Queue Invalid.
+const STATUS_INVALID_CQ: u16 = 0x100;
+/// Command specific status: Invalid Queue Identifier.
+const STATUS_INVALID_QID: u16 = 0x101;
+/// Command specific status: Invalid Format.
+const STATUS_INVALID_FORMAT: u16 = 0x10A;
+/// Command specific status: Invalid Queue Deletion.
+const STATUS_INVALID_QUEUE_DELETION: u16 = 0x10C;
+/// Generic status: Command Aborted due to Failed Fused Command.
+const STATUS_FUSED_ABORT: u16 = 0x09;
+/// Zone state: Empty.
+const ZONE_EMPTY: u8 = 0x1;
+/// Zone state: Implicitly Opened.
+const ZONE_IMPLICITLY_OPENED: u8 = 0x2;
+/// Zone state: Explicitly Opened.
+const ZONE_EXPLICITLY_OPENED: u8 = 0x3;
+/// Zone state: Closed.
+const ZONE_CLOSED: u8 = 0x4;
+/// Zone state: Full.
+const ZONE_FULL: u8 = 0xE;
+/// Command specific status: Zone Boundary Error.
+const STATUS_ZONE_BOUNDARY_ERROR: u16 = 0x1B8;
+/// Command specific status: Invalid Zone State Transition.
+const STATUS_INVALID_ZONE_STATE_TRANSITION: u16 = 0x1BF;
+/// Media and data integrity error: Compare Failure.
+const STATUS_COMPARE_FAILURE: u16 = 0x285;
+
+/// Number of I/O queues granted by Set Features Number of Queues (zero-based).
+const MAX_IO_QUEUES: u32 = 15;
+
+/// Page-aligned heap memory to be used with a `MockController`.
+///
+/// The memory is identity mapped, so wrap it in an `IdentityAllocator`
+/// to get an `Allocator` for the `Device`. The mock controller accesses
+/// queues and data buffers directly through their physical addresses,
+/// which requires all DMA memory to come from this allocator.
+#[derive(Debug, Default, Clone)]
+pub struct MockMemory {
+    allocations: Arc<Mutex<BTreeMap<usize, Layout>>>,
+}
+
+impl FrameAllocator for MockMemory {
+    unsafe fn allocate(&self, size: usize) -> usize {
+        unsafe { self.allocate_aligned(size, PAGE_SIZE) }
+    }
+
+    unsafe fn allocate_aligned(&self, size: usize, align: usize) -> usize {
+        let layout = Layout::from_size_align(size.max(1), align.max(PAGE_SIZE)).unwrap();
+        let addr = unsafe { alloc_zeroed(layout) } as usize;
+        self.allocations.lock().unwrap().insert(addr, layout);
+        addr
+    }
+
+    unsafe fn deallocate(&self, addr: usize) {
+        if let Some(layout) = self.allocations.lock().unwrap().remove(&addr) {
+            unsafe { dealloc(addr as *mut u8, layout) }
+        }
+    }
+}
+
+impl MockMemory {
+    /// Returns the number of allocations which are not deallocated yet,
+    /// e.g. to check that no DMA memory is leaked.
+    pub fn allocation_count(&self) -> usize {
+        self.allocations.lock().unwrap().len()
+    }
+}
+
+/// A submission or completion queue created on the mock controller.
+#[derive(Debug, Clone, Copy)]
+struct Queue {
+    /// The slots, or the PRP list describing them if not contiguous
+    base: usize,
+    /// The slots are physically contiguous
+    contiguous: bool,
+    size: u16,
+    /// Head for submission queues, tail for completion queues
+    position: u16,
+    /// The completion queue of a submission queue
+    cq_id: u16,
+    /// Phase tag of a completion queue
+    phase: bool,
+}
+
+impl Queue {
+    fn new(base: usize, size: u16, cq_id: u16) -> Self {
+        Self {
+            base,
+            contiguous: true,
+            size,
+            position: 0,
+            cq_id,
+            phase: true,
+        }
+    }
+
+    /// Get the address of the slot at `position`.
+    fn slot(&self, position: u16, entry_size: usize, page_size: usize) -> usize {
+        let offset = position as usize * entry_size;
+        if self.contiguous {
+            return self.base + offset;
+        }
+        let entry = self.base + offset / page_size * 8;
+        let page = unsafe { (entry as *const u64).read_volatile() } as usize;
+        page + offset % page_size
+    }
+}
+
+/// A submission queue entry read from host memory.
+struct Entry {
+    opcode: u8,
+    /// The fused operation, 1 for the first and 2 for the second command
+    fuse: u8,
+    cmd_id: u16,
+    ns_id: u32,
+    prp: (usize, usize),
+    sgl: bool,
+    cdw: [u32; 6],
+}
+
+/// The internal state of the mock controller.
+struct State {
+    cap: u64,
+    version: u32,
+    cc: u32,
+    csts: u32,
+    intms: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+    sub_queues: BTreeMap<u16, Queue>,
+    comp_queues: BTreeMap<u16, Queue>,
+    features: BTreeMap<u8, u32>,
+    block_size: usize,
+    lba_formats: [usize; 2],
+    lba_format: usize,
+    disk: Vec<u8>,
+    fail_next: Option<u16>,
+    commands: usize,
+    async_events: VecDeque<u16>,
+    self_test: Option<u8>,
+    sanitized: bool,
+    /// Addresses of the shadow doorbell and EventIdx buffers
+    shadow: Option<(usize, usize)>,
+    cmbsz: u32,
+    timestamp: u64,
+    /// Post the completions of an I/O doorbell write in reverse order
+    reverse_completions: bool,
+    /// Report the namespace as write protected
+    write_protected: bool,
+    /// The first command of a fused operation failed
+    fused_failed: bool,
+    /// Size of the zones (in blocks), 0 if the namespace is not zoned
+    zone_size: u64,
+    /// The state and write pointer of each zone
+    zones: Vec<(u8, u64)>,
+    /// The Identify Controller data given by `with_identify_controller`
+    identify_controller: Option<Vec<u8>>,
+}
+
+/// An in-memory NVMe controller for tests.
+///
+/// It implements `Mmio` and answers the register accesses of a `Device`
+/// like a real controller with a single namespace (ID 1) backed by a
+/// `Vec<u8>` disk. Commands are executed synchronously when the submission
+/// queue doorbell is written, so the whole submit and complete path can be
+/// exercised deterministically without hardware.
+///
+/// It supports identify (including an EUI64 and a UUID for the namespace),
+/// features, log pages (zeroed except for the firmware slots, self-tests and
+/// sanitize status), queue creation and deletion, asynchronous events (see
+/// `trigger_async_event`), device self-tests and sanitize operations which
+/// complete immediately (sanitizing erases the disk),
+/// and the flush, read, write, compare, write zeroes and dataset management
+/// I/O commands, as well as zone reports, appends and management for a zoned namespace
+/// (see `with_zones`).
+/// Reads and writes accept both PRPs and SGLs.
+/// At most 16 I/O queues are granted.
+/// The namespace can be formatted to `block_size` (index 0) or to 4096 or
+/// 512 bytes (index 1), which erases the disk.
+/// All DMA memory must be allocated by `MockMemory`.
+///
+/// The controller is cheap to clone and all clones share the same state,
+/// so a clone can be kept to inspect the disk after passing it to the device.
+#[derive(Clone)]
+pub struct MockController {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockController {
+    /// Creates a mock controller with a namespace of `block_count` blocks.
+    ///
+    /// The `block_size` (in bytes) must be a power of two no less than 512.
+    pub fn new(block_count: u64, block_size: usize) -> Self {
+        assert!(block_size.is_power_of_two() && block_size >= 512);
+
+        // MQES = 1023, CQR, TO = 10s, NSSRS, NVM command set, MPSMIN = 4K
+        let cap = 1023 | (1 << 16) | (20 << 24) | (1 << 36) | (1 << 37);
+        let state = State {
+            cap,
+            version: 0x0001_0400,
+            cc: 0,
+            csts: 0,
+            intms: 0,
+            aqa: 0,
+            asq: 0,
+            acq: 0,
+            sub_queues: BTreeMap::new(),
+            comp_queues: BTreeMap::new(),
+            features: BTreeMap::new(),
+            block_size,
+            lba_formats: [block_size, if block_size == 512 { 4096 } else { 512 }],
+            lba_format: 0,
+            disk: vec![0; block_count as usize * block_size],
+            fail_next: None,
+            commands: 0,
+            async_events: VecDeque::new(),
+            self_test: None,
+            sanitized: false,
+            shadow: None,
+            cmbsz: 0,
+            timestamp: 0,
+            reverse_completions: false,
+            write_protected: false,
+            fused_failed: false,
+            zone_size: 0,
+            zones: Vec::new(),
+            identify_controller: None,
+        };
+
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Clears CAP.CQR, so that I/O queues may be described by PRP lists.
+    pub fn with_noncontiguous_queues(self) -> Self {
+        self.state().cap &= !(1 << 16);
+        self
+    }
+
+    /// Sets the maximum number of entries of an I/O queue reported in CAP.MQES.
+    pub fn with_max_queue_entries(self, entries: u16) -> Self {
+        {
+            let mut state = self.state();
+            state.cap = (state.cap & !0xFFFF) | (entries as u64 - 1);
+        }
+        self
+    }
+
+    /// Sets the minimum and maximum memory page size reported in CAP.MPSMIN
+    /// and CAP.MPSMAX, which the driver then configures in CC.MPS.
+    pub fn with_min_page_size(self, page_size: usize) -> Self {
+        {
+            let mut state = self.state();
+            let mps = (page_size.trailing_zeros() - 12) as u64 & 0xF;
+            state.cap = (state.cap & !(0xFF << 48)) | (mps << 48) | (mps << 52);
+        }
+        self
+    }
+
+    /// Sets the doorbell stride reported in CAP.DSTRD.
+    pub fn with_doorbell_stride(self, stride: u8) -> Self {
+        {
+            let mut state = self.state();
+            state.cap = (state.cap & !(0xF << 32)) | ((stride as u64 & 0xF) << 32);
+        }
+        self
+    }
+
+    /// Returns a copy of the disk content.
+    pub fn disk(&self) -> Vec<u8> {
+        self.state().disk.clone()
+    }
+
+    /// Writes `data` to the disk starting at byte `offset`.
+    pub fn write_disk(&self, offset: usize, data: &[u8]) {
+        self.state().disk[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    /// Reports a CMB of `pages` 4 KiB pages for submission queues.
+    ///
+    /// The CMB starts at offset 0 of BAR 0. As the mock reads queues
+    /// from host memory anyway, any memory may be used for it.
+    pub fn with_cmb(self, pages: u32) -> Self {
+        self.state().cmbsz = pages << 12 | 1;
+        self
+    }
+
+    /// Completes the I/O commands submitted by a doorbell write in reverse order.
+    ///
+    /// NVMe does not require commands to complete in submission order,
+    /// this exercises the handling of out-of-order completions.
+    pub fn with_reversed_completions(self) -> Self {
+        self.state().reverse_completions = true;
+        self
+    }
+
+    /// Makes the namespace a zoned namespace with zones of `zone_size` blocks.
+    ///
+    /// All zones start empty. Writes at the write pointer of a zone advance it,
+    /// other writes are executed without checks.
+    pub fn with_zones(self, zone_size: u64) -> Self {
+        {
+            let mut state = self.state();
+            let blocks = (state.disk.len() / state.block_size) as u64;
+            state.zone_size = zone_size;
+            state.zones = (0..blocks / zone_size)
+                .map(|index| (ZONE_EMPTY, index * zone_size))
+                .collect();
+        }
+        self
+    }
+
+    /// Answers Identify Controller with `page` instead of the generated data,
+    /// e.g. with a page captured from a real controller.
+    ///
+    /// The page is padded with zeros to 4096 bytes.
+    pub fn with_identify_controller(self, page: &[u8]) -> Self {
+        let mut data = vec![0; 4096];
+        data[..page.len()].copy_from_slice(page);
+        self.state().identify_controller = Some(data);
+        self
+    }
+
+    /// Reports the namespace as write protected in NSATTR.
+    ///
+    /// Only the identify data changes, writes are still executed.
+    pub fn with_write_protection(self) -> Self {
+        self.state().write_protected = true;
+        self
+    }
+
+    /// Makes the next command fail with the given status.
+    ///
+    /// The `status` contains the status code in bits 0-7
+    /// and the status code type in bits 8-10.
+    pub fn fail_next_command(&self, status: u16) {
+        self.state().fail_next = Some(status);
+    }
+
+    /// Completes an outstanding Asynchronous Event Request.
+    ///
+    /// Returns `false` if no request is outstanding.
+    pub fn trigger_async_event(&self, event_type: u8, info: u8, log_page: u8) -> bool {
+        let mut state = self.state();
+        let (Some(cmd_id), Some(queue)) =
+            (state.async_events.pop_front(), state.sub_queues.get(&0))
+        else {
+            return false;
+        };
+
+        let queue = *queue;
+        let result = event_type as u32 & 0x7 | (info as u32) << 8 | (log_page as u32) << 16;
+        state.post_completion(0, &queue, cmd_id, result, STATUS_SUCCESS);
+        true
+    }
+
+    /// Puts the controller into a fatal state (CSTS.CFS).
+    ///
+    /// No command is processed until the controller is reset.
+    pub fn fail_controller(&self) {
+        self.state().csts |= 1 << 1;
+    }
+
+    /// Returns the number of commands executed so far.
+    pub fn command_count(&self) -> usize {
+        self.state().commands
+    }
+
+    /// Returns the number of I/O submission queues currently created.
+    pub fn io_queue_count(&self) -> usize {
+        self.state()
+            .sub_queues
+            .keys()
+            .filter(|&&id| id != 0)
+            .count()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+impl MockController {
+    /// Brings up a `Device` on the mock controller for a test.
+    pub(crate) fn device(&self) -> crate::Device<crate::IdentityAllocator<MockMemory>> {
+        let memory = crate::IdentityAllocator(MockMemory::default());
+        crate::Device::init_with_mmio(self.clone(), memory).unwrap()
+    }
+}
+
+impl Mmio for MockController {
+    fn read32(&self, offset: usize) -> u32 {
+        let state = self.state();
+        match offset {
+            0x00 => state.cap as u32,
+            0x04 => (state.cap >> 32) as u32,
+            0x08 => state.version,
+            0x0C | 0x10 => state.intms,
+            0x14 => state.cc,
+            0x1C => state.csts,
+            0x24 => state.aqa,
+            0x28 => state.asq as u32,
+            0x2C => (state.asq >> 32) as u32,
+            0x30 => state.acq as u32,
+            0x34 => (state.acq >> 32) as u32,
+            0x3C => state.cmbsz,
+            _ => 0,
+        }
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        let mut state = self.state();
+        match offset {
+            0x0C => state.intms |= value,
+            0x10 => state.intms &= !value,
+            0x14 => state.write_cc(value),
+            0x20 if value == 0x4E56_4D65 => {
+                state.write_cc(0);
+                state.csts |= 1 << 4;
+            }
+            0x24 => state.aqa = value,
+            0x28 => state.asq = (state.asq & !0xFFFF_FFFF) | value as u64,
+            0x2C => state.asq = (state.asq & 0xFFFF_FFFF) | (value as u64) << 32,
+            0x30 => state.acq = (state.acq & !0xFFFF_FFFF) | value as u64,
+            0x34 => state.acq = (state.acq & 0xFFFF_FFFF) | (value as u64) << 32,
+            offset if offset >= 0x1000 => {
+                let stride = 4 << ((state.cap >> 32) & 0xF);
+                assert!(
+                    (offset - 0x1000).is_multiple_of(stride),
+                    "doorbell write at {offset:#x} is not aligned to the stride"
+                );
+                let index = (offset - 0x1000) / stride;
+                if index.is_multiple_of(2) {
+                    // The shadow doorbell holds the tail of I/O queues.
+                    let tail = match state.shadow {
+                        Some((doorbells, _)) if index != 0 => {
+                            let addr = doorbells + offset - 0x1000;
+                            unsafe { (addr as *const u32).read_volatile() }
+                        }
+                        _ => value,
+                    };
+                    state.ring_submission((index / 2) as u16, tail as u16);
+                    // Ask to be notified of the next doorbell update.
+                    if let Some((_, event_indices)) = state.shadow
+                        && index != 0
+                    {
+                        let addr = event_indices + offset - 0x1000;
+                        unsafe { (addr as *mut u32).write_volatile(tail) };
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl State {
+    /// Handles a write to the controller configuration register.
+    fn write_cc(&mut self, value: u32) {
+        let enabled = self.cc & 1 != 0;
+        self.cc = value;
+
+        if value & 1 != 0 && !enabled {
+            let sq_size = (self.aqa & 0xFFF) as u16 + 1;
+            let cq_size = ((self.aqa >> 16) & 0xFFF) as u16 + 1;
+            let admin_sq = Queue::new(self.asq as usize, sq_size, 0);
+            self.sub_queues.insert(0, admin_sq);
+            self.comp_queues
+                .insert(0, Queue::new(self.acq as usize, cq_size, 0));
+            self.csts |= 1;
+        } else if value & 1 == 0 && enabled {
+            self.sub_queues.clear();
+            self.comp_queues.clear();
+            self.async_events.clear();
+            self.shadow = None;
+            // The granted queues are lost by a reset.
+            self.features.remove(&0x07);
+            self.csts &= !0b11;
+        }
+
+        // Shutdown notification completes immediately.
+        if (value >> 14) & 0x3 != 0 {
+            self.csts = (self.csts & !(0x3 << 2)) | (0b10 << 2);
+        } else {
+            self.csts &= !(0x3 << 2);
+        }
+    }
+
+    /// The memory page size configured in CC.MPS.
+    fn page_size(&self) -> usize {
+        1 << (12 + ((self.cc >> 7) & 0xF))
+    }
+
+    /// Executes all commands up to the new `tail` of a submission queue.
+    fn ring_submission(&mut self, qid: u16, tail: u16) {
+        let Some(mut queue) = self.sub_queues.get(&qid).copied() else {
+            return;
+        };
+        if self.csts & (1 << 1) != 0 {
+            return;
+        }
+
+        let mut delayed = Vec::new();
+        while queue.position != tail {
+            let entry = read_entry(queue.slot(queue.position, 64, self.page_size()));
+            queue.position = (queue.position + 1) % queue.size;
+            self.sub_queues.insert(qid, queue);
+
+            self.commands += 1;
+            // Asynchronous Event Requests complete once an event is triggered.
+            if qid == 0 && entry.opcode == 0x0C && self.fail_next.is_none() {
+                self.async_events.push_back(entry.cmd_id);
+                continue;
+            }
+            let (result, status) = match self.fail_next.take() {
+                Some(status) => (0, status),
+                None if entry.fuse == 2 && self.fused_failed => (0, STATUS_FUSED_ABORT),
+                None if qid == 0 => self.execute_admin(&entry),
+                None => self.execute_io(&entry),
+            };
+            self.fused_failed = entry.fuse == 1 && status != STATUS_SUCCESS;
+            if qid != 0 && self.reverse_completions {
+                delayed.push((entry.cmd_id, result, status));
+            } else {
+                self.post_completion(qid, &queue, entry.cmd_id, result, status);
+            }
+
+            // The command may have deleted its own queue.
+            match self.sub_queues.get(&qid) {
+                Some(current) => queue = *current,
+                None => return,
+            }
+        }
+
+        for (cmd_id, result, status) in delayed.into_iter().rev() {
+            self.post_completion(qid, &queue, cmd_id, result, status);
+        }
+    }
+
+    /// Writes a completion entry to the completion queue of `sq`.
+    fn post_completion(&mut self, qid: u16, sq: &Queue, cmd_id: u16, result: u32, status: u16) {
+        let page_size = self.page_size();
+        let Some(cq) = self.comp_queues.get_mut(&sq.cq_id) else {
+            return;
+        };
+
+        let dwords = [
+            result,
+            0,
+            sq.position as u32 | (qid as u32) << 16,
+            cmd_id as u32 | ((status as u32) << 17) | (cq.phase as u32) << 16,
+        ];
+        let addr = cq.slot(cq.position, 16, page_size);
+        unsafe { (addr as *mut [u32; 4]).write_volatile(dwords) };
+
+        cq.position = (cq.position + 1) % cq.size;
+        if cq.position == 0 {
+            cq.phase = !cq.phase;
+        }
+    }
+
+    /// Executes an admin command, returning dword 0 and the status.
+    fn execute_admin(&mut self, entry: &Entry) -> (u32, u16) {
+        let [cdw10, cdw11, cdw12, cdw13, ..] = entry.cdw;
+        let queue_id = cdw10 as u16;
+        let queue_size = (cdw10 >> 16) as u16 + 1;
+
+        match entry.opcode {
+            // Delete I/O Submission Queue
+            0x00 => match queue_id != 0 && self.sub_queues.remove(&queue_id).is_some() {
+                true => (0, STATUS_SUCCESS),
+                false => (0, STATUS_INVALID_QID),
+            },
+            // Create I/O Submission Queue
+            0x01 => {
+                let cq_id = (cdw11 >> 16) as u16;
+                if !self.comp_queues.contains_key(&cq_id) || cq_id == 0 {
+                    return (0, STATUS_INVALID_CQ);
+                }
+                if !self.valid_io_queue(queue_id) || self.sub_queues.contains_key(&queue_id) {
+                    return (0, STATUS_INVALID_QID);
+                }
+                let mut queue = Queue::new(entry.prp.0, queue_size, cq_id);
+                queue.contiguous = cdw11 & 1 != 0;
+                self.sub_queues.insert(queue_id, queue);
+                (0, STATUS_SUCCESS)
+            }
+            // Get Log Page
+            0x02 => {
+                let dwords = ((cdw10 >> 16) | (cdw11 & 0xFFFF) << 16) as usize + 1;
+                let mut log = vec![0; dwords * 4];
+                // Firmware Slot Information: slot 1 is active
+                if cdw10 as u8 == 0x03 && log.len() >= 16 {
+                    log[0] = 1;
+                    log[8..16].copy_from_slice(b"1.0     ");
+                }
+                // Changed Namespace List: the namespace of the mock
+                if cdw10 as u8 == 0x04 && log.len() >= 4 {
+                    log[..4].copy_from_slice(&NAMESPACE_ID.to_le_bytes());
+                }
+                // SMART / Health Information: 310 K composite, sensor 1 at 305 K
+                if cdw10 as u8 == 0x02 && log.len() >= 202 {
+                    log[1..3].copy_from_slice(&310u16.to_le_bytes());
+                    log[200..202].copy_from_slice(&305u16.to_le_bytes());
+                }
+                // Sanitize Status
+                if cdw10 as u8 == 0x81 && log.len() >= 4 {
+                    log[..2].copy_from_slice(&0xFFFFu16.to_le_bytes());
+                    log[2] = self.sanitized as u8;
+                }
+                // Telemetry Host-Initiated: data areas end at blocks 1, 2 and 3,
+                // and every byte of a block holds the block number
+                if cdw10 as u8 == 0x07 {
+                    let offset = (cdw12 as u64 | (cdw13 as u64) << 32) as usize;
+                    for (index, byte) in log.iter_mut().enumerate() {
+                        *byte = ((offset + index) / 512) as u8;
+                    }
+                    if offset == 0 && log.len() >= 14 {
+                        log[..14].fill(0);
+                        log[0] = 0x07;
+                        log[8..14].copy_from_slice(&[1, 0, 2, 0, 3, 0]);
+                    }
+                }
+                // Device Self-test: self-tests complete immediately
+                if cdw10 as u8 == 0x06 && log.len() >= 4 {
+                    for (index, entry) in log[4..].chunks_exact_mut(28).enumerate() {
+                        entry[0] = match self.self_test {
+                            Some(code) if index == 0 => code << 4,
+                            _ => 0xF,
+                        };
+                    }
+                }
+                self.write_data(entry, &log);
+                (0, STATUS_SUCCESS)
+            }
+            // Delete I/O Completion Queue
+            0x04 => {
+                if self.sub_queues.values().any(|sq| sq.cq_id == queue_id) {
+                    return (0, STATUS_INVALID_QUEUE_DELETION);
+                }
+                match queue_id != 0 && self.comp_queues.remove(&queue_id).is_some() {
+                    true => (0, STATUS_SUCCESS),
+                    false => (0, STATUS_INVALID_QID),
+                }
+            }
+            // Create I/O Completion Queue
+            0x05 => {
+                if !self.valid_io_queue(queue_id) || self.comp_queues.contains_key(&queue_id) {
+                    return (0, STATUS_INVALID_QID);
+                }
+                let mut queue = Queue::new(entry.prp.0, queue_size, queue_id);
+                queue.contiguous = cdw11 & 1 != 0;
+                self.comp_queues.insert(queue_id, queue);
+                (0, STATUS_SUCCESS)
+            }
+            // Identify
+            0x06 => match self.identify(cdw10 as u8, entry.ns_id) {
+                Some(page) => {
+                    self.write_data(entry, &page);
+                    (0, STATUS_SUCCESS)
+                }
+                None => (0, STATUS_INVALID_FIELD),
+            },
+            // Set Features: Number of Queues
+            0x09 if cdw10 as u8 == 0x07 => {
+                let granted = (cdw11 & 0xFFFF).min(MAX_IO_QUEUES) * 0x1_0001;
+                self.features.insert(0x07, granted);
+                (granted, STATUS_SUCCESS)
+            }
+            // Set Features: Timestamp, which is set by the host from now on
+            0x09 if cdw10 as u8 == 0x0E => {
+                let data = self.read_data(entry, 8);
+                self.timestamp = u64::from_le_bytes(data.try_into().unwrap()) | 1 << 49;
+                (0, STATUS_SUCCESS)
+            }
+            // Set Features
+            0x09 => {
+                self.features.insert(cdw10 as u8, cdw11);
+                (cdw11, STATUS_SUCCESS)
+            }
+            // Get Features
+            0x0A => match (cdw10 >> 8) & 0x7 {
+                3 => (0b101, STATUS_SUCCESS),
+                _ if cdw10 as u8 == 0x0E => {
+                    self.write_data(entry, &self.timestamp.to_le_bytes());
+                    (0, STATUS_SUCCESS)
+                }
+                _ => {
+                    let value = self.features.get(&(cdw10 as u8));
+                    (value.copied().unwrap_or_default(), STATUS_SUCCESS)
+                }
+            },
+            // Abort, commands are executed synchronously and never aborted
+            0x08 => (1, STATUS_SUCCESS),
+            // Format NVM, which also erases the disk
+            0x80 => {
+                if entry.ns_id != NAMESPACE_ID && entry.ns_id != 0xFFFF_FFFF {
+                    return (0, STATUS_INVALID_NAMESPACE);
+                }
+                let Some(&size) = self.lba_formats.get(cdw10 as usize & 0xF) else {
+                    return (0, STATUS_INVALID_FORMAT);
+                };
+                self.lba_format = cdw10 as usize & 0xF;
+                self.block_size = size;
+                self.disk.fill(0);
+                (0, STATUS_SUCCESS)
+            }
+            // Firmware Commit and Firmware Image Download
+            0x10 | 0x11 => (0, STATUS_SUCCESS),
+            // Sanitize, which completes immediately
+            0x84 => {
+                self.disk.fill(0);
+                self.sanitized = true;
+                (0, STATUS_SUCCESS)
+            }
+            // Keep Alive
+            0x18 => (0, STATUS_SUCCESS),
+            // Doorbell Buffer Config
+            0x7C => {
+                self.shadow = Some(entry.prp);
+                (0, STATUS_SUCCESS)
+            }
+            // Device Self-test
+            0x14 => {
+                self.self_test = Some(cdw10 as u8 & 0xF);
+                (0, STATUS_SUCCESS)
+            }
+            _ => (0, STATUS_INVALID_OPCODE),
+        }
+    }
+
+    /// Checks whether `qid` is an I/O queue granted by Number of Queues.
+    fn valid_io_queue(&self, qid: u16) -> bool {
+        let granted = self
+            .features
+            .get(&0x07)
+            .map_or(0, |count| (count & 0xFFFF) + 1);
+        qid != 0 && (qid as u32) <= granted
+    }
+
+    /// Builds an identify data structure.
+    fn identify(&self, cns: u8, ns_id: u32) -> Option<Vec<u8>> {
+        if cns == 0x01
+            && let Some(page) = &self.identify_controller
+        {
+            return Some(page.clone());
+        }
+
+        let mut page = vec![0u8; 4096];
+        let put = |page: &mut Vec<u8>, offset: usize, bytes: &[u8]| {
+            page[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+
+        match cns {
+            // Namespace
+            0x00 if ns_id == NAMESPACE_ID => {
+                let blocks = (self.disk.len() / self.block_size) as u64;
+                put(&mut page, 0, &blocks.to_le_bytes());
+                put(&mut page, 8, &blocks.to_le_bytes());
+                put(&mut page, 16, &blocks.to_le_bytes());
+                // NSFEAT = OPTPERF, NOIOB = 256, NPWG = NPWA = 8 blocks
+                page[24] = 1 << 4;
+                put(&mut page, 46, &256u16.to_le_bytes());
+                put(&mut page, 64, &7u16.to_le_bytes());
+                put(&mut page, 66, &7u16.to_le_bytes());
+                page[99] = self.write_protected as u8;
+                page[25] = self.lba_formats.len() as u8 - 1;
+                page[26] = self.lba_format as u8;
+                for (index, size) in self.lba_formats.iter().enumerate() {
+                    let lbads = size.trailing_zeros();
+                    put(&mut page, 128 + index * 4, &(lbads << 16).to_le_bytes());
+                }
+            }
+            0x00 => {}
+            // Controller
+            0x01 => {
+                put(&mut page, 0, &0x1B36u16.to_le_bytes());
+                put(&mut page, 2, &0x1AF4u16.to_le_bytes());
+                let pad = |text: &str, len: usize| {
+                    let mut bytes = vec![b' '; len];
+                    bytes[..text.len()].copy_from_slice(text.as_bytes());
+                    bytes
+                };
+                put(&mut page, 4, &pad("MOCK0001", 20));
+                put(&mut page, 24, &pad("nvme-rs mock controller", 40));
+                put(&mut page, 64, &pad("1.0", 8));
+                // MDTS = 128K
+                page[77] = 5;
+                // CNTLID
+                put(&mut page, 78, &1u16.to_le_bytes());
+                // ACL = 4 outstanding aborts
+                page[258] = 3;
+                // AERL = 4 outstanding requests
+                page[259] = 3;
+                // LPA = Extended data and telemetry log pages
+                page[261] = 0b1100;
+                put(&mut page, 80, &self.version.to_le_bytes());
+                // OACS = Format NVM, Firmware, Device Self-test
+                // and Doorbell Buffer Config
+                put(&mut page, 256, &0b1_0001_0110u16.to_le_bytes());
+                // KAS = 100 ms
+                put(&mut page, 320, &1u16.to_le_bytes());
+                // SANICAP = Crypto Erase, Block Erase and Overwrite
+                put(&mut page, 328, &0b111u32.to_le_bytes());
+                // SGLS = SGLs without alignment requirement
+                put(&mut page, 536, &1u32.to_le_bytes());
+                // SQES and CQES
+                page[512] = 0x66;
+                page[513] = 0x44;
+                put(&mut page, 516, &NAMESPACE_ID.to_le_bytes());
+                // ONCS = Compare, Dataset Management, Write Zeroes, Timestamp
+                put(&mut page, 520, &0b100_1101u16.to_le_bytes());
+                // FUSES = Compare and Write
+                put(&mut page, 522, &1u16.to_le_bytes());
+                // NPSS = 2 power states: 25 W, and 0.05 W non-operational
+                page[263] = 1;
+                put(&mut page, 2048, &2500u16.to_le_bytes());
+                put(&mut page, 2080, &500u16.to_le_bytes());
+                page[2083] = 0b11;
+                put(&mut page, 2084, &5000u32.to_le_bytes());
+                put(&mut page, 2088, &10000u32.to_le_bytes());
+            }
+            // Active and Allocated Namespace ID lists
+            0x02 | 0x10 => {
+                if ns_id < NAMESPACE_ID {
+                    put(&mut page, 0, &NAMESPACE_ID.to_le_bytes());
+                }
+            }
+            // Namespace Identification Descriptor list
+            0x03 if ns_id == NAMESPACE_ID => {
+                put(&mut page, 0, &[1, 8, 0, 0]);
+                put(&mut page, 4, &MOCK_EUI64);
+                put(&mut page, 12, &[3, 16, 0, 0]);
+                put(&mut page, 16, &MOCK_UUID);
+                if self.zone_size != 0 {
+                    put(&mut page, 32, &[4, 1, 0, 0, 2]);
+                }
+            }
+            _ => return None,
+        }
+
+        Some(page)
+    }
+
+    /// Executes an I/O command, returning dword 0 and the status.
+    fn execute_io(&mut self, entry: &Entry) -> (u32, u16) {
+        if entry.ns_id != NAMESPACE_ID {
+            return (0, STATUS_INVALID_NAMESPACE);
+        }
+
+        let [cdw10, cdw11, cdw12, cdw13, ..] = entry.cdw;
+        let lba = cdw10 as u64 | (cdw11 as u64) << 32;
+        let blocks = (cdw12 & 0xFFFF) as u64 + 1;
+        let range = || {
+            let disk_blocks = (self.disk.len() / self.block_size) as u64;
+            let end = lba.checked_add(blocks).filter(|&end| end <= disk_blocks)?;
+            Some(lba as usize * self.block_size..end as usize * self.block_size)
+        };
+
+        match entry.opcode {
+            // Flush
+            0x00 => (0, STATUS_SUCCESS),
+            // Write
+            0x01 => match range() {
+                Some(range) => {
+                    let data = self.read_data(entry, range.len());
+                    self.disk[range].copy_from_slice(&data);
+                    self.advance_write_pointer(lba, blocks);
+                    (0, STATUS_SUCCESS)
+                }
+                None => (0, STATUS_LBA_OUT_OF_RANGE),
+            },
+            // Read
+            0x02 => match range() {
+                Some(range) => {
+                    let data = self.disk[range].to_vec();
+                    self.write_data(entry, &data);
+                    (0, STATUS_SUCCESS)
+                }
+                None => (0, STATUS_LBA_OUT_OF_RANGE),
+            },
+            // Dataset Management, deallocated blocks read as zeroes
+            0x09 => {
+                let count = (cdw10 & 0xFF) as usize + 1;
+                let ranges = self.read_data(entry, count * 16);
+                let disk_blocks = (self.disk.len() / self.block_size) as u64;
+                for range in ranges.chunks_exact(16) {
+                    let length = u32::from_le_bytes(range[4..8].try_into().unwrap()) as u64;
+                    let lba = u64::from_le_bytes(range[8..].try_into().unwrap());
+                    if lba.checked_add(length).is_none_or(|end| end > disk_blocks) {
+                        return (0, STATUS_LBA_OUT_OF_RANGE);
+                    }
+                }
+                if cdw11 & (1 << 2) != 0 {
+                    for range in ranges.chunks_exact(16) {
+                        let length = u32::from_le_bytes(range[4..8].try_into().unwrap()) as usize;
+                        let lba = u64::from_le_bytes(range[8..].try_into().unwrap()) as usize;
+                        let start = lba * self.block_size;
+                        self.disk[start..start + length * self.block_size].fill(0);
+                    }
+                }
+                (0, STATUS_SUCCESS)
+            }
+            // Compare
+            0x05 => match range() {
+                Some(range) => match self.read_data(entry, range.len()) == self.disk[range] {
+                    true => (0, STATUS_SUCCESS),
+                    false => (0, STATUS_COMPARE_FAILURE),
+                },
+                None => (0, STATUS_LBA_OUT_OF_RANGE),
+            },
+            // Write Zeroes
+            0x08 => match range() {
+                Some(range) => {
+                    self.disk[range].fill(0);
+                    (0, STATUS_SUCCESS)
+                }
+                None => (0, STATUS_LBA_OUT_OF_RANGE),
+            },
+            // Zone Management Send: Close, Finish, Open and Reset
+            0x79 if self.zone_size != 0 => {
+                let action = cdw13 as u8;
+                if cdw13 & (1 << 8) != 0 {
+                    for index in 0..self.zones.len() {
+                        // Zones in other states are skipped.
+                        let _ = self.transition_zone(index, action);
+                    }
+                    return (0, STATUS_SUCCESS);
+                }
+                if !lba.is_multiple_of(self.zone_size) {
+                    return (0, STATUS_INVALID_FIELD);
+                }
+                let index = (lba / self.zone_size) as usize;
+                if index >= self.zones.len() {
+                    return (0, STATUS_LBA_OUT_OF_RANGE);
+                }
+                match self.transition_zone(index, action) {
+                    Some(()) => (0, STATUS_SUCCESS),
+                    None => (0, STATUS_INVALID_ZONE_STATE_TRANSITION),
+                }
+            }
+            // Zone Management Receive: Report Zones
+            0x7A if self.zone_size != 0 && cdw13 & 0xFF == 0 => {
+                let len = (cdw12 as usize + 1) * 4;
+                let first = (lba / self.zone_size) as usize;
+                let zones = self.zones.get(first..).unwrap_or_default();
+                let count = zones.len().min(len / 64 - 1);
+
+                let mut data = vec![0u8; len];
+                // Without a partial report, all zones from `first` on are counted.
+                let reported = if cdw13 & (1 << 16) != 0 {
+                    count
+                } else {
+                    zones.len()
+                };
+                data[..8].copy_from_slice(&(reported as u64).to_le_bytes());
+                for (index, &(state, write_pointer)) in zones.iter().take(count).enumerate() {
+                    let descriptor = &mut data[64 + index * 64..128 + index * 64];
+                    let start = (first + index) as u64 * self.zone_size;
+                    descriptor[0] = 0x2;
+                    descriptor[1] = state << 4;
+                    descriptor[8..16].copy_from_slice(&self.zone_size.to_le_bytes());
+                    descriptor[16..24].copy_from_slice(&start.to_le_bytes());
+                    descriptor[24..32].copy_from_slice(&write_pointer.to_le_bytes());
+                }
+                self.write_data(entry, &data);
+                (0, STATUS_SUCCESS)
+            }
+            // Zone Append, the assigned LBA fits into dword 0
+            0x7D if self.zone_size != 0 => {
+                let Some(&(_, write_pointer)) = self.zones.get((lba / self.zone_size) as usize)
+                else {
+                    return (0, STATUS_LBA_OUT_OF_RANGE);
+                };
+                if !lba.is_multiple_of(self.zone_size) {
+                    return (0, STATUS_INVALID_FIELD);
+                }
+                if write_pointer + blocks > lba + self.zone_size {
+                    return (0, STATUS_ZONE_BOUNDARY_ERROR);
+                }
+                let start = write_pointer as usize * self.block_size;
+                let data = self.read_data(entry, blocks as usize * self.block_size);
+                self.disk[start..start + data.len()].copy_from_slice(&data);
+                self.advance_write_pointer(write_pointer, blocks);
+                (write_pointer as u32, STATUS_SUCCESS)
+            }
+            _ => (0, STATUS_INVALID_OPCODE),
+        }
+    }
+
+    /// Applies a zone send action to a zone, if its state allows it.
+    fn transition_zone(&mut self, index: usize, action: u8) -> Option<()> {
+        let start = index as u64 * self.zone_size;
+        let end = start + self.zone_size;
+        let (state, write_pointer) = &mut self.zones[index];
+        let open = matches!(*state, ZONE_IMPLICITLY_OPENED | ZONE_EXPLICITLY_OPENED);
+        (*state, *write_pointer) = match action {
+            // Close
+            1 if open || *state == ZONE_CLOSED => (ZONE_CLOSED, *write_pointer),
+            // Finish
+            2 if open || matches!(*state, ZONE_EMPTY | ZONE_CLOSED | ZONE_FULL) => (ZONE_FULL, end),
+            // Open
+            3 if open || matches!(*state, ZONE_EMPTY | ZONE_CLOSED) => {
+                (ZONE_EXPLICITLY_OPENED, *write_pointer)
+            }
+            // Reset
+            4 if open || matches!(*state, ZONE_EMPTY | ZONE_CLOSED | ZONE_FULL) => {
+                (ZONE_EMPTY, start)
+            }
+            _ => return None,
+        };
+        Some(())
+    }
+
+    /// Advances the write pointer of a zone written at its write pointer.
+    fn advance_write_pointer(&mut self, lba: u64, blocks: u64) {
+        if self.zone_size == 0 {
+            return;
+        }
+        let zone_size = self.zone_size;
+        let Some((state, write_pointer)) = self.zones.get_mut((lba / zone_size) as usize) else {
+            return;
+        };
+        if *write_pointer == lba {
+            *write_pointer = (lba + blocks).min((lba / zone_size + 1) * zone_size);
+            let full = write_pointer.is_multiple_of(zone_size);
+            *state = if full {
+                ZONE_FULL
+            } else {
+                ZONE_IMPLICITLY_OPENED
+            };
+        }
+    }
+
+    /// Resolves the host memory segments described by a PRP pair.
+    fn prp_segments(&self, prp: (usize, usize), len: usize) -> Vec<(usize, usize)> {
+        let page_size = self.page_size();
+        let first = (page_size - (prp.0 & (page_size - 1))).min(len);
+        let mut segments = vec![(prp.0, first)];
+        let mut remaining = len - first;
+
+        if remaining == 0 {
+            return segments;
+        }
+        if remaining <= page_size {
+            segments.push((prp.1, remaining));
+            return segments;
+        }
+
+        let mut entry_addr = prp.1;
+        while remaining > 0 {
+            let value = unsafe { (entry_addr as *const u64).read_volatile() } as usize;
+            let last_in_page = (entry_addr + 8) & (page_size - 1) == 0;
+            if last_in_page && remaining > page_size {
+                entry_addr = value;
+                continue;
+            }
+            let size = remaining.min(page_size);
+            segments.push((value, size));
+            remaining -= size;
+            entry_addr += 8;
+        }
+
+        segments
+    }
+
+    /// Resolves the host memory segments described by an SGL descriptor.
+    fn sgl_segments(&self, descriptor: (usize, usize), len: usize) -> Vec<(usize, usize)> {
+        let (address, high) = descriptor;
+        let length = high & 0xFFFF_FFFF;
+        let segments = match high >> 60 {
+            // Data Block
+            0x0 => vec![(address, length)],
+            // Last Segment of Data Block descriptors
+            0x3 => (0..length / 16)
+                .map(|index| {
+                    let entry = unsafe { ((address + index * 16) as *const [u64; 2]).read() };
+                    (entry[0] as usize, entry[1] as usize & 0xFFFF_FFFF)
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        // Only the first `len` bytes are transferred.
+        let mut remaining = len;
+        segments
+            .into_iter()
+            .map(|(address, length)| {
+                let size = length.min(remaining);
+                remaining -= size;
+                (address, size)
+            })
+            .collect()
+    }
+
+    /// Resolves the host memory segments of the data of a command.
+    fn data_segments(&self, entry: &Entry, len: usize) -> Vec<(usize, usize)> {
+        match entry.sgl {
+            true => self.sgl_segments(entry.prp, len),
+            false => self.prp_segments(entry.prp, len),
+        }
+    }
+
+    /// Reads `len` bytes from host memory described by the data pointer.
+    fn read_data(&self, entry: &Entry, len: usize) -> Vec<u8> {
+        let mut data = Vec::with_capacity(len);
+        for (addr, size) in self.data_segments(entry, len) {
+            let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, size) };
+            data.extend_from_slice(bytes);
+        }
+        data
+    }
+
+    /// Writes `data` to host memory described by the data pointer.
+    fn write_data(&self, entry: &Entry, data: &[u8]) {
+        let mut offset = 0;
+        for (addr, size) in self.data_segments(entry, data.len()) {
+            let src = data[offset..offset + size].as_ptr();
+            unsafe { core::ptr::copy_nonoverlapping(src, addr as *mut u8, size) };
+            offset += size;
+        }
+    }
+}
+
+/// Reads a submission queue entry from host memory.
+fn read_entry(addr: usize) -> Entry {
+    let dwords = unsafe { (addr as *const [u32; 16]).read_volatile() };
+    Entry {
+        opcode: dwords[0] as u8,
+        fuse: (dwords[0] >> 8) as u8 & 0x3,
+        cmd_id: (dwords[0] >> 16) as u16,
+        ns_id: dwords[1],
+        sgl: (dwords[0] >> 14) & 0x3 != 0,
+        prp: (
+            (dwords[6] as u64 | (dwords[7] as u64) << 32) as usize,
+            (dwords[8] as u64 | (dwords[9] as u64) << 32) as usize,
+        ),
+        cdw: [
+            dwords[10], dwords[11], dwords[12], dwords[13], dwords[14], dwords[15],
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{Dma, IdentityAllocator};
+
+    #[test]
+    fn identify() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+        assert_eq!(
+            device.controller_data().model_number,
+            "nvme-rs mock controller"
+        );
+
+        let namespaces = device.identify_namespaces(0).unwrap();
+        assert_eq!(namespaces.len(), 1);
+        assert_eq!(namespaces[0].id(), NAMESPACE_ID);
+        assert_eq!(namespaces[0].block_count(), 1024);
+        assert_eq!(namespaces[0].block_size(), 512);
+    }
+
+    #[test]
+    fn create_and_delete_queue_pair() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+        let namespace = device.identify_namespaces(0).unwrap().remove(0);
+
+        let qpair = device.create_io_queue_pair(namespace, 64, 0, None).unwrap();
+        assert_eq!(mock.io_queue_count(), 1);
+        device.delete_io_queue_pair(qpair).unwrap();
+        assert_eq!(mock.io_queue_count(), 0);
+    }
+
+    #[test]
+    fn write_and_read() {
+        let mock = MockController::new(1024, 512);
+        let mut device = mock.device();
+        let namespace = device.identify_namespaces(0).unwrap().remove(0);
+        let mut qpair = device.create_io_queue_pair(namespace, 64, 0, None).unwrap();
+
+        let memory = IdentityAllocator(MockMemory::default());
+        let mut data = Dma::<u8>::allocate_zeroed(8192, &memory);
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+        qpair.write_blocks(&data, 8).unwrap();
+        assert_eq!(mock.disk()[8 * 512..8 * 512 + 8192], data[..]);
+
+        let mut read = Dma::<u8>::allocate_zeroed(8192, &memory);
+        qpair.read_blocks(&mut read, 8).unwrap();
+        assert_eq!(read[..], data[..]);
+        device.delete_io_queue_pair(qpair).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use alloc::vec::Vec;
+
+use crate::cmd::{Command, NamespaceList};
+use crate::device::{Device, NAMESPACE_LIST_SIZE};
+use crate::error::{Error, Result};
+use crate::memory::Allocator;
+
+/// Namespace Management select: Create.
+const SELECT_CREATE: u8 = 0;
+/// Namespace Management select: Delete.
+const SELECT_DELETE: u8 = 1;
+/// Namespace Attachment select: Controller Attach.
+const SELECT_ATTACH: u8 = 0;
+/// Namespace Attachment select: Controller Detach.
+const SELECT_DETACH: u8 = 1;
+
+/// Log page identifier of the Changed Namespace List.
+const LOG_CHANGED_NAMESPACES: u8 = 0x04;
+/// The first entry of a Changed Namespace List with more than 1024 changes.
+const CHANGED_NAMESPACES_OVERFLOW: u32 = 0xFFFF_FFFF;
+
+/// The properties of a namespace created by `Device::create_namespace`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NamespaceSpec {
+    /// The size of the namespace (in blocks)
+    pub size_blocks: u64,
+    /// The capacity of the namespace (in blocks)
+    ///
+    /// It is less than the size for a thin provisioned namespace.
+    pub capacity_blocks: u64,
+    /// The LBA format of the namespace, an index into the
+    /// LBA formats supported by the controller
+    pub lba_format_index: u8,
+    /// The namespace may be attached to multiple controllers
+    pub shared: bool,
+}
+
+impl<A: Allocator> Device<A> {
+    /// Create a namespace, returning its ID.
+    ///
+    /// The block size of the namespace is given by the LBA format, so the
+    /// size and capacity are in blocks of that size. The new namespace is
+    /// not attached to any controller yet, see `attach_namespace`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::FeatureNotSupported` if the controller does not
+    /// support namespace management.
+    pub fn create_namespace(&mut self, spec: NamespaceSpec) -> Result<u32> {
+        self.check_namespace_management()?;
+
+        // The fields of the Identify Namespace data structure set by the host.
+        self.admin_buffer.fill(0);
+        self.admin_buffer[0..8].copy_from_slice(&spec.size_blocks.to_le_bytes());
+        self.admin_buffer[8..16].copy_from_slice(&spec.capacity_blocks.to_le_bytes());
+        // The upper bits of the format index are placed in bits 6:5 of FLBAS.
+        let index = spec.lba_format_index;
+        self.admin_buffer[26] = (index & 0xF) | ((index >> 4) & 0x3) << 5;
+        self.admin_buffer[30] = spec.shared as u8;
+
+        let cmd_id = self.next_cmd_id();
+        let completion = self.exec_admin(Command::namespace_management(
+            cmd_id,
+            0,
+            SELECT_CREATE,
+            self.admin_buffer.phys_addr,
+        ))?;
+        Ok(completion.result())
+    }
+
+    /// Delete the namespace `ns_id`.
+    ///
+    /// The namespace is detached from all controllers and its data is lost.
+    /// Queue pairs of the namespace must not be used anymore.
+    pub fn delete_namespace(&mut self, ns_id: u32) -> Result<()> {
+        self.check_namespace_management()?;
+        let cmd_id = self.next_cmd_id();
+        self.exec_admin(Command::namespace_management(
+            cmd_id,
+            ns_id,
+            SELECT_DELETE,
+            0,
+        ))?;
+        Ok(())
+    }
+
+    /// Attach the namespace `ns_id` to this controller.
+    ///
+    /// Afterwards it is reported by `identify_namespaces`.
+    pub fn attach_namespace(&mut self, ns_id: u32) -> Result<()> {
+        self.namespace_attachment(ns_id, SELECT_ATTACH)
+    }
+
+    /// Detach the namespace `ns_id` from this controller.
+    pub fn detach_namespace(&mut self, ns_id: u32) -> Result<()> {
+        self.namespace_attachment(ns_id, SELECT_DETACH)
+    }
+
+    /// Get the IDs of the namespaces changed since the list was last read,
+    /// e.g. after a namespace attribute changed event.
+    ///
+    /// Reading the list clears it and unmasks further namespace change
+    /// events. If more than 1024 namespaces changed, the controller does not
+    /// list them, and the IDs of all active namespaces are returned instead
+    /// so that every namespace is scanned again.
+    pub fn changed_namespaces(&mut self) -> Result<Vec<u32>> {
+        let mut log = [0u8; NAMESPACE_LIST_SIZE * 4];
+        self.get_log_page(LOG_CHANGED_NAMESPACES, 0, 0, &mut log)?;
+
+        let ids: Vec<u32> = log
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+            .take_while(|&id| id != 0)
+            .collect();
+        if ids.first() != Some(&CHANGED_NAMESPACES_OVERFLOW) {
+            return Ok(ids);
+        }
+
+        let mut ids = Vec::new();
+        loop {
+            let base = ids.last().copied().unwrap_or_default();
+            let list = self.namespace_list(NamespaceList::Active, base)?;
+            // A list which is not full is the last one.
+            let done = list.len() < NAMESPACE_LIST_SIZE;
+            ids.extend(list);
+            if done {
+                return Ok(ids);
+            }
+        }
+    }
+
+    /// Attach or detach a namespace to or from this controller.
+    fn namespace_attachment(&mut self, ns_id: u32, select: u8) -> Result<()> {
+        self.check_namespace_management()?;
+
+        // A controller list with the ID of this controller only.
+        let controller_id = self.controller_data().controller_id;
+        self.admin_buffer.fill(0);
+        self.admin_buffer[0..2].copy_from_slice(&1u16.to_le_bytes());
+        self.admin_buffer[2..4].copy_from_slice(&controller_id.to_le_bytes());
+
+        let cmd_id = self.next_cmd_id();
+        self.exec_admin(Command::namespace_attachment(
+            cmd_id,
+            ns_id,
+            select,
+            self.admin_buffer.phys_addr,
+        ))?;
+        Ok(())
+    }
+
+    /// Checks that the controller supports namespace management.
+    fn check_namespace_management(&self) -> Result<()> {
+        if !self.controller_data().supports_namespace_management() {
+            return Err(Error::FeatureNotSupported);
+        }
+        Ok(())
+    }
+}
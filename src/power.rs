@@ -0,0 +1,62 @@
+use crate::device::Device;
+use crate::error::Result;
+use crate::features::FeatureSelect;
+use crate::memory::Allocator;
+
+/// Power Management feature identifier.
+const FEATURE_POWER_MANAGEMENT: u8 = 0x02;
+
+/// A power state supported by the controller.
+///
+/// Reported by the power state descriptors of Identify Controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerStateDescriptor {
+    /// Maximum power drawn in this state (in microwatts)
+    pub max_power_uw: u32,
+    /// Latency to enter this state (in microseconds)
+    pub entry_latency_us: u32,
+    /// Latency to leave this state (in microseconds)
+    pub exit_latency_us: u32,
+    /// No I/O command is processed in this state (NOPS)
+    ///
+    /// The controller leaves the state on its own once a command is submitted.
+    pub non_operational: bool,
+}
+
+impl PowerStateDescriptor {
+    /// Parses a 32-byte power state descriptor.
+    pub(crate) fn from_raw(raw: &[u8]) -> Self {
+        let max_power = u16::from_le_bytes([raw[0], raw[1]]) as u32;
+        let extract_u32 =
+            |start: usize| u32::from_le_bytes(raw[start..start + 4].try_into().unwrap());
+
+        Self {
+            // MXPS: the maximum power is in 0.0001 W units instead of 0.01 W.
+            max_power_uw: match raw[3] & 1 != 0 {
+                true => max_power * 100,
+                false => max_power * 10_000,
+            },
+            entry_latency_us: extract_u32(4),
+            exit_latency_us: extract_u32(8),
+            non_operational: raw[3] & (1 << 1) != 0,
+        }
+    }
+}
+
+impl<A: Allocator> Device<A> {
+    /// Put the controller into power state `ps`.
+    ///
+    /// The states are the indices of `power_states` in the controller data,
+    /// where 0 draws the most power. Entering a state takes its entry
+    /// latency, and leaving it the exit latency.
+    pub fn set_power_state(&mut self, ps: u8) -> Result<()> {
+        self.set_features(FEATURE_POWER_MANAGEMENT, ps as u32 & 0x1F, false, None)?;
+        Ok(())
+    }
+
+    /// Get the current power state of the controller.
+    pub fn power_state(&mut self) -> Result<u8> {
+        let value = self.get_features(FEATURE_POWER_MANAGEMENT, FeatureSelect::Current, None)?;
+        Ok(value as u8 & 0x1F)
+    }
+}
@@ -1,19 +1,95 @@
-use core::hint::spin_loop;
-
+use crate::cmb::Cmb;
 use crate::cmd::Command;
 use crate::error::{Error, Result};
 use crate::memory::{Allocator, Dma};
+use crate::status::Status;
+use crate::timeout::Timeout;
+use crate::waiter::{CompletionWaiter, PollingWaiter};
 
 /// Completion entry in the NVMe completion queue.
 #[derive(Debug, Clone)]
 #[repr(C, packed)]
-pub(crate) struct Completion {
-    command_specific: u32,
-    _rsvd: u32,
-    pub sq_head: u16,
+pub struct Completion {
+    pub(crate) command_specific: u32,
+    /// Dword 1, only used by some commands
+    pub(crate) command_specific_high: u32,
+    pub(crate) sq_head: u16,
     sq_id: u16,
     cmd_id: u16,
-    pub status: u16,
+    pub(crate) status: u16,
+}
+
+/// Number of polls of a completion queue between two checks of the controller status.
+const FATAL_CHECK_INTERVAL: usize = 1024;
+
+/// Generic status: Command Aborted due to Failed Fused Command.
+const STATUS_FUSED_ABORT: u16 = 0x09;
+
+/// Generic status: Namespace Not Ready.
+const STATUS_NAMESPACE_NOT_READY: u16 = 0x82;
+
+/// Command specific status: Zone Is Full.
+const STATUS_ZONE_FULL: u16 = 0x1B9;
+
+/// Command specific status: Zone Is Read Only.
+const STATUS_ZONE_READ_ONLY: u16 = 0x1BA;
+
+/// Command specific status: Zone Is Offline.
+const STATUS_ZONE_OFFLINE: u16 = 0x1BB;
+
+/// Command specific status: Too Many Active Zones.
+const STATUS_TOO_MANY_ACTIVE_ZONES: u16 = 0x1BD;
+
+/// Command specific status: Too Many Open Zones.
+const STATUS_TOO_MANY_OPEN_ZONES: u16 = 0x1BE;
+
+/// Command specific status: Invalid Zone State Transition.
+const STATUS_INVALID_ZONE_STATE_TRANSITION: u16 = 0x1BF;
+
+/// Media and data integrity error: Compare Failure.
+const STATUS_COMPARE_FAILURE: u16 = 0x285;
+
+impl Completion {
+    /// Get the command specific result (dword 0).
+    pub fn result(&self) -> u32 {
+        self.command_specific
+    }
+
+    /// Get the status field (without the phase tag).
+    pub fn status(&self) -> u16 {
+        self.status >> 1
+    }
+
+    /// Get the command identifier.
+    pub fn cmd_id(&self) -> u16 {
+        self.cmd_id
+    }
+
+    /// Checks whether the command was aborted because the other
+    /// command of its fused operation failed.
+    pub(crate) fn is_fused_abort(&self) -> bool {
+        (self.status >> 1) & 0x7ff == STATUS_FUSED_ABORT
+    }
+
+    /// Decodes the status field of the completion.
+    ///
+    /// Returns `Ok(())` if the command succeeded, otherwise an error
+    /// describing the failure.
+    pub(crate) fn check(&self) -> Result<()> {
+        let status = (self.status >> 1) & 0x7ff;
+        match status {
+            0 => Ok(()),
+            STATUS_NAMESPACE_NOT_READY => Err(Error::NamespaceNotReady),
+            STATUS_COMPARE_FAILURE => Err(Error::CompareMismatch),
+            STATUS_ZONE_FULL => Err(Error::ZoneFull),
+            STATUS_ZONE_READ_ONLY => Err(Error::ZoneReadOnly),
+            STATUS_ZONE_OFFLINE => Err(Error::ZoneOffline),
+            STATUS_TOO_MANY_ACTIVE_ZONES => Err(Error::TooManyActiveZones),
+            STATUS_TOO_MANY_OPEN_ZONES => Err(Error::TooManyOpenZones),
+            STATUS_INVALID_ZONE_STATE_TRANSITION => Err(Error::InvalidZoneStateTransition),
+            _ => Err(Status::from_raw(self.status >> 1).into()),
+        }
+    }
 }
 
 /// Represents an NVMe submission queue.
@@ -27,6 +103,8 @@ pub(crate) struct SubQueue {
     pub head: usize,
     /// Current tail position of the queue
     pub tail: usize,
+    /// The PRP list describing the slots if they are not physically contiguous
+    pub prp_list: Option<Dma<u64>>,
 }
 
 impl SubQueue {
@@ -34,25 +112,65 @@ impl SubQueue {
     ///
     /// The allocator should implement the `Allocator` trait.
     pub fn new<A: Allocator>(len: usize, allocator: &A) -> Self {
+        Self::new_aligned(len, 4096, allocator)
+    }
+
+    /// Creates a new submission queue aligned to `align` bytes,
+    /// e.g. to the memory page size of the controller.
+    pub fn new_aligned<A: Allocator>(len: usize, align: usize, allocator: &A) -> Self {
         Self {
-            data: Dma::allocate(len, allocator),
+            data: Dma::allocate_aligned(len, align, allocator),
             head: 0,
             tail: 0,
+            prp_list: None,
         }
     }
 
-    /// Pushes a command to the submission queue
+    /// Resets the queue to its initial empty state.
+    pub fn reset(&mut self) {
+        self.head = 0;
+        self.tail = 0;
+    }
+
+    /// Get the address of the queue for the Create I/O Submission Queue command.
     ///
-    /// It blocks until there is space available in the queue.
-    pub fn push(&mut self, entry: Command) -> usize {
-        loop {
-            if let Ok(tail) = self.try_push(entry) {
-                return tail;
-            }
-            spin_loop();
+    /// It is the PRP list if the slots are not physically contiguous.
+    pub fn address(&self) -> usize {
+        self.prp_list
+            .as_ref()
+            .map_or(self.data.phys_addr, |list| list.phys_addr)
+    }
+
+    /// Releases the slots, which may be in the CMB, and the PRP list of the queue.
+    pub fn free<A: Allocator>(&self, cmb: Option<&mut Cmb>, allocator: &A) {
+        if !cmb.is_some_and(|cmb| cmb.release(self.data.addr as usize)) {
+            self.data.deallocate(allocator);
+        }
+        if let Some(list) = &self.prp_list {
+            list.deallocate(allocator);
         }
     }
 
+    /// Checks whether `head` reported by a completion is plausible.
+    ///
+    /// The controller can only consume entries between the current head
+    /// and tail, so any other value means the completion queue was overrun
+    /// or the completion is stale.
+    pub fn is_valid_head(&self, head: usize) -> bool {
+        let count = self.data.count;
+        let consumed = (head + count - self.head) % count;
+        let pending = (self.tail + count - self.head) % count;
+        head < count && consumed <= pending
+    }
+
+    /// Get the number of free slots in the queue.
+    ///
+    /// One slot is always left empty to tell a full queue from an empty one.
+    pub fn available(&self) -> usize {
+        let count = self.data.count;
+        count - 1 - (self.tail + count - self.head) % count
+    }
+
     /// Attempts to push a command to the submission queue.
     ///
     /// It does not block if the queue is full.
@@ -78,6 +196,8 @@ pub(crate) struct CompQueue {
     pub head: usize,
     /// Used to determine if an entry is valid
     pub phase: bool,
+    /// The PRP list describing the slots if they are not physically contiguous
+    pub prp_list: Option<Dma<u64>>,
 }
 
 impl CompQueue {
@@ -85,35 +205,85 @@ impl CompQueue {
     ///
     /// The allocator should implement the `Allocator` trait.
     pub fn new<A: Allocator>(len: usize, allocator: &A) -> Self {
+        Self::new_aligned(len, 4096, allocator)
+    }
+
+    /// Creates a new completion queue aligned to `align` bytes,
+    /// e.g. to the memory page size of the controller.
+    pub fn new_aligned<A: Allocator>(len: usize, align: usize, allocator: &A) -> Self {
         Self {
-            data: Dma::allocate(len, allocator),
+            data: Dma::allocate_aligned(len, align, allocator),
             head: 0,
             phase: true,
+            prp_list: None,
         }
     }
 
-    /// Pops a completion entry from the queue.
+    /// Resets the queue to its initial empty state.
     ///
-    /// It blocks until there is a valid entry available.
-    pub fn pop(&mut self) -> (usize, Completion) {
-        loop {
-            if let Some(val) = self.try_pop() {
-                return val;
-            }
-            spin_loop();
+    /// Stale entries are cleared, otherwise their phase tags
+    /// could be mistaken for new completions.
+    pub fn reset(&mut self) {
+        unsafe { core::ptr::write_bytes(self.data.addr, 0, self.data.count) };
+        self.head = 0;
+        self.phase = true;
+    }
+
+    /// Get the address of the queue for the Create I/O Completion Queue command.
+    ///
+    /// It is the PRP list if the slots are not physically contiguous.
+    pub fn address(&self) -> usize {
+        self.prp_list
+            .as_ref()
+            .map_or(self.data.phys_addr, |list| list.phys_addr)
+    }
+
+    /// Releases the slots and the PRP list of the queue.
+    pub fn free<A: Allocator>(&self, allocator: &A) {
+        self.data.deallocate(allocator);
+        if let Some(list) = &self.prp_list {
+            list.deallocate(allocator);
         }
     }
 
-    /// Pops a step of completion entries from the queue.
+    /// Pops a completion entry from the queue.
+    ///
+    /// It blocks until there is a valid entry available
+    /// or returns `Error::ControllerTimeout` once the `timeout` expires.
+    /// While waiting, `is_fatal` is checked every now and then, and
+    /// `Error::ControllerFatal` is returned once it reports a fatal
+    /// controller status, as the entry would never arrive.
+    pub fn pop(
+        &mut self,
+        timeout: &mut Timeout,
+        is_fatal: &dyn Fn() -> bool,
+    ) -> Result<(usize, Completion)> {
+        self.pop_with(timeout, &PollingWaiter, is_fatal)
+    }
+
+    /// Pops a completion entry from the queue using `waiter` to wait.
     ///
-    /// It returns the final head position and the completion entry.
-    pub fn pop_n(&mut self, step: usize) -> (usize, Completion) {
-        self.head += step - 1;
-        if self.head >= self.data.count {
-            self.phase = !self.phase;
+    /// See `pop` for more details.
+    pub fn pop_with(
+        &mut self,
+        timeout: &mut Timeout,
+        waiter: &dyn CompletionWaiter,
+        is_fatal: &dyn Fn() -> bool,
+    ) -> Result<(usize, Completion)> {
+        let mut polls = 0usize;
+        loop {
+            if let Some(val) = self.try_pop() {
+                return Ok(val);
+            }
+            // Reading the controller status is slow, so it is not done on every poll.
+            polls = polls.wrapping_add(1);
+            let expired = timeout.tick();
+            if (expired.is_err() || polls.is_multiple_of(FATAL_CHECK_INTERVAL)) && is_fatal() {
+                return Err(Error::ControllerFatal);
+            }
+            expired?;
+            waiter.wait();
         }
-        self.head %= self.data.count;
-        self.pop()
     }
 
     /// Attempts to pop a completion entry from the queue.
@@ -133,3 +303,134 @@ impl CompQueue {
         })
     }
 }
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use super::*;
+    use crate::memory::IdentityAllocator;
+    use crate::mock::MockMemory;
+
+    /// Number of random queues driven by each property test.
+    const ROUNDS: usize = 200;
+
+    /// A xorshift generator, so that failures are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        /// A number in `low..=high`.
+        fn range(&mut self, low: usize, high: usize) -> usize {
+            low + (self.next() % (high - low + 1) as u64) as usize
+        }
+    }
+
+    /// The completion the controller posts for the `id`th command.
+    fn completion(id: usize, phase: bool) -> Completion {
+        Completion {
+            command_specific: 0,
+            command_specific_high: 0,
+            sq_head: 0,
+            sq_id: 0,
+            cmd_id: id as u16,
+            status: phase as u16,
+        }
+    }
+
+    #[test]
+    fn completion_phase_across_wraps() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        let allocator = IdentityAllocator(MockMemory::default());
+        for _ in 0..ROUNDS {
+            let count = rng.range(2, 64);
+            let mut queue = CompQueue::new(count, &allocator);
+            queue.reset();
+
+            // The controller side, and the reference model of the host side.
+            let (mut tail, mut tail_phase, mut posted) = (0, true, 0);
+            let (mut head, mut phase, mut popped) = (0, true, 0);
+            while posted < 8 * count {
+                // A batch of posts, of pops, or both interleaved, which may
+                // cross the end of the ring several times.
+                let mode = rng.range(0, 2);
+                for _ in 0..rng.range(1, 3 * count) {
+                    if mode != 1 && posted - popped < count {
+                        queue.data[tail] = completion(posted, tail_phase);
+                        posted += 1;
+                        tail = (tail + 1) % count;
+                        tail_phase ^= tail == 0;
+                    }
+                    if mode != 0 {
+                        let entry = queue.try_pop();
+                        if popped == posted {
+                            assert!(entry.is_none(), "stale completion popped");
+                            continue;
+                        }
+                        head = (head + 1) % count;
+                        phase ^= head == 0;
+                        let (new_head, entry) = entry.expect("posted completion not popped");
+                        assert_eq!(new_head, head);
+                        assert_eq!(entry.cmd_id() as usize, popped);
+                        popped += 1;
+                    }
+                    assert_eq!(queue.head, head);
+                    assert_eq!(queue.phase, phase);
+                }
+            }
+            while popped < posted {
+                let (_, entry) = queue.try_pop().unwrap();
+                assert_eq!(entry.cmd_id() as usize, popped);
+                popped += 1;
+            }
+            assert!(queue.try_pop().is_none());
+            queue.data.deallocate(&allocator);
+        }
+    }
+
+    #[test]
+    fn submission_slots_across_wraps() {
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        let allocator = IdentityAllocator(MockMemory::default());
+        for _ in 0..ROUNDS {
+            let count = rng.range(2, 64);
+            let mut queue = SubQueue::new(count, &allocator);
+
+            // The reference model counts the commands pushed and consumed.
+            let (mut pushed, mut consumed) = (0, 0);
+            while consumed < 8 * count {
+                if rng.range(0, 1) == 0 {
+                    for _ in 0..rng.range(1, 2 * count) {
+                        let result = queue.try_push(Command::flush(pushed as u16, 1));
+                        if pushed - consumed == count - 1 {
+                            assert!(matches!(result, Err(Error::SubQueueFull)));
+                        } else {
+                            assert_eq!(queue.data[pushed % count].cmd_id(), pushed as u16);
+                            pushed += 1;
+                            assert_eq!(result.unwrap(), pushed % count);
+                        }
+                    }
+                } else {
+                    // The controller consumes some of the pending commands.
+                    consumed += rng.range(0, pushed - consumed);
+                    let head = consumed % count;
+                    assert!(queue.is_valid_head(head));
+                    queue.head = head;
+                }
+                let pending = pushed - consumed;
+                assert_eq!(queue.tail, pushed % count);
+                assert_eq!(queue.available(), count - 1 - pending);
+                // Only the heads up to the tail can be reported.
+                for ahead in 0..count {
+                    let head = (consumed + ahead) % count;
+                    assert_eq!(queue.is_valid_head(head), ahead <= pending);
+                }
+            }
+            queue.data.deallocate(&allocator);
+        }
+    }
+}
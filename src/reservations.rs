@@ -0,0 +1,205 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::cmd::Command;
+use crate::error::{Error, Result};
+use crate::io::IoQueuePair;
+use crate::memory::Allocator;
+
+/// Size of the header of the Reservation Status data structure (in bytes).
+const STATUS_HEADER_SIZE: usize = 24;
+
+/// Size of a Registered Controller data structure (in bytes).
+const REGISTERED_CONTROLLER_SIZE: usize = 24;
+
+/// Maximum number of registered controllers read by `reservation_report`.
+const MAX_REGISTERED_CONTROLLERS: usize = 64;
+
+/// The type of a reservation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationType {
+    /// Only the holder may write
+    WriteExclusive = 1,
+    /// Only the holder may read or write
+    ExclusiveAccess = 2,
+    /// Only registrants may write, there is a single holder
+    WriteExclusiveRegistrantsOnly = 3,
+    /// Only registrants may read or write, there is a single holder
+    ExclusiveAccessRegistrantsOnly = 4,
+    /// Only registrants may write, all of them are holders
+    WriteExclusiveAllRegistrants = 5,
+    /// Only registrants may read or write, all of them are holders
+    ExclusiveAccessAllRegistrants = 6,
+}
+
+/// What `IoQueuePair::reservation_register` does with the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterAction {
+    /// Register the new key
+    Register = 0,
+    /// Unregister the current key
+    Unregister = 1,
+    /// Replace the current key with the new key
+    Replace = 2,
+}
+
+/// How `IoQueuePair::reservation_acquire` acquires the reservation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcquireAction {
+    /// Acquire the reservation if it is not held by another host
+    Acquire = 0,
+    /// Take over the reservation and unregister the preempted key
+    Preempt = 1,
+    /// Like `Preempt`, and also abort the commands of the preempted host
+    PreemptAndAbort = 2,
+}
+
+/// How `IoQueuePair::reservation_release` releases the reservation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReleaseAction {
+    /// Release the reservation held by this host
+    Release = 0,
+    /// Release the reservation and unregister all registrants
+    Clear = 1,
+}
+
+/// A controller registered for the reservations of a namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisteredController {
+    /// The ID of the controller
+    pub controller_id: u16,
+    /// The host of the controller holds the reservation
+    pub holds_reservation: bool,
+    /// The ID of the host of the controller
+    pub host_id: u64,
+    /// The reservation key registered by the host
+    pub key: u64,
+}
+
+/// The reservation status of a namespace.
+///
+/// Reported by `IoQueuePair::reservation_report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReservationStatus {
+    /// Incremented by every registration and preemption
+    pub generation: u32,
+    /// The type of the current reservation, 0 if there is none
+    pub rtype: u8,
+    /// The controllers registered for the namespace
+    pub registered_controllers: Vec<RegisteredController>,
+}
+
+impl<A: Allocator> IoQueuePair<A> {
+    /// Register, unregister or replace a reservation key for the namespace.
+    ///
+    /// The `current_key` is ignored by `RegisterAction::Register` and
+    /// the `new_key` by `RegisterAction::Unregister`. Any previously
+    /// submitted requests are completed first.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::FeatureNotSupported` if the namespace
+    /// does not support reservations.
+    pub fn reservation_register(
+        &mut self,
+        action: RegisterAction,
+        current_key: u64,
+        new_key: u64,
+    ) -> Result<()> {
+        let mut data = [0u8; 16];
+        data[..8].copy_from_slice(&current_key.to_le_bytes());
+        data[8..].copy_from_slice(&new_key.to_le_bytes());
+
+        let ns_id = self.reservation_namespace()?;
+        self.exec_with_buffer(&mut data, |cmd_id, data_ptr| {
+            let ignore_key = action == RegisterAction::Register;
+            Command::reservation_register(cmd_id, ns_id, data_ptr, action as u8, ignore_key)
+        })?;
+        Ok(())
+    }
+
+    /// Acquire a reservation of the namespace with the registered `key`.
+    ///
+    /// The `preempt_key` is the key of the registrant to preempt,
+    /// and it is ignored by `AcquireAction::Acquire`.
+    /// See `reservation_register` for more details.
+    pub fn reservation_acquire(
+        &mut self,
+        action: AcquireAction,
+        rtype: ReservationType,
+        key: u64,
+        preempt_key: u64,
+    ) -> Result<()> {
+        let mut data = [0u8; 16];
+        data[..8].copy_from_slice(&key.to_le_bytes());
+        data[8..].copy_from_slice(&preempt_key.to_le_bytes());
+
+        let ns_id = self.reservation_namespace()?;
+        self.exec_with_buffer(&mut data, |cmd_id, data_ptr| {
+            Command::reservation_acquire(cmd_id, ns_id, data_ptr, action as u8, rtype as u8)
+        })?;
+        Ok(())
+    }
+
+    /// Release or clear the reservation of the namespace.
+    ///
+    /// The `rtype` must match the type of the current reservation.
+    /// See `reservation_register` for more details.
+    pub fn reservation_release(
+        &mut self,
+        action: ReleaseAction,
+        rtype: ReservationType,
+        key: u64,
+    ) -> Result<()> {
+        let mut data = key.to_le_bytes();
+
+        let ns_id = self.reservation_namespace()?;
+        self.exec_with_buffer(&mut data, |cmd_id, data_ptr| {
+            Command::reservation_release(cmd_id, ns_id, data_ptr, action as u8, rtype as u8)
+        })?;
+        Ok(())
+    }
+
+    /// Get the reservation status of the namespace.
+    ///
+    /// At most 64 registered controllers are reported.
+    /// See `reservation_register` for more details.
+    pub fn reservation_report(&mut self) -> Result<ReservationStatus> {
+        let size = STATUS_HEADER_SIZE + MAX_REGISTERED_CONTROLLERS * REGISTERED_CONTROLLER_SIZE;
+        let mut data = vec![0u8; size];
+
+        let ns_id = self.reservation_namespace()?;
+        self.exec_with_buffer(&mut data, |cmd_id, data_ptr| {
+            Command::reservation_report(cmd_id, ns_id, data_ptr, (size / 4) as u32)
+        })?;
+
+        let count = u16::from_le_bytes([data[5], data[6]]) as usize;
+        let registered_controllers = data[STATUS_HEADER_SIZE..]
+            .chunks_exact(REGISTERED_CONTROLLER_SIZE)
+            .take(count)
+            .map(|entry| RegisteredController {
+                controller_id: u16::from_le_bytes([entry[0], entry[1]]),
+                holds_reservation: entry[2] & 1 != 0,
+                host_id: u64::from_le_bytes(entry[8..16].try_into().unwrap()),
+                key: u64::from_le_bytes(entry[16..24].try_into().unwrap()),
+            })
+            .collect();
+
+        Ok(ReservationStatus {
+            generation: u32::from_le_bytes(data[..4].try_into().unwrap()),
+            rtype: data[4],
+            registered_controllers,
+        })
+    }
+
+    /// Get the namespace ID after checking that it supports reservations.
+    ///
+    /// Any previously submitted requests are completed first.
+    fn reservation_namespace(&mut self) -> Result<u32> {
+        if self.namespace().reservation_capabilities() == 0 {
+            return Err(Error::FeatureNotSupported);
+        }
+        self.flush()?;
+        Ok(self.namespace().id())
+    }
+}
@@ -0,0 +1,129 @@
+use crate::cmd::Command;
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::memory::Allocator;
+
+/// SANICAP bit: Crypto Erase is supported.
+const SANICAP_CRYPTO_ERASE: u32 = 1 << 0;
+/// SANICAP bit: Block Erase is supported.
+const SANICAP_BLOCK_ERASE: u32 = 1 << 1;
+/// SANICAP bit: Overwrite is supported.
+const SANICAP_OVERWRITE: u32 = 1 << 2;
+
+/// Sanitize Status log page identifier.
+const LOG_SANITIZE_STATUS: u8 = 0x81;
+
+/// What a sanitize operation does with the user data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizeAction {
+    /// Leave the failure mode after a failed sanitize operation
+    ExitFailureMode,
+    /// Erase all blocks with a low level block erase
+    BlockErase,
+    /// Overwrite all blocks with a pattern
+    Overwrite {
+        /// The number of passes (1 to 16, where 0 also means 16)
+        passes: u8,
+        /// The 32 bit pattern which is written
+        pattern: u32,
+        /// Invert the pattern between passes
+        invert: bool,
+    },
+    /// Erase the media encryption keys, making all user data unreadable
+    CryptoErase,
+}
+
+/// The state of the most recent sanitize operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizeState {
+    /// The NVM subsystem has never been sanitized
+    NeverSanitized,
+    /// The most recent sanitize operation completed successfully
+    Completed,
+    /// A sanitize operation is in progress
+    InProgress,
+    /// The most recent sanitize operation failed
+    Failed,
+    /// The most recent sanitize operation completed successfully,
+    /// but the blocks were not deallocated
+    CompletedWithoutDeallocate,
+    /// A state reserved by the specification
+    Reserved(u8),
+}
+
+/// The progress and state of sanitize operations.
+///
+/// Reported by the Sanitize Status log page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SanitizeStatus {
+    /// The progress of the current sanitize operation, out of 65536
+    ///
+    /// It is 0xFFFF if no operation is in progress.
+    pub progress: u16,
+    /// The state of the most recent sanitize operation
+    pub state: SanitizeState,
+}
+
+impl<A: Allocator> Device<A> {
+    /// Start a sanitize operation, which alters all user data of the NVM
+    /// subsystem so that it cannot be recovered.
+    ///
+    /// The operation runs in the background once this returns, and its
+    /// progress can be polled with `sanitize_status`. Until it completes,
+    /// most commands are aborted by the controller.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::FeatureNotSupported` if the controller does not
+    /// support `action`.
+    pub fn sanitize(&mut self, action: SanitizeAction) -> Result<()> {
+        let sanicap = self.controller_data().sanitize_capabilities;
+        let (sanact, passes, pattern, invert, required) = match action {
+            SanitizeAction::ExitFailureMode => (1, 0, 0, false, 0),
+            SanitizeAction::BlockErase => (2, 0, 0, false, SANICAP_BLOCK_ERASE),
+            SanitizeAction::Overwrite {
+                passes,
+                pattern,
+                invert,
+            } => (3, passes, pattern, invert, SANICAP_OVERWRITE),
+            SanitizeAction::CryptoErase => (4, 0, 0, false, SANICAP_CRYPTO_ERASE),
+        };
+        // Exiting the failure mode is possible with any sanitize support.
+        if sanicap & required != required || sanicap & 0x7 == 0 {
+            return Err(Error::FeatureNotSupported);
+        }
+
+        let cmd_id = self.next_cmd_id();
+        self.exec_admin(Command::sanitize(
+            cmd_id,
+            sanact,
+            false,
+            // The pass count is 0 for 16 passes.
+            passes & 0xF,
+            invert,
+            false,
+            pattern,
+        ))?;
+        Ok(())
+    }
+
+    /// Get the progress and state of sanitize operations.
+    pub fn sanitize_status(&mut self) -> Result<SanitizeStatus> {
+        let mut log = [0u8; 4];
+        self.get_log_page(LOG_SANITIZE_STATUS, 0, 0, &mut log)?;
+
+        let state = match log[2] & 0x7 {
+            0 => SanitizeState::NeverSanitized,
+            1 => SanitizeState::Completed,
+            2 => SanitizeState::InProgress,
+            3 => SanitizeState::Failed,
+            4 => SanitizeState::CompletedWithoutDeallocate,
+            state => SanitizeState::Reserved(state),
+        };
+
+        Ok(SanitizeStatus {
+            progress: u16::from_le_bytes([log[0], log[1]]),
+            state,
+        })
+    }
+}
@@ -0,0 +1,157 @@
+use alloc::vec::Vec;
+
+use crate::cmd::Command;
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::memory::Allocator;
+
+/// Device Self-test log page identifier.
+const LOG_SELF_TEST: u8 = 0x06;
+
+/// Size of the Device Self-test log page (in bytes).
+const SELF_TEST_LOG_SIZE: usize = 564;
+
+/// Size of a result entry of the Device Self-test log page (in bytes).
+const SELF_TEST_RESULT_SIZE: usize = 28;
+
+/// Result code of an unused entry of the Device Self-test log page.
+const SELF_TEST_RESULT_UNUSED: u8 = 0xF;
+
+/// The namespace ID selecting all namespaces.
+const ALL_NAMESPACES: u32 = 0xFFFF_FFFF;
+
+/// The kind of a device self-test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestKind {
+    /// A short self-test, which usually takes minutes
+    Short,
+    /// An extended self-test, which may take hours
+    Extended,
+    /// A vendor specific self-test
+    VendorSpecific,
+    /// A self-test code reserved by the specification
+    Reserved(u8),
+}
+
+impl SelfTestKind {
+    /// Decodes a self-test code.
+    fn from_raw(raw: u8) -> Self {
+        match raw {
+            1 => Self::Short,
+            2 => Self::Extended,
+            0xE => Self::VendorSpecific,
+            code => Self::Reserved(code),
+        }
+    }
+
+    /// Get the self-test code.
+    fn code(&self) -> u8 {
+        match self {
+            Self::Short => 1,
+            Self::Extended => 2,
+            Self::VendorSpecific => 0xE,
+            Self::Reserved(code) => *code,
+        }
+    }
+}
+
+/// The result of a completed device self-test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfTestResult {
+    /// The kind of the self-test
+    pub kind: SelfTestKind,
+    /// The result code, 0 means the self-test passed
+    ///
+    /// Other values tell why it was aborted (1 to 4) or which
+    /// segment failed (5 to 7), see the specification.
+    pub result: u8,
+    /// The number of the first failed segment, if any
+    pub failed_segment: Option<u8>,
+    /// The power on hours of the controller when the self-test completed
+    pub power_on_hours: u64,
+    /// The namespace in which the failing LBA occurred
+    pub ns_id: Option<u32>,
+    /// The first failing LBA
+    pub failing_lba: Option<u64>,
+}
+
+/// The state of the device self-tests.
+///
+/// Reported by the Device Self-test log page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestLog {
+    /// The self-test in progress, if any
+    pub current: Option<SelfTestKind>,
+    /// The progress of the current self-test (in percent)
+    pub progress: u8,
+    /// The results of the most recent self-tests, newest first
+    pub results: Vec<SelfTestResult>,
+}
+
+impl SelfTestResult {
+    /// Parses a result entry, returning `None` for an unused entry.
+    fn from_bytes(entry: &[u8]) -> Option<Self> {
+        let result = entry[0] & 0xF;
+        if result == SELF_TEST_RESULT_UNUSED {
+            return None;
+        }
+
+        // Bits of the valid diagnostic information.
+        let valid = entry[2];
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(entry[offset..offset + 8].try_into().unwrap());
+        let ns_id = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+
+        Some(Self {
+            kind: SelfTestKind::from_raw(entry[0] >> 4),
+            result,
+            failed_segment: (entry[1] != 0).then_some(entry[1]),
+            power_on_hours: u64_at(4),
+            ns_id: (valid & 1 != 0).then_some(ns_id),
+            failing_lba: (valid & (1 << 1) != 0).then(|| u64_at(16)),
+        })
+    }
+}
+
+impl<A: Allocator> Device<A> {
+    /// Start a device self-test of the controller and all namespaces.
+    ///
+    /// The self-test runs in the background, and its progress and result
+    /// can be read with `self_test_log`. Only one self-test can run at a time.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::FeatureNotSupported` if the controller does not
+    /// support device self-tests.
+    pub fn start_self_test(&mut self, kind: SelfTestKind) -> Result<()> {
+        if !self.controller_data().supports_self_test() {
+            return Err(Error::FeatureNotSupported);
+        }
+
+        let cmd_id = self.next_cmd_id();
+        self.exec_admin(Command::device_self_test(
+            cmd_id,
+            ALL_NAMESPACES,
+            kind.code(),
+        ))?;
+        Ok(())
+    }
+
+    /// Get the progress of the current self-test and the recent results.
+    pub fn self_test_log(&mut self) -> Result<SelfTestLog> {
+        let mut log = [0u8; SELF_TEST_LOG_SIZE];
+        self.get_log_page(LOG_SELF_TEST, 0, 0, &mut log)?;
+
+        let current = log[0] & 0xF;
+        let results = log[4..]
+            .chunks_exact(SELF_TEST_RESULT_SIZE)
+            .filter_map(SelfTestResult::from_bytes)
+            .collect();
+
+        Ok(SelfTestLog {
+            current: (current != 0).then(|| SelfTestKind::from_raw(current)),
+            progress: log[1] & 0x7F,
+            results,
+        })
+    }
+}
@@ -0,0 +1,104 @@
+use crate::error::{Error, Result};
+use crate::memory::{Allocator, Dma};
+
+/// SGL descriptor type: Data Block.
+const DESCRIPTOR_DATA_BLOCK: u8 = 0x0;
+/// SGL descriptor type: Last Segment.
+const DESCRIPTOR_LAST_SEGMENT: u8 = 0x3;
+
+/// An SGL descriptor as laid out in memory and in the data pointer.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, packed)]
+pub(crate) struct SglDescriptor {
+    address: u64,
+    length: u32,
+    _rsvd: [u8; 3],
+    /// Descriptor type (bits 7:4) and subtype (bits 3:0)
+    kind: u8,
+}
+
+impl SglDescriptor {
+    fn new(address: usize, length: usize, kind: u8) -> Self {
+        Self {
+            address: address as u64,
+            length: length as u32,
+            kind: kind << 4,
+            ..Default::default()
+        }
+    }
+
+    /// Get the descriptor as the data pointer of a command.
+    fn as_data_ptr(&self) -> [u64; 2] {
+        let high = self.length as u64 | (self.kind as u64) << 56;
+        [self.address, high]
+    }
+}
+
+/// A scatter gather list describing the data buffer of a command.
+///
+/// A single segment fits into the data pointer itself, otherwise it points
+/// to one last segment holding a data block descriptor per segment.
+pub(crate) struct Sgl {
+    descriptor: SglDescriptor,
+    segment: Option<Dma<SglDescriptor>>,
+}
+
+impl Sgl {
+    /// Creates an SGL for the `(address, length)` segments.
+    ///
+    /// Each segment must be physically contiguous, and if `dword_aligned`
+    /// is set, its address and length must be aligned to a dword.
+    pub fn create<A: Allocator>(
+        allocator: &A,
+        segments: &[(usize, usize)],
+        dword_aligned: bool,
+    ) -> Result<Self> {
+        for &(address, length) in segments {
+            if length == 0 || length > u32::MAX as usize {
+                return Err(Error::InvalidBufferSize);
+            }
+            if dword_aligned && (address | length) & 0x3 != 0 {
+                return Err(Error::NotAlignedToDword);
+            }
+        }
+
+        let data_block = |&(address, length)| {
+            SglDescriptor::new(allocator.translate(address), length, DESCRIPTOR_DATA_BLOCK)
+        };
+
+        match segments {
+            [] => Err(Error::InvalidBufferSize),
+            [segment] => Ok(Self {
+                descriptor: data_block(segment),
+                segment: None,
+            }),
+            segments => {
+                let mut segment = Dma::<SglDescriptor>::allocate(segments.len(), allocator);
+                for (descriptor, data) in segment.iter_mut().zip(segments) {
+                    *descriptor = data_block(data);
+                }
+                let length = segments.len() * size_of::<SglDescriptor>();
+                Ok(Self {
+                    descriptor: SglDescriptor::new(
+                        segment.phys_addr,
+                        length,
+                        DESCRIPTOR_LAST_SEGMENT,
+                    ),
+                    segment: Some(segment),
+                })
+            }
+        }
+    }
+
+    /// Get the data pointer of a command using this SGL.
+    pub fn data_ptr(&self) -> [u64; 2] {
+        self.descriptor.as_data_ptr()
+    }
+
+    /// Releases the memory of the SGL.
+    pub fn release<A: Allocator>(self, allocator: &A) {
+        if let Some(segment) = self.segment {
+            segment.deallocate(allocator);
+        }
+    }
+}
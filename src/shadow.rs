@@ -0,0 +1,96 @@
+use core::sync::atomic::{Ordering, fence};
+
+use crate::cmd::Command;
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::memory::{Allocator, Dma};
+
+/// The shadow doorbell and EventIdx buffers shared with the controller.
+///
+/// The host writes new doorbell values to the shadow doorbell buffer,
+/// and the controller reports in the EventIdx buffer the value after
+/// which it wants the doorbell register to be written as well. Both
+/// buffers are laid out like the doorbell registers.
+#[derive(Clone, Copy)]
+pub(crate) struct ShadowDoorbells {
+    doorbells: *mut u32,
+    event_indices: *mut u32,
+}
+
+unsafe impl Send for ShadowDoorbells {}
+unsafe impl Sync for ShadowDoorbells {}
+
+impl ShadowDoorbells {
+    /// Update the shadow doorbell at dword `index` to `value`.
+    ///
+    /// Returns whether the doorbell register must be written as well,
+    /// which is the case if the controller asked for an event between
+    /// the old and the new value.
+    pub fn update(&self, index: usize, value: u32) -> bool {
+        let old = unsafe { self.doorbells.add(index).read_volatile() };
+        unsafe { self.doorbells.add(index).write_volatile(value) };
+        // The new value must be visible before the event index is read.
+        fence(Ordering::SeqCst);
+        let event_index = unsafe { self.event_indices.add(index).read_volatile() };
+
+        let (value, old, event_index) = (value as u16, old as u16, event_index as u16);
+        value.wrapping_sub(event_index).wrapping_sub(1) < value.wrapping_sub(old)
+    }
+
+    /// Reset the shadow doorbell at dword `index`, e.g. for a new queue.
+    pub fn clear(&self, index: usize) {
+        unsafe {
+            self.doorbells.add(index).write_volatile(0);
+            self.event_indices.add(index).write_volatile(0);
+        }
+    }
+}
+
+impl<A: Allocator> Device<A> {
+    /// Let the controller use shadow doorbells for the I/O queues.
+    ///
+    /// The doorbell registers are then only written when the controller
+    /// asks for it, which saves most of the MMIO exits of a virtualized
+    /// guest. It must be enabled before any I/O queue pair is created,
+    /// and again after a reset.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::FeatureNotSupported` if the controller does not
+    /// support the Doorbell Buffer Config command or the buffers do not
+    /// fit into a memory page, and `Error::IoQueuesExist` if an I/O queue
+    /// pair exists.
+    pub fn enable_shadow_doorbells(&mut self) -> Result<()> {
+        if !self.data.supports_doorbell_buffer_config() {
+            return Err(Error::FeatureNotSupported);
+        }
+        if !self.io_queue_ids.is_empty() {
+            return Err(Error::IoQueuesExist);
+        }
+        let size = 2 * (self.data.max_io_queues as usize + 1) * self.doorbell_stride();
+        if size > self.data.min_pagesize {
+            return Err(Error::FeatureNotSupported);
+        }
+
+        let allocator = self.allocator.clone();
+        let buffers = self.shadow_buffers.get_or_insert_with(|| {
+            let page_size = self.data.min_pagesize;
+            let count = page_size / 4;
+            [
+                Dma::allocate_aligned(count, page_size, allocator.as_ref()),
+                Dma::allocate_aligned(count, page_size, allocator.as_ref()),
+            ]
+        });
+        buffers.iter_mut().for_each(Dma::zero);
+        let shadow = ShadowDoorbells {
+            doorbells: buffers[0].addr,
+            event_indices: buffers[1].addr,
+        };
+        let data_ptr = [buffers[0].phys_addr as u64, buffers[1].phys_addr as u64];
+
+        let cmd_id = self.next_cmd_id();
+        self.exec_admin(Command::doorbell_buffer_config(cmd_id, data_ptr))?;
+        self.doorbell_helper.set_shadow(shadow);
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use core::marker::PhantomData;
+
+use crate::error::Result;
+use crate::io::IoQueuePair;
+use crate::memory::Allocator;
+
+/// A mutual exclusion lock provided by the caller.
+///
+/// Implement it over the lock type of your environment (e.g. a spinlock
+/// or a scheduler aware mutex), so that `SharedIoQueuePair` works
+/// with any `no_std` locking primitive.
+pub trait Lock<T> {
+    /// Creates a new lock protecting `value`.
+    fn new(value: T) -> Self;
+
+    /// Runs `f` with exclusive access to the protected value.
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R;
+
+    /// Consumes the lock and returns the protected value.
+    fn into_inner(self) -> T;
+}
+
+/// An `IoQueuePair` that can be shared between threads.
+///
+/// Every operation takes `&self` and is serialized by the lock `L`,
+/// so it can be put in an `Arc` to fan out work from many threads
+/// onto a single hardware queue pair.
+///
+/// Note that `flush` waits for the requests of all threads, not only
+/// those submitted by the calling thread. Prefer the `*_sync` methods
+/// if each thread needs to know the status of its own requests.
+pub struct SharedIoQueuePair<A: Allocator, L: Lock<IoQueuePair<A>>> {
+    inner: L,
+    _marker: PhantomData<fn() -> A>,
+}
+
+impl<A: Allocator, L: Lock<IoQueuePair<A>>> SharedIoQueuePair<A, L> {
+    /// Wraps a queue pair so that it can be shared.
+    pub fn new(qpair: IoQueuePair<A>) -> Self {
+        Self {
+            inner: L::new(qpair),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the queue pair, e.g. to delete it.
+    pub fn into_inner(self) -> IoQueuePair<A> {
+        self.inner.into_inner()
+    }
+
+    /// Runs `f` with exclusive access to the queue pair.
+    ///
+    /// Use it to perform several operations without other
+    /// threads submitting in between.
+    pub fn with<R>(&self, f: impl FnOnce(&mut IoQueuePair<A>) -> R) -> R {
+        self.inner.with(f)
+    }
+
+    /// Submits a read request, see `IoQueuePair::read`.
+    pub fn read(&self, dest: *mut u8, bytes: usize, lba: u64) -> Result<()> {
+        self.with(|qpair| qpair.read(dest, bytes, lba))
+    }
+
+    /// Submits a write request, see `IoQueuePair::write`.
+    pub fn write(&self, src: *const u8, bytes: usize, lba: u64) -> Result<()> {
+        self.with(|qpair| qpair.write(src, bytes, lba))
+    }
+
+    /// Waits for all in-flight requests of all threads, see `IoQueuePair::flush`.
+    pub fn flush(&self) -> Result<()> {
+        self.with(|qpair| qpair.flush())
+    }
+
+    /// Reads and waits for the completion, see `IoQueuePair::read_sync`.
+    pub fn read_sync(&self, buf: &mut [u8], lba: u64) -> Result<()> {
+        self.with(|qpair| qpair.read_sync(buf, lba))
+    }
+
+    /// Writes and waits for the completion, see `IoQueuePair::write_sync`.
+    pub fn write_sync(&self, buf: &[u8], lba: u64) -> Result<()> {
+        self.with(|qpair| qpair.write_sync(buf, lba))
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use std::sync::Mutex;
+    use std::thread;
+
+    use super::*;
+    use crate::memory::{Dma, IdentityAllocator};
+    use crate::mock::{MockController, MockMemory};
+
+    struct MutexLock<T>(Mutex<T>);
+
+    impl<T> Lock<T> for MutexLock<T> {
+        fn new(value: T) -> Self {
+            Self(Mutex::new(value))
+        }
+
+        fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+            f(&mut self.0.lock().unwrap())
+        }
+
+        fn into_inner(self) -> T {
+            self.0.into_inner().unwrap()
+        }
+    }
+
+    #[test]
+    fn interleaved_blocking_operations() {
+        let mock = MockController::new(1024, 512).with_reversed_completions();
+        let mut device = mock.device();
+        let namespace = device.identify_namespaces(0).unwrap().remove(0);
+        let qpair = device.create_io_queue_pair(namespace, 16, 0, None).unwrap();
+        let shared = SharedIoQueuePair::<_, MutexLock<_>>::new(qpair);
+
+        let memory = IdentityAllocator(MockMemory::default());
+        thread::scope(|scope| {
+            for thread in 0..2u8 {
+                let (shared, memory) = (&shared, &memory);
+                scope.spawn(move || {
+                    let mut pending = Dma::<u8>::allocate_zeroed(512, memory);
+                    let mut data = Dma::<u8>::allocate_zeroed(512, memory);
+                    for round in 0..100u8 {
+                        // A request of this thread, and maybe one of the other
+                        // thread, are still in flight when the blocking calls
+                        // submit theirs.
+                        let lba = thread as u64 * 256 + round as u64;
+                        shared
+                            .with(|qpair| {
+                                pending.fill(round ^ 0xFF);
+                                qpair.write(pending.addr, 512, lba + 128)
+                            })
+                            .unwrap();
+                        data.fill(round ^ thread);
+                        shared.write_sync(&data, lba).unwrap();
+                        data.fill(0);
+                        shared.read_sync(&mut data, lba).unwrap();
+                        assert!(data.iter().all(|&byte| byte == round ^ thread));
+                    }
+                });
+            }
+        });
+
+        let disk = mock.disk();
+        for thread in 0..2usize {
+            for round in 0..100usize {
+                let lba = thread * 256 + round;
+                assert!(
+                    disk[lba * 512..][..512]
+                        .iter()
+                        .all(|&byte| byte == (round ^ thread) as u8)
+                );
+                let lba = lba + 128;
+                assert!(
+                    disk[lba * 512..][..512]
+                        .iter()
+                        .all(|&byte| byte == round as u8 ^ 0xFF)
+                );
+            }
+        }
+        device.delete_io_queue_pair(shared.into_inner()).unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+use core::fmt::{self, Display};
+
+/// The type of a status code, which defines how the code is interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusCodeType {
+    /// The status is applicable to many commands
+    Generic,
+    /// The status is specific to the command that failed
+    CommandSpecific,
+    /// The command failed due to a media or data integrity error
+    MediaError,
+    /// The command failed due to the path between host and controller
+    PathRelated,
+    /// The status is specific to the vendor
+    VendorSpecific,
+    /// A status code type reserved by the specification
+    Reserved(u8),
+}
+
+/// The status of a failed command, decoded from its completion entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    /// The type of `code`
+    pub code_type: StatusCodeType,
+    /// The status code
+    pub code: u8,
+    /// More information is available in the Error Information log page
+    pub more: bool,
+    /// Retrying the command is expected to fail again
+    pub do_not_retry: bool,
+}
+
+impl Status {
+    /// Decodes the status field of a completion (without the phase tag).
+    pub fn from_raw(raw: u16) -> Self {
+        let code_type = match (raw >> 8) as u8 & 0x7 {
+            0 => StatusCodeType::Generic,
+            1 => StatusCodeType::CommandSpecific,
+            2 => StatusCodeType::MediaError,
+            3 => StatusCodeType::PathRelated,
+            7 => StatusCodeType::VendorSpecific,
+            value => StatusCodeType::Reserved(value),
+        };
+
+        Self {
+            code_type,
+            code: raw as u8,
+            more: raw & (1 << 13) != 0,
+            do_not_retry: raw & (1 << 14) != 0,
+        }
+    }
+
+    /// Get a description of the common status codes.
+    fn description(&self) -> Option<&'static str> {
+        let description = match (self.code_type, self.code) {
+            (StatusCodeType::Generic, code) => match code {
+                0x00 => "Successful Completion",
+                0x01 => "Invalid Command Opcode",
+                0x02 => "Invalid Field in Command",
+                0x03 => "Command ID Conflict",
+                0x04 => "Data Transfer Error",
+                0x05 => "Commands Aborted due to Power Loss Notification",
+                0x06 => "Internal Error",
+                0x07 => "Command Abort Requested",
+                0x08 => "Command Aborted due to SQ Deletion",
+                0x09 => "Command Aborted due to Failed Fused Command",
+                0x0A => "Command Aborted due to Missing Fused Command",
+                0x0B => "Invalid Namespace or Format",
+                0x0C => "Command Sequence Error",
+                0x80 => "LBA Out of Range",
+                0x81 => "Capacity Exceeded",
+                0x82 => "Namespace Not Ready",
+                0x83 => "Reservation Conflict",
+                0x84 => "Format In Progress",
+                _ => return None,
+            },
+            (StatusCodeType::CommandSpecific, code) => match code {
+                0x00 => "Completion Queue Invalid",
+                0x01 => "Invalid Queue Identifier",
+                0x02 => "Invalid Queue Size",
+                0x03 => "Abort Command Limit Exceeded",
+                0x05 => "Asynchronous Event Request Limit Exceeded",
+                0x06 => "Invalid Firmware Slot",
+                0x07 => "Invalid Firmware Image",
+                0x08 => "Invalid Interrupt Vector",
+                0x09 => "Invalid Log Page",
+                0x0A => "Invalid Format",
+                0x0B => "Firmware Activation Requires Conventional Reset",
+                0x0C => "Invalid Queue Deletion",
+                0x0D => "Feature Identifier Not Saveable",
+                0x0E => "Feature Not Changeable",
+                0x0F => "Feature Not Namespace Specific",
+                0x10 => "Firmware Activation Requires NVM Subsystem Reset",
+                0x11 => "Firmware Activation Requires Controller Level Reset",
+                0x12 => "Firmware Activation Requires Maximum Time Violation",
+                0x13 => "Firmware Activation Prohibited",
+                0x14 => "Overlapping Range",
+                0x80 => "Conflicting Attributes",
+                0x81 => "Invalid Protection Information",
+                0x82 => "Attempted Write to Read Only Range",
+                _ => return None,
+            },
+            (StatusCodeType::MediaError, code) => match code {
+                0x80 => "Write Fault",
+                0x81 => "Unrecovered Read Error",
+                0x82 => "End-to-end Guard Check Error",
+                0x83 => "End-to-end Application Tag Check Error",
+                0x84 => "End-to-end Reference Tag Check Error",
+                0x85 => "Compare Failure",
+                0x86 => "Access Denied",
+                0x87 => "Deallocated or Unwritten Logical Block",
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(description)
+    }
+}
+
+impl Display for StatusCodeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatusCodeType::Generic => write!(f, "generic"),
+            StatusCodeType::CommandSpecific => write!(f, "command specific"),
+            StatusCodeType::MediaError => write!(f, "media error"),
+            StatusCodeType::PathRelated => write!(f, "path related"),
+            StatusCodeType::VendorSpecific => write!(f, "vendor specific"),
+            StatusCodeType::Reserved(value) => write!(f, "reserved type {value}"),
+        }
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.description() {
+            Some(description) => write!(f, "{description}"),
+            None => write!(f, "Unknown status"),
+        }?;
+        write!(f, " ({} status {:#x})", self.code_type, self.code)
+    }
+}
@@ -0,0 +1,53 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::memory::Allocator;
+
+/// Telemetry Host-Initiated log page identifier.
+const LOG_TELEMETRY_HOST: u8 = 0x07;
+
+/// LPA bit of the telemetry log pages.
+const LPA_TELEMETRY: u8 = 1 << 3;
+
+/// Log specific field: Create Telemetry Host-Initiated Data.
+const LSP_CREATE_TELEMETRY: u8 = 1;
+
+/// Size of a telemetry data block, the header being the first one.
+const TELEMETRY_BLOCK_SIZE: usize = 512;
+
+impl<A: Allocator> Device<A> {
+    /// Capture the host-initiated telemetry log of the controller.
+    ///
+    /// The controller captures its internal state, which is returned
+    /// with the header followed by the data areas 1 to 3. It is meant to
+    /// be passed to the vendor for debugging and may be megabytes large,
+    /// so it is read in parts through the admin buffer.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::FeatureNotSupported` if the controller does not
+    /// support the telemetry log pages.
+    pub fn telemetry_host(&mut self) -> Result<Vec<u8>> {
+        if self.data.log_page_attributes & LPA_TELEMETRY == 0 {
+            return Err(Error::FeatureNotSupported);
+        }
+
+        let mut header = [0u8; TELEMETRY_BLOCK_SIZE];
+        self.get_log_page(LOG_TELEMETRY_HOST, LSP_CREATE_TELEMETRY, 0, &mut header)?;
+
+        // The last block of data area 3, which also ends the areas 1 and 2.
+        let last_block = u16::from_le_bytes([header[12], header[13]]) as usize;
+        let mut log = vec![0u8; (last_block + 1) * TELEMETRY_BLOCK_SIZE];
+        log[..TELEMETRY_BLOCK_SIZE].copy_from_slice(&header);
+        self.get_log_page(
+            LOG_TELEMETRY_HOST,
+            0,
+            TELEMETRY_BLOCK_SIZE as u64,
+            &mut log[TELEMETRY_BLOCK_SIZE..],
+        )?;
+
+        Ok(log)
+    }
+}
@@ -0,0 +1,71 @@
+use crate::device::Device;
+use crate::error::Result;
+use crate::memory::Allocator;
+
+/// Temperature Threshold feature identifier.
+const FEATURE_TEMPERATURE_THRESHOLD: u8 = 0x04;
+
+/// SMART / Health Information log page identifier.
+const LOG_SMART_HEALTH: u8 = 0x02;
+
+/// Maximum number of temperature sensors besides the composite temperature.
+const MAX_TEMPERATURE_SENSORS: usize = 8;
+
+/// Which side of a temperature threshold raises an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThresholdKind {
+    /// The event is raised when the temperature rises above the threshold
+    OverTemperature = 0,
+    /// The event is raised when the temperature falls below the threshold
+    UnderTemperature = 1,
+}
+
+/// The temperatures reported by the SMART / Health Information log page.
+///
+/// All temperatures are in Kelvin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Temperatures {
+    /// The composite temperature of the controller and its namespaces
+    pub composite: u16,
+    /// The temperature of each sensor, indexed by the sensor number - 1
+    ///
+    /// It is `None` if the sensor is not implemented.
+    pub sensors: [Option<u16>; MAX_TEMPERATURE_SENSORS],
+}
+
+impl<A: Allocator> Device<A> {
+    /// Set a temperature threshold of the controller.
+    ///
+    /// The `sensor` is 0 for the composite temperature or the sensor number
+    /// (1 to 8) of `Temperatures::sensors`. Once the temperature crosses
+    /// the threshold of `kind`, a SMART / health status event is reported
+    /// through `poll_async_event`, so that a thermal control loop can react
+    /// on it and read `temperatures`.
+    pub fn set_temperature_threshold(
+        &mut self,
+        sensor: u8,
+        kelvin: u16,
+        kind: ThresholdKind,
+    ) -> Result<()> {
+        let value = (kind as u32) << 20 | (sensor as u32 & 0xF) << 16 | kelvin as u32;
+        self.set_features(FEATURE_TEMPERATURE_THRESHOLD, value, false, None)?;
+        Ok(())
+    }
+
+    /// Get the current temperatures of the controller.
+    pub fn temperatures(&mut self) -> Result<Temperatures> {
+        // The temperature sensors are the last of the used fields.
+        let mut log = [0u8; 216];
+        self.get_log_page(LOG_SMART_HEALTH, 0, 0, &mut log)?;
+
+        let sensors = core::array::from_fn(|sensor| {
+            let kelvin = u16::from_le_bytes([log[200 + sensor * 2], log[201 + sensor * 2]]);
+            (kelvin != 0).then_some(kelvin)
+        });
+
+        Ok(Temperatures {
+            composite: u16::from_le_bytes([log[1], log[2]]),
+            sensors,
+        })
+    }
+}
@@ -0,0 +1,67 @@
+use core::hint::spin_loop;
+
+use crate::error::{Error, Result};
+
+/// Rough number of spin iterations treated as one millisecond.
+///
+/// There is no clock source in a `no_std` driver, so timeouts are
+/// measured in busy-wait iterations instead of wall time.
+const SPINS_PER_MS: usize = 10_000;
+
+/// Default timeout for admin commands (in milliseconds).
+pub(crate) const ADMIN_TIMEOUT_MS: usize = 5_000;
+
+/// Default timeout for I/O commands (in milliseconds).
+pub(crate) const IO_TIMEOUT_MS: usize = 30_000;
+
+/// Timeout for a namespace to become ready (in milliseconds).
+pub(crate) const NAMESPACE_READY_TIMEOUT_MS: usize = 10_000;
+
+/// A spin budget used to bound busy-wait loops.
+///
+/// Every call to `spin` consumes one iteration of the budget,
+/// and once the budget is exhausted an error is returned instead
+/// of spinning forever on a wedged controller.
+pub(crate) struct Timeout {
+    remaining: usize,
+}
+
+impl Timeout {
+    /// Creates a new timeout which expires after roughly `ms` milliseconds.
+    pub fn from_millis(ms: usize) -> Self {
+        Self {
+            remaining: ms.saturating_mul(SPINS_PER_MS),
+        }
+    }
+
+    /// Spins once, consuming one iteration of the budget.
+    ///
+    /// Returns `Error::ControllerTimeout` if the budget is exhausted.
+    pub fn spin(&mut self) -> Result<()> {
+        self.tick()?;
+        spin_loop();
+        Ok(())
+    }
+
+    /// Consumes one iteration of the budget without spinning.
+    ///
+    /// Used when the caller waits by other means.
+    /// Returns `Error::ControllerTimeout` if the budget is exhausted.
+    pub fn tick(&mut self) -> Result<()> {
+        if self.remaining == 0 {
+            return Err(Error::ControllerTimeout);
+        }
+        self.remaining -= 1;
+        Ok(())
+    }
+
+    /// Spins `spins` times, consuming them from the budget.
+    ///
+    /// Returns `Error::ControllerTimeout` if the budget is exhausted.
+    pub fn wait(&mut self, spins: usize) -> Result<()> {
+        for _ in 0..spins {
+            self.spin()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use core::hint::spin_loop;
+#[cfg(feature = "async")]
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Waker,
+};
+
+/// Waits for completions of an `IoQueuePair`.
+///
+/// By default completions are polled, but with interrupts (e.g. MSI-X)
+/// the waiting task can be parked instead: implement `wait` over your
+/// scheduler or a `wfi` loop, and call `notify` from the interrupt handler
+/// of the completion queue to wake it up.
+pub trait CompletionWaiter: Send + Sync {
+    /// Waits for the next completion or a spurious wake up.
+    ///
+    /// It is called repeatedly until the expected completion has arrived,
+    /// so it may return early. Each call consumes one iteration of the
+    /// completion timeout.
+    fn wait(&self);
+
+    /// Wakes up the waiter, called by the interrupt handler.
+    fn notify(&self);
+
+    /// Registers the waker of a task waiting for a completion.
+    ///
+    /// It is called by pending futures, such as `IoFuture`, and the task
+    /// should be woken up by `notify`. By default it is woken up at once,
+    /// so the completion queue is polled again by the executor.
+    #[cfg(feature = "async")]
+    fn register(&self, waker: &Waker) {
+        waker.wake_by_ref();
+    }
+}
+
+/// A `CompletionWaiter` which polls the completion queue by spinning.
+///
+/// This is the default of every `IoQueuePair`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PollingWaiter;
+
+impl CompletionWaiter for PollingWaiter {
+    fn wait(&self) {
+        spin_loop();
+    }
+
+    fn notify(&self) {}
+}
+
+/// A `CompletionWaiter` which wakes up the task of a pending future.
+///
+/// Call `notify` from the interrupt handler of the completion queue.
+/// Blocking operations still poll the completion queue by spinning.
+#[cfg(feature = "async")]
+#[derive(Default)]
+pub struct WakerWaiter {
+    locked: AtomicBool,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+#[cfg(feature = "async")]
+unsafe impl Send for WakerWaiter {}
+#[cfg(feature = "async")]
+unsafe impl Sync for WakerWaiter {}
+
+#[cfg(feature = "async")]
+impl WakerWaiter {
+    /// Runs `f` on the waker while holding the lock.
+    fn with_waker<T>(&self, f: impl FnOnce(&mut Option<Waker>) -> T) -> T {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        let result = f(unsafe { &mut *self.waker.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+#[cfg(feature = "async")]
+impl CompletionWaiter for WakerWaiter {
+    fn wait(&self) {
+        spin_loop();
+    }
+
+    fn notify(&self) {
+        if let Some(waker) = self.with_waker(Option::take) {
+            waker.wake();
+        }
+    }
+
+    fn register(&self, waker: &Waker) {
+        self.with_waker(|current| match current {
+            Some(current) if current.will_wake(waker) => {}
+            _ => *current = Some(waker.clone()),
+        });
+    }
+}
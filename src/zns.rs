@@ -0,0 +1,218 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::cmd::Command;
+use crate::error::{Error, Result};
+use crate::io::IoQueuePair;
+use crate::memory::Allocator;
+
+/// Size of the header of a zone report and of a zone descriptor (in bytes).
+const ZONE_DESCRIPTOR_SIZE: usize = 64;
+
+/// Zone Receive Action: Report Zones.
+const ACTION_REPORT_ZONES: u8 = 0;
+
+/// An action changing the state of zones, sent by `IoQueuePair::manage_zone`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ZoneAction {
+    /// Close an open zone, releasing its open resources
+    Close = 1,
+    /// Mark a zone as full without writing the remaining blocks
+    Finish = 2,
+    /// Explicitly open a zone for writing
+    Open = 3,
+    /// Empty a zone and move its write pointer back to its first block
+    Reset = 4,
+    /// Take a read only zone offline
+    Offline = 5,
+}
+
+/// The state of a zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneState {
+    /// Nothing has been written since the last reset
+    Empty,
+    /// Opened by a write
+    ImplicitlyOpened,
+    /// Opened by an Open Zone action
+    ExplicitlyOpened,
+    /// Partially written and not open
+    Closed,
+    /// Can only be read
+    ReadOnly,
+    /// Written up to its capacity
+    Full,
+    /// Can neither be read nor written
+    Offline,
+    /// A state not defined by the specification
+    Unknown(u8),
+}
+
+impl ZoneState {
+    /// Decode the zone state (ZS) of a zone descriptor.
+    fn from_raw(raw: u8) -> Self {
+        match raw {
+            0x1 => Self::Empty,
+            0x2 => Self::ImplicitlyOpened,
+            0x3 => Self::ExplicitlyOpened,
+            0x4 => Self::Closed,
+            0xD => Self::ReadOnly,
+            0xE => Self::Full,
+            0xF => Self::Offline,
+            _ => Self::Unknown(raw),
+        }
+    }
+}
+
+/// A zone of a zoned namespace.
+///
+/// Reported by `IoQueuePair::report_zones`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZoneDescriptor {
+    /// The type of the zone, 2 for sequential write required
+    pub zone_type: u8,
+    /// The state of the zone
+    pub zone_state: ZoneState,
+    /// The number of blocks that can be written to the zone
+    pub capacity: u64,
+    /// The first block of the zone
+    pub start_lba: u64,
+    /// The block at which the next write to the zone has to start
+    pub write_pointer: u64,
+}
+
+impl ZoneDescriptor {
+    /// Parse a zone descriptor.
+    fn from_raw(raw: &[u8]) -> Self {
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(raw[offset..offset + 8].try_into().unwrap());
+        Self {
+            zone_type: raw[0] & 0xF,
+            zone_state: ZoneState::from_raw(raw[1] >> 4),
+            capacity: u64_at(8),
+            start_lba: u64_at(16),
+            write_pointer: u64_at(24),
+        }
+    }
+}
+
+impl<A: Allocator> IoQueuePair<A> {
+    /// Get the zones of the namespace, starting with the zone containing `start_lba`.
+    ///
+    /// At most `max_zones` zones are reported, and fewer if they don't
+    /// fit into the maximum transfer size. Any previously submitted
+    /// requests are completed first.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::FeatureNotSupported` if the namespace is not zoned.
+    pub fn report_zones(
+        &mut self,
+        start_lba: u64,
+        max_zones: usize,
+    ) -> Result<Vec<ZoneDescriptor>> {
+        let ns_id = self.zoned_namespace()?;
+        let max_zones = max_zones.min(self.max_transfer_size / ZONE_DESCRIPTOR_SIZE - 1);
+        if max_zones == 0 {
+            return Ok(Vec::new());
+        }
+
+        let size = (max_zones + 1) * ZONE_DESCRIPTOR_SIZE;
+        let mut data = vec![0u8; size];
+        self.exec_with_buffer(&mut data, |cmd_id, data_ptr| {
+            let dwords = (size / 4) as u32;
+            Command::zone_management_receive(
+                cmd_id,
+                ns_id,
+                start_lba,
+                data_ptr,
+                dwords,
+                ACTION_REPORT_ZONES,
+                true,
+            )
+        })?;
+
+        // With a partial report, the header counts the returned zones only.
+        let count = u64::from_le_bytes(data[..8].try_into().unwrap()) as usize;
+        let zones = data[ZONE_DESCRIPTOR_SIZE..]
+            .chunks_exact(ZONE_DESCRIPTOR_SIZE)
+            .take(count)
+            .map(ZoneDescriptor::from_raw)
+            .collect();
+        Ok(zones)
+    }
+
+    /// Append `buf` to the zone starting at `zone_start`.
+    ///
+    /// The controller writes the data at the write pointer of the zone and
+    /// returns the first block written, so several appends to the same zone
+    /// can be in flight without coordinating the write pointer. The buffer is
+    /// copied into a DMA buffer, and its length must be a multiple of the
+    /// block size. Any previously submitted requests are completed first.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::InvalidZone` if `zone_start` is not the first block
+    /// of a zone, and `Error::ZoneCapacityExceeded` if the data does not fit
+    /// into the remaining capacity of the zone.
+    pub fn zone_append(&mut self, zone_start: u64, buf: &[u8]) -> Result<u64> {
+        let block_size = self.namespace().block_size();
+        if buf.is_empty() || !(buf.len() as u64).is_multiple_of(block_size) {
+            return Err(Error::InvalidBufferSize);
+        }
+        if self.namespace().is_read_only() {
+            return Err(Error::NamespaceReadOnly);
+        }
+
+        let zone = self
+            .report_zones(zone_start, 1)?
+            .pop()
+            .filter(|zone| zone.start_lba == zone_start)
+            .ok_or(Error::InvalidZone)?;
+        let blocks = buf.len() as u64 / block_size;
+        let remaining = (zone.start_lba + zone.capacity).saturating_sub(zone.write_pointer);
+        if blocks > remaining {
+            return Err(Error::ZoneCapacityExceeded);
+        }
+
+        let ns_id = self.namespace().id();
+        let mut data = buf.to_vec();
+        let completion = self.exec_with_buffer(&mut data, |cmd_id, data_ptr| {
+            Command::zone_append(cmd_id, ns_id, zone_start, blocks as u16 - 1, data_ptr)
+        })?;
+        Ok(completion.command_specific as u64 | (completion.command_specific_high as u64) << 32)
+    }
+
+    /// Apply `action` to the zone starting at `lba`.
+    ///
+    /// With `select_all`, `lba` is ignored and the action is applied to
+    /// every zone in a state the action applies to, e.g. resetting all
+    /// written zones. Any previously submitted requests are completed first.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::InvalidZoneStateTransition` if the zone cannot change
+    /// to the requested state, `Error::TooManyActiveZones` or
+    /// `Error::TooManyOpenZones` if the controller ran out of zone resources,
+    /// and `Error::ZoneReadOnly` or `Error::ZoneOffline` for zones that can
+    /// no longer be written.
+    pub fn manage_zone(&mut self, lba: u64, action: ZoneAction, select_all: bool) -> Result<()> {
+        let ns_id = self.zoned_namespace()?;
+        self.exec_without_data(|cmd_id| {
+            Command::zone_management_send(cmd_id, ns_id, lba, action as u8, select_all)
+        })?;
+        Ok(())
+    }
+
+    /// Get the namespace ID after checking that the namespace is zoned.
+    ///
+    /// Any previously submitted requests are completed first.
+    fn zoned_namespace(&mut self) -> Result<u32> {
+        if !self.namespace().is_zoned() {
+            return Err(Error::FeatureNotSupported);
+        }
+        self.flush()?;
+        Ok(self.namespace().id())
+    }
+}